@@ -0,0 +1,200 @@
+// Operand expressions, e.g. (WIDTH/2)-4 or lo(sprites+2).
+// Operators and their precedence are as in C.
+
+use std::convert::TryFrom;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExprError {
+    // Uses a name that isn't defined, or isn't defined yet
+    Unresolved(String),
+    Invalid(String),
+}
+
+// Lowest precedence first
+const BINARY_OPS: [&[&str]; 6] = [
+    &["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"],
+];
+
+struct ExprParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    lookup: &'a mut dyn FnMut(&str) -> Option<i64>,
+    // The first name that couldn't be looked up
+    unresolved: Option<String>,
+}
+
+fn invalid<T>(msg: String) -> Result<T, ExprError> {
+    Err(ExprError::Invalid(msg))
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, ExprError> {
+    let result = match op {
+        "|" => Some(lhs | rhs),
+        "^" => Some(lhs ^ rhs),
+        "&" => Some(lhs & rhs),
+        "<<" => u32::try_from(rhs).ok().and_then(|r| lhs.checked_shl(r))
+                    .filter(|v| (v >> rhs) == lhs),
+        ">>" => u32::try_from(rhs).ok().and_then(|r| lhs.checked_shr(r)),
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" | "%" if rhs == 0 => return invalid("Division by zero".to_string()),
+        "/" => lhs.checked_div(rhs),
+        "%" => lhs.checked_rem(rhs),
+        _ => panic!("Unknown operator {}", op),
+    };
+    result.ok_or_else(|| ExprError::Invalid("Expression overflows".to_string()))
+}
+
+impl<'a> ExprParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, pred: F) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| pred(*c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ExprError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => invalid(format!("Expected \"{}\", got \"{}\"", expected, c)),
+            None => invalid(format!("Expected \"{}\"", expected)),
+        }
+    }
+
+    // Values can't be checked while a name is missing, e.g. 1/foo
+    fn check_value(&self, result: Result<i64, ExprError>) -> Result<i64, ExprError> {
+        match result {
+            Err(_) if self.unresolved.is_some() => Ok(0),
+            _ => result,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, ExprError> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level+1)?;
+        loop {
+            let _ = self.peek();
+            let op = BINARY_OPS[level].iter().find(|op| {
+                op.chars().enumerate().all(|(i, c)| self.chars.get(self.pos+i) == Some(&c))
+            });
+            match op {
+                None => return Ok(value),
+                Some(op) => {
+                    self.pos += op.len();
+                    let rhs = self.binary(level+1)?;
+                    value = self.check_value(apply(op, value, rhs))?;
+                }
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                let value = self.unary()?;
+                self.check_value(value.checked_neg().ok_or_else(
+                        || ExprError::Invalid("Expression overflows".to_string())))
+            }
+            Some('~') => {
+                self.pos += 1;
+                self.unary().map(|v| !v)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, ExprError> {
+        match self.peek() {
+            None => invalid("Expected a value".to_string()),
+            Some('(') => {
+                self.pos += 1;
+                let value = self.binary(0)?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some('\'') => {
+                let c = match self.chars.get(self.pos+1) {
+                    Some(&c) if c != '\'' => c,
+                    _ => return invalid("Expected a character".to_string()),
+                };
+                self.pos += 2;
+                self.expect('\'')?;
+                Ok(i64::from(u32::from(c)))
+            }
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || (c == '_') => {
+                let name = self.take_while(|c| c.is_ascii_alphanumeric() || (c == '_'));
+                let func = name.to_lowercase();
+                if ((func == "hi") || (func == "lo")) && (self.peek() == Some('(')) {
+                    self.pos += 1;
+                    let value = self.binary(0)?;
+                    self.expect(')')?;
+                    return Ok(if func == "hi" { (value >> 8) & 0xFF } else { value & 0xFF });
+                }
+                match (self.lookup)(&name) {
+                    Some(value) => Ok(value),
+                    None => {
+                        // Carry on to check the rest of it
+                        if self.unresolved.is_none() {
+                            self.unresolved = Some(name);
+                        }
+                        Ok(0)
+                    }
+                }
+            }
+            Some(c) => invalid(format!("Unexpected \"{}\"", c)),
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, ExprError> {
+        let text = self.take_while(|c| c.is_ascii_alphanumeric() || (c == '_'));
+        let lower = text.to_lowercase();
+        let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+            (hex, 16)
+        } else if let Some(bin) = lower.strip_prefix("0b") {
+            (bin, 2)
+        } else {
+            (lower.as_str(), 10)
+        };
+        let digits = digits.replace('_', "");
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return invalid(format!("Invalid number \"{}\"", text));
+        }
+        i64::from_str_radix(&digits, radix)
+            .or_else(|_| invalid(format!("Number \"{}\" is too large", text)))
+    }
+}
+
+// Mistakes in the expression itself are Invalid even if it uses names
+// that can't be found. Otherwise the first of those is Unresolved.
+pub fn eval_expr(text: &str, lookup: &mut dyn FnMut(&str) -> Option<i64>)
+        -> Result<i64, ExprError> {
+    let mut parser = ExprParser { chars: text.chars().collect(), pos: 0, lookup, unresolved: None };
+    let value = parser.binary(0)?;
+    if let Some(c) = parser.peek() {
+        return invalid(format!("Unexpected \"{}\"", c));
+    }
+    match parser.unresolved {
+        Some(name) => Err(ExprError::Unresolved(name)),
+        None => Ok(value),
+    }
+}
@@ -1,6 +1,9 @@
 use system::instr::*;
-use std::collections::{HashMap, HashSet};
+use system::MEMORY_SIZE;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use self::expr::{eval_expr, ExprError};
 mod test;
+mod expr;
 
 struct AsmError {
     line_no: usize,
@@ -17,7 +20,6 @@ impl AsmError {
     }
 }
 
-#[cfg(test)]
 pub fn parse_asm_str(asm: &str) -> Result<Vec<Box<dyn Instr>>, String> {
     let mut warnings: Vec<String> = vec![];
     parse_asm(asm, &"<str>".to_string(), &mut warnings)
@@ -29,46 +31,152 @@ fn parse_asm_str_with_warnings(asm: &str, warnings: &mut Vec<String>)
     parse_asm(asm, &"<str>".to_string(), warnings)
 }
 
+// Where the assembled program came from, for debuggers
+#[derive(Default, Debug, PartialEq)]
+pub struct DebugInfo {
+    // Address of each instruction to its source line, numbered from 1
+    pub lines: BTreeMap<u16, usize>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+impl DebugInfo {
+    pub fn line_for_addr(&self, addr: u16) -> Option<usize> {
+        self.lines.get(&addr).cloned()
+    }
+
+    // The first instruction on the line, or on the next line that has one.
+    // Returns its address and line.
+    pub fn addr_for_line(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines.iter()
+            .filter(|&(_, l)| *l >= line)
+            .min_by_key(|&(addr, l)| (*l, *addr))
+            .map(|(addr, l)| (*addr, *l))
+    }
+
+    // The label at or before addr, if there is one
+    pub fn label_for_addr(&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels.iter()
+            .filter(|&(_, a)| *a <= addr)
+            .max_by_key(|&(_, a)| *a)
+            .map(|(name, a)| (name.as_str(), *a))
+    }
+
+    // The labels as a symbol map, one "<address> <label>" per line
+    pub fn symbol_map(&self) -> String {
+        let mut symbols: Vec<(&u16, &String)> = self.labels.iter().map(|(l, a)| (a, l)).collect();
+        symbols.sort();
+        symbols.iter().map(|&(addr, label)| format!("0x{:04x} {}\n", addr, label)).collect()
+    }
+
+    // Reads back a symbol map, which only has labels
+    pub fn parse_symbol_map(text: &str, filename: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::default();
+        for (idx, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue
+            }
+            let addr = match words.as_slice() {
+                [addr, _] => addr.strip_prefix("0x").and_then(|a| u16::from_str_radix(a, 16).ok()),
+                _ => None,
+            };
+            match addr {
+                None => return Err(format!("{}:{}: error: Expected \"<address> <label>\"",
+                                           filename, idx+1)),
+                Some(addr) => { let _ = info.labels.insert(words[1].to_string(), addr); }
+            }
+        }
+        Ok(info)
+    }
+}
+
 pub fn parse_asm(asm: &str, filename: &str, warnings: &mut Vec<String>) -> Result<Vec<Box<dyn Instr>>, String> {
+    parse_asm_with_info(asm, filename, warnings).map(|(instrs, _)| instrs)
+}
+
+pub fn parse_asm_with_info(asm: &str, filename: &str, warnings: &mut Vec<String>)
+        -> Result<(Vec<Box<dyn Instr>>, DebugInfo), String> {
     let mut instrs: Vec<Box<dyn Instr>> = vec![];
-    let mut symbols: HashMap<String, u16> = HashMap::new();
-    let mut addr: u16 = 0x0200;
+    let mut defs = Definitions::default();
+    // u32 so that it can be just past the end of memory
+    let mut addr: u32 = 0x0200;
     let mut errs: Vec<AsmError> = vec![];
+    let mut info = DebugInfo::default();
+    // Lines using labels defined after them, with the index of their first
+    // instruction and its address
+    let mut deferred: Vec<(usize, &str, usize, u32)> = vec![];
+    // The line number and text each instruction came from
+    let mut sources: Vec<(usize, &str)> = vec![];
 
     for (line_no, line) in asm.lines().enumerate() {
-        match parse_line(&line, &mut symbols, addr) {
+        defs.deferred = false;
+        match parse_line(line, &mut defs, addr) {
             Err(err) => errs.push(AsmError::new(
                     line_no, line.to_string(),
                     err.msg, err.pos, err.len)),
             Ok(mut i) => {
-                addr += 2*(i.len() as u16);
+                // Addresses are filled in with the symbols below, anything
+                // else has to be assembled again once the labels are known.
+                if defs.deferred && !i.iter().any(|ins| ins.get_symbol().is_some()) {
+                    deferred.push((line_no, line, instrs.len(), addr));
+                }
+                for ins in &i {
+                    let _ = info.lines.insert(addr as u16, line_no+1);
+                    addr += u32::from(ins.get_size());
+                    sources.push((line_no, line));
+                }
                 instrs.append(&mut i);
             },
         }
     }
+    info.labels = defs.labels.iter().map(|(name, addr)| (name.clone(), *addr)).collect();
+
+    defs.final_pass = true;
+    for (line_no, line, index, addr) in deferred {
+        match parse_line(line, &mut defs, addr) {
+            Err(err) => errs.push(AsmError::new(
+                    line_no, line.to_string(),
+                    err.msg, err.pos, err.len)),
+            Ok(redone) => {
+                for (offset, ins) in redone.into_iter().enumerate() {
+                    instrs[index+offset] = ins;
+                }
+            }
+        }
+    }
 
     // Patch up symbol addresses
-    let mut resolved_syms = HashSet::new();
-    for ins in &mut instrs {
+    for (ins, &(line_no, line)) in instrs.iter_mut().zip(&sources) {
         if let Some(sym) = ins.get_symbol() {
-            match symbols.get(&sym) {
+            let value = match defs.labels.get(&sym) {
                 Some(addr) => {
-                    ins.resolve_symbol(*addr);
-                    let _ = resolved_syms.insert(sym);
+                    let _ = defs.used.insert(sym.clone());
+                    Ok(i64::from(*addr))
                 }
-                None => {
-                    errs.push(AsmError::new(
-                        //TODO: line info for these
-                        0, "".to_string(), format!("Could not resolve symbol \"{}\"", sym),
-                        0, 1));
-                },
-            }
+                None => defs.eval(&sym),
+            };
+            // LDL is the only one with a 16 bit address
+            let max = if ins.get_size() == 4 { 0xFFFF } else { 0xFFF };
+            let msg = match value {
+                Ok(v) if (0..=max).contains(&v) => {
+                    ins.resolve_symbol(v as u16);
+                    continue
+                }
+                Ok(v) => format!("Address 0x{:X} from \"{}\" is out of range", v, sym),
+                Err(ExprError::Unresolved(name)) => format!("Could not resolve symbol \"{}\"", name),
+                Err(ExprError::Invalid(msg)) => format!("Could not resolve symbol \"{}\": {}", sym, msg),
+            };
+            let (char_no, len) = match line.find(&sym) {
+                Some(pos) => (pos, sym.len()),
+                None => (0, 0),
+            };
+            errs.push(AsmError::new(line_no, line.to_string(), msg, char_no, len));
         }
     }
 
     // Check for unused labels
-    for (sym, _) in symbols {
-        if !resolved_syms.contains(&sym) {
+    for sym in defs.labels.keys() {
+        if !defs.used.contains(sym) {
             warnings.push(format!("{}: warning: Unused label \"{}\"", filename, sym));
         }
     }
@@ -95,7 +203,7 @@ pub fn parse_asm(asm: &str, filename: &str, warnings: &mut Vec<String>) -> Resul
         return Err(err_msg);
     }
 
-    Ok(instrs)
+    Ok((instrs, info))
 }
 
 
@@ -104,14 +212,20 @@ struct AsmArg {
     s: String,
     upper: String,
     pos: usize,
+    // The arg as an expression, which might use names defined later
+    value: Result<i64, ExprError>,
+    // Set when the arg is a register alias
+    register: Option<u8>,
 }
 
 impl AsmArg {
     fn new(s: String, pos: usize) -> AsmArg {
         AsmArg{
             upper: s.to_uppercase(),
+            value: eval_arg(&s, &mut |_| None),
             s,
             pos,
+            register: None,
         }
     }
 
@@ -124,15 +238,62 @@ impl AsmArg {
     }
 }
 
+// Tracks whether we're inside quotes or brackets, where spaces and
+// commas don't split args
+#[derive(Default)]
+struct Nesting {
+    quote: Option<char>,
+    escaped: bool,
+    depth: usize,
+}
+
+impl Nesting {
+    fn is_nested(&self) -> bool {
+        self.quote.is_some() || (self.depth > 0)
+    }
+
+    fn update(&mut self, c: char) {
+        match (self.quote, c) {
+            (Some(_), _) if self.escaped => self.escaped = false,
+            (Some('"'), '\\') => self.escaped = true,
+            (Some(q), c) if c == q => self.quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => self.quote = Some(c),
+            (None, '(') => self.depth += 1,
+            (None, ')') if self.depth > 0 => self.depth -= 1,
+            _ => {}
+        }
+    }
+}
+
+// Where a comment starts, if not in a string
+fn find_comment(line: &str) -> Option<usize> {
+    let mut nesting = Nesting::default();
+    let mut prev = None;
+    for (idx, c) in line.char_indices() {
+        if nesting.quote.is_none() && (c == '/') && (prev == Some('/')) {
+            return Some(idx-1);
+        }
+        nesting.update(c);
+        prev = Some(c);
+    }
+    None
+}
+
 fn split_asm_line(line: &str) -> Vec<AsmArg> {
     let mut start = 0;
     let mut part = String::from("");
     let mut parts: Vec<AsmArg> = vec![];
     let terminators = [' ', '\t', ','];
+    // Expressions and strings may have spaces and commas inside brackets
+    // or quotes
+    let mut nesting = Nesting::default();
+    let len = line.chars().count();
 
     for (idx, c) in line.chars().enumerate() {
-        let is_terminator = terminators.contains(&c);
-        let is_last = idx == line.len()-1;
+        let is_terminator = terminators.contains(&c) && !nesting.is_nested();
+        let is_last = idx == len-1;
+        nesting.update(c);
 
         if !is_terminator {
             if part.is_empty() {
@@ -163,9 +324,118 @@ impl ErrInfo {
     }
 }
 
+// Labels and the names given with .equ (or =) and .alias. A constant's
+// value can only use names defined before it.
+#[derive(Default)]
+struct Definitions {
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    // Labels that have been used
+    used: HashSet<String>,
+    // Set when the line uses a name that isn't defined yet
+    deferred: bool,
+    // Once every label is known, anything left over is an error
+    final_pass: bool,
+}
+
+// Operands that aren't numbers or V registers
+const RESERVED_NAMES: [&str; 10] = ["I", "[I]", "DT", "ST", "K", "F", "HF", "B", "R", "PITCH"];
+
+fn is_register_name(name: &str) -> bool {
+    RESERVED_NAMES.contains(&name.to_uppercase().as_str()) || parse_vx_str(name).is_ok()
+}
+
+// Evaluates an arg. Register names aren't labels that might be defined
+// later, so those are invalid rather than unresolved.
+fn eval_arg(s: &str, lookup: &mut dyn FnMut(&str) -> Option<i64>) -> Result<i64, ExprError> {
+    match eval_expr(s, lookup) {
+        Err(ExprError::Unresolved(ref name)) if is_register_name(name) => {
+            Err(ExprError::Invalid(format!("Expected a number, got \"{}\"", s)))
+        }
+        result => result,
+    }
+}
+
+impl Definitions {
+    fn eval(&mut self, s: &str) -> Result<i64, ExprError> {
+        let Definitions { ref constants, ref labels, ref mut used, .. } = *self;
+        eval_arg(s, &mut |name| {
+            constants.get(name).cloned().or_else(|| {
+                labels.get(name).map(|addr| {
+                    let _ = used.insert(name.to_string());
+                    i64::from(*addr)
+                })
+            })
+        })
+    }
+
+    fn resolve(&mut self, arg: &mut AsmArg) -> Result<(), ErrInfo> {
+        arg.register = self.aliases.get(&arg.s).cloned();
+        if arg.register.is_some() {
+            arg.value = Err(ExprError::Invalid(format!("Expected a number, got \"{}\"", arg.s)));
+        } else if let Err(ExprError::Unresolved(_)) = arg.value {
+            arg.value = self.eval(&arg.s);
+        }
+        if let Err(ExprError::Unresolved(ref name)) = arg.value {
+            if self.final_pass {
+                return Err(ErrInfo::new(format!("Could not resolve symbol \"{}\"", name),
+                                        arg.pos, arg.len()));
+            }
+            self.deferred = true;
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, name: &AsmArg) -> Result<(), ErrInfo> {
+        let valid = name.s.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+                    name.s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let err = |msg: String| Err(ErrInfo::new(msg, name.pos, name.len()));
+        if !valid {
+            return err(format!("Invalid name \"{}\"", name.s))
+        }
+        if is_register_name(&name.s) {
+            return err(format!("\"{}\" is a register name", name.s))
+        }
+        if self.constants.contains_key(&name.s) || self.aliases.contains_key(&name.s) {
+            return err(format!("\"{}\" is already defined", name.s))
+        }
+        Ok(())
+    }
+
+    // Handles the line if it defines something, returning whether it did
+    fn parse_definition(&mut self, mnemonic: &AsmArg, args: &mut [AsmArg])
+            -> Result<bool, ErrInfo> {
+        let is_alias = mnemonic.upper == ".ALIAS";
+        if is_alias || (mnemonic.upper == ".EQU") {
+            if args.len() != 2 {
+                return Err(ErrInfo::new(
+                        format!("Expected 2 args for {}, got {}", mnemonic.s, args.len()),
+                        mnemonic.pos, mnemonic.len()));
+            }
+            self.check_name(&args[0])?;
+        } else if (args.len() == 2) && args[0].str_cmp("=") {
+            // NAME = value
+            self.check_name(mnemonic)?;
+        } else {
+            return Ok(false);
+        }
+
+        let name = if args[0].str_cmp("=") { mnemonic.s.clone() } else { args[0].s.clone() };
+        let value = &mut args[1];
+        self.resolve(value)?;
+        if is_alias {
+            let _ = self.aliases.insert(name, parse_vx(value)?);
+        } else {
+            let _ = self.constants.insert(name, parse_value(value, "constants")?);
+        }
+        Ok(true)
+    }
+}
+
 fn parse_line(line: &str,
-              symbols: &mut HashMap<String, u16>,
-              current_addr: u16)
+              defs: &mut Definitions,
+              current_addr: u32)
                 -> Result<Vec<Box<dyn Instr>>, ErrInfo> {
     // This function will add new symbols to the map and return an
     // instruction object if one was required.
@@ -173,9 +443,8 @@ fn parse_line(line: &str,
     // will take care of that.
     let mut instrs: Vec<Box<dyn Instr>> = vec![];
 
-    let comment_chars = "//";
     let mut no_comments_line = line;
-    if let Some(idx) = no_comments_line.find(comment_chars) {
+    if let Some(idx) = find_comment(no_comments_line) {
         no_comments_line = no_comments_line.split_at(idx).0;
     }
 
@@ -192,7 +461,12 @@ fn parse_line(line: &str,
     if args.is_empty() && mnemonic.s.ends_with(':') {
         // Add a symbol for this address
         let sym_name = mnemonic.s[..mnemonic.len()-1].to_string();
-        if symbols.insert(sym_name, current_addr).is_some() {
+        if current_addr >= MEMORY_SIZE as u32 {
+            return Err(ErrInfo::new(
+                "Label is past the end of memory".to_string(),
+                mnemonic.pos, mnemonic.len()));
+        }
+        if defs.labels.insert(sym_name, current_addr as u16).is_some() {
             return Err(ErrInfo::new(
                 "Label repeated".to_string(),
                 mnemonic.pos, mnemonic.len()));
@@ -200,6 +474,20 @@ fn parse_line(line: &str,
         return Ok(instrs);
     }
 
+    if defs.parse_definition(&mnemonic, &mut args)? {
+        return Ok(instrs);
+    }
+    for arg in &mut args {
+        defs.resolve(arg)?;
+    }
+
+    if let Some(bytes) = parse_data_directive(&mnemonic, &args, current_addr)? {
+        if !bytes.is_empty() {
+            instrs.push(Box::new(DataInstr::create(bytes)));
+        }
+        return Ok(instrs);
+    }
+
     if mnemonic.upper == "JP" {
         // JP can have one or two args
         if args.is_empty() || (args.len() > 2) {
@@ -221,11 +509,37 @@ fn parse_line(line: &str,
                 "CLS"   => instrs.push(Box::new(ClearDisplayInstr::create())),
                 "RET"   => instrs.push(Box::new(RetInstr::create())),
                 "BRK"   => instrs.push(Box::new(SysInstr::create(0xFFF))),
+                "SCR"   => instrs.push(Box::new(ScrollRightInstr::create())),
+                "SCL"   => instrs.push(Box::new(ScrollLeftInstr::create())),
+                "EXIT"  => instrs.push(Box::new(ExitInstr::create())),
+                "LOW"   => instrs.push(Box::new(LowResInstr::create())),
+                "HIGH"  => instrs.push(Box::new(HighResInstr::create())),
+                "AUDIO" => instrs.push(Box::new(AudioInstr::create())),
                 // Single argument
-                ".WORD" => instrs.push(Box::new(WordInstr::create(
-                            parse_extended_addr(&args[0]).unwrap()))),
+                ".WORD" => instrs.push(Box::new(WordInstr::create(parse_word(&args[0])?))),
+                "SCD"   => {
+                    match parse_n(&args[0]) {
+                        Err(e) => return Err(e),
+                        Ok(n) => instrs.push(Box::new(ScrollDownInstr::create(n))),
+                    }
+                }
+                "SCU"   => {
+                    match parse_n(&args[0]) {
+                        Err(e) => return Err(e),
+                        Ok(n) => instrs.push(Box::new(ScrollUpInstr::create(n))),
+                    }
+                }
+                "PLANE" => {
+                    match parse_n(&args[0]) {
+                        Err(e) => return Err(e),
+                        Ok(n) if n > 3 => return Err(ErrInfo::new(
+                                "Plane must be < 4".to_string(),
+                                args[0].pos, args[0].len())),
+                        Ok(n) => instrs.push(Box::new(PlaneInstr::create(n))),
+                    }
+                }
                 "SYS"   => {
-                    match parse_nnn_or_symbol(&args[0]) {
+                    match parse_nnn_or_symbol(&args[0])? {
                         AddressOrSymbol::Symbol(s) => {
                             instrs.push(Box::new(SysInstr::create_with_symbol(s)));
                         }
@@ -237,24 +551,33 @@ fn parse_line(line: &str,
                 "JP"   => {
                     if args.len() == 2 {
                         // Use the parser here to allow different formatting
-                        if parse_vx(&args[0]).unwrap() != 0 {
-                            return Err(ErrInfo::new(
-                                    "Jump plus instruction can only use V0!".to_string(),
-                                    args[0].pos, args[0].len()));
-                        }
-
-                        // JP V0, addr so use the 2nd arg
-                        match parse_nnn_or_symbol(&args[1]) {
-                            AddressOrSymbol::Symbol(s) => {
-                                instrs.push(Box::new(JumpPlusVZeroInstr::create_with_symbol(s)));
+                        let vx = parse_vx(&args[0])?;
+                        if vx != 0 {
+                            // With the jump_uses_vx quirk this is BXNN, so
+                            // the address has to start with X. Labels
+                            // defined later are filled in on the next pass.
+                            let addr = check_addr(&args[1], parse_number(&args[1])?, 0xFFF)?;
+                            if args[1].value.is_ok() && ((addr >> 8) != u16::from(vx)) {
+                                return Err(ErrInfo::new(
+                                        format!("Jump plus V{:X} needs an address from 0x{:X}00 \
+                                                 to 0x{:X}FF", vx, vx, vx),
+                                        args[1].pos, args[1].len()));
                             }
-                            AddressOrSymbol::Address(a) => {
-                                instrs.push(Box::new(JumpPlusVZeroInstr::create(a)));
+                            instrs.push(Box::new(JumpPlusVZeroInstr::create(addr)));
+                        } else {
+                            // JP V0, addr so use the 2nd arg
+                            match parse_nnn_or_symbol(&args[1])? {
+                                AddressOrSymbol::Symbol(s) => {
+                                    instrs.push(Box::new(JumpPlusVZeroInstr::create_with_symbol(s)));
+                                }
+                                AddressOrSymbol::Address(a) => {
+                                    instrs.push(Box::new(JumpPlusVZeroInstr::create(a)));
+                                }
                             }
                         }
                     } else {
                         //Usual JP addr
-                        match parse_nnn_or_symbol(&args[0]) {
+                        match parse_nnn_or_symbol(&args[0])? {
                             AddressOrSymbol::Symbol(s) => {
                                 instrs.push(Box::new(JumpInstr::create_with_symbol(s)));
                             }
@@ -265,7 +588,7 @@ fn parse_line(line: &str,
                     }
                 }
                 "CALL" => {
-                    match parse_nnn_or_symbol(&args[0]) {
+                    match parse_nnn_or_symbol(&args[0])? {
                         AddressOrSymbol::Symbol(s) => {
                             instrs.push(Box::new(CallInstr::create_with_symbol(s)));
                         }
@@ -276,6 +599,18 @@ fn parse_line(line: &str,
                 }
 
                 // Two arguments
+                "LDL"    => {
+                    if !args[0].str_cmp("I") {
+                        return Err(ErrInfo::new(
+                                "First argument of LDL must be I".to_string(),
+                                args[0].pos, args[0].len()));
+                    }
+                    match parse_addr_or_symbol(&args[1], 0xFFFF)? {
+                        AddressOrSymbol::Address(a) => instrs.push(Box::new(LongLoadIInstr::create(a))),
+                        AddressOrSymbol::Symbol(s) => instrs.push(Box::new(
+                                LongLoadIInstr::create_with_symbol(s))),
+                    }
+                }
                 "RND"    => {
                     match parse_vx(&args[0]) {
                         Err(e) => return Err(e),
@@ -293,9 +628,9 @@ fn parse_line(line: &str,
                     } else if let Ok(a) = parse_xx(&args[1]) {
                         instrs.push(Box::new(SkipEqualInstr::create(vx, a)))
                     } else {
-                        return Err(ErrInfo::new(
+                        return Err(byte_or_register_error(&args[1], ErrInfo::new(
                                 "Invalid argument 2 for SE instruction".to_string(),
-                                args[1].pos, args[1].len()));
+                                args[1].pos, args[1].len())));
                     }
                 },
 
@@ -307,9 +642,9 @@ fn parse_line(line: &str,
                     } else if let Ok(a) = parse_xx(&args[1]) {
                         instrs.push(Box::new(SkipNotEqualInstr::create(vx, a)))
                     } else {
-                        return Err(ErrInfo::new(
+                        return Err(byte_or_register_error(&args[1], ErrInfo::new(
                                 "Invalid argument 2 for SNE instruction".to_string(),
-                                args[1].pos, args[1].len()));
+                                args[1].pos, args[1].len())));
                     }
                 }
 
@@ -322,9 +657,9 @@ fn parse_line(line: &str,
                         } else if let Ok(b) = parse_xx(&args[1]) {
                             instrs.push(Box::new(AddByteInstr::create(a, b)));
                         } else {
-                            return Err(ErrInfo::new(
+                            return Err(byte_or_register_error(&args[1], ErrInfo::new(
                                     "Invalid arguments for ADD instruction".to_string(),
-                                    args[1].pos, 0));
+                                    args[1].pos, 0)));
                         }
                     // I, Vx
                     } else if args[0].str_cmp("I") {
@@ -356,19 +691,27 @@ fn parse_line(line: &str,
                         } else if args[1].str_cmp("[I]") {
                             // LD V, [I]
                             instrs.push(Box::new(ReadRegsFromMemInstr::create(a)));
+                        } else if args[1].str_cmp("R") {
+                            // LD V, R
+                            instrs.push(Box::new(ReadRegsFromRplInstr::create(a)));
                         } else {
-                            return Err(ErrInfo::new(
+                            return Err(byte_or_register_error(&args[1], ErrInfo::new(
                                     "Invalid args to LD instruction".to_string(),
-                                    args[0].pos, 0));
+                                    args[0].pos, 0)));
                         }
                     } else if args[0].str_cmp("I") {
-                        // Special 16 bit address sequence
-                        if let Ok(addr) = parse_extended_addr(&args[1]) {
-                            emit_extended_load(&mut instrs, addr);
+                        // Special 16 bit address sequence, for numbers only.
+                        // Labels get the same code wherever they're defined.
+                        let number = {
+                            let constants = &defs.constants;
+                            eval_arg(&args[1].s, &mut |name| constants.get(name).cloned())
+                        };
+                        if let Ok(v) = number {
+                            emit_extended_load(&mut instrs, check_addr(&args[1], v, 0xFFFF)?);
                         } else {
                             // LD I, nnn
                             // Using the *2nd* argument!
-                            match parse_nnn_or_symbol(&args[1]) {
+                            match parse_nnn_or_symbol(&args[1])? {
                                 AddressOrSymbol::Symbol(s) => {
                                     instrs.push(Box::new(LoadIInstr::create_with_symbol(s)));
                                 }
@@ -386,6 +729,15 @@ fn parse_line(line: &str,
                     } else if args[0].str_cmp("F") {
                         // LD F, V
                         instrs.push(Box::new(GetDigitAddrInstr::create(parse_vx(&args[1]).unwrap())));
+                    } else if args[0].str_cmp("PITCH") {
+                        // LD PITCH, V
+                        instrs.push(Box::new(SetPitchInstr::create(parse_vx(&args[1])?)));
+                    } else if args[0].str_cmp("HF") {
+                        // LD HF, V
+                        instrs.push(Box::new(GetBigDigitAddrInstr::create(parse_vx(&args[1])?)));
+                    } else if args[0].str_cmp("R") {
+                        // LD R, V
+                        instrs.push(Box::new(WriteRegsToRplInstr::create(parse_vx(&args[1])?)));
                     } else if args[0].str_cmp("B") {
                         // LD B, V
                         instrs.push(Box::new(StoreBCDInstr::create(parse_vx(&args[1]).unwrap())));
@@ -401,9 +753,9 @@ fn parse_line(line: &str,
 
                 // Only draw has 3
                 "DRW"   => instrs.push(Box::new(DrawSpriteInstr::create(
-                            parse_vx(&args[0]).unwrap(),
-                            parse_vx(&args[1]).unwrap(),
-                            parse_n(&args[2]).unwrap()))),
+                            parse_vx(&args[0])?,
+                            parse_vx(&args[1])?,
+                            parse_n(&args[2])?))),
                 _ => return Err(ErrInfo::new(
                         format!("Unrecognised mnemonic: {}", mnemonic.s),
                         mnemonic.pos, mnemonic.len())),
@@ -421,9 +773,120 @@ fn parse_line(line: &str,
         }
     }
 
+    let size: usize = instrs.iter().map(|ins| usize::from(ins.get_size())).sum();
+    if (current_addr as usize + size) > MEMORY_SIZE {
+        return Err(ErrInfo::new(
+                format!("{} goes past the end of memory", mnemonic.s),
+                mnemonic.pos, mnemonic.len()));
+    }
     Ok(instrs)
 }
 
+// .byte, .text and the directives that leave space, as the bytes they
+// assemble to. None if it's something else.
+fn parse_data_directive(mnemonic: &AsmArg, args: &[AsmArg], current_addr: u32)
+        -> Result<Option<Vec<u8>>, ErrInfo> {
+    let num_args = |min: usize, max: usize| {
+        if (args.len() < min) || (args.len() > max) {
+            let expected = match max {
+                usize::MAX => format!("at least {}", min),
+                _ if min == max => min.to_string(),
+                _ => format!("{} to {}", min, max),
+            };
+            return Err(ErrInfo::new(
+                    format!("Expected {} args for {}, got {}", expected, mnemonic.s, args.len()),
+                    mnemonic.pos, mnemonic.len()));
+        }
+        Ok(())
+    };
+    // Sizes decide where everything after goes, so must be known now
+    let parse_size = |arg: &AsmArg| {
+        match parse_value(arg, "sizes and addresses")? {
+            v if v < 0 => Err(ErrInfo::new("Size can't be negative".to_string(),
+                                           arg.pos, arg.len())),
+            v => Ok(v),
+        }
+    };
+
+    let bytes = match mnemonic.upper.as_str() {
+        ".BYTE" => {
+            num_args(1, usize::MAX)?;
+            args.iter().map(parse_xx).collect::<Result<Vec<u8>, ErrInfo>>()?
+        }
+        ".TEXT" => {
+            num_args(1, 1)?;
+            parse_text(&args[0])?
+        }
+        ".FILL" => {
+            num_args(1, 2)?;
+            let value = match args.get(1) {
+                None => 0,
+                Some(arg) => parse_xx(arg)?,
+            };
+            vec![value; parse_size(&args[0])?.min(0x10000) as usize]
+        }
+        ".SPACE" => {
+            num_args(1, 1)?;
+            vec![0; parse_size(&args[0])?.min(0x10000) as usize]
+        }
+        ".ALIGN" => {
+            num_args(1, 1)?;
+            let align = parse_size(&args[0])?;
+            if align == 0 {
+                return Err(ErrInfo::new("Alignment must be > 0".to_string(),
+                                        args[0].pos, args[0].len()));
+            }
+            let addr = i64::from(current_addr);
+            vec![0; ((align - (addr % align)) % align).min(0x10000) as usize]
+        }
+        ".ORG" => {
+            num_args(1, 1)?;
+            let addr = parse_size(&args[0])?;
+            if addr < i64::from(current_addr) {
+                return Err(ErrInfo::new(
+                        format!("Can't go back to 0x{:X}, already at 0x{:X}", addr, current_addr),
+                        args[0].pos, args[0].len()));
+            }
+            vec![0; (addr - i64::from(current_addr)).min(0x10000) as usize]
+        }
+        _ => return Ok(None),
+    };
+
+    if (current_addr as usize + bytes.len()) > MEMORY_SIZE {
+        return Err(ErrInfo::new(
+                format!("{} goes past the end of memory", mnemonic.s),
+                mnemonic.pos, mnemonic.len()));
+    }
+    Ok(Some(bytes))
+}
+
+// A quoted string, with C style escapes for quotes, backslashes,
+// newlines, tabs and zero bytes
+fn parse_text(arg: &AsmArg) -> Result<Vec<u8>, ErrInfo> {
+    let err = |msg: &str| Err(ErrInfo::new(msg.to_string(), arg.pos, arg.len()));
+    if (arg.len() < 2) || !arg.s.starts_with('"') || !arg.s.ends_with('"') {
+        return err("Expected a string in double quotes");
+    }
+
+    let mut bytes = vec![];
+    let mut chars = arg.s[1..arg.len()-1].chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c) if (c == '\\') || (c == '"') => c,
+                _ => return err("Unknown escape in string"),
+            },
+            c if !c.is_ascii() => return err("Strings can only have ASCII characters"),
+            c => c,
+        };
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
 fn emit_extended_load(instrs: &mut Vec<Box<dyn Instr>>, addr: u16) {
     if addr <= 0xFFF {
         instrs.push(Box::new(LoadIInstr::create(addr)));
@@ -476,9 +939,15 @@ fn handle_vx_mnemonic(instrs: &mut Vec<Box<dyn Instr>>,
         Ok(v) => v,
     };
 
+    // Shifts take an optional VY, only used by the shift quirk
+    let y = match args.get(1) {
+        None => 0,
+        Some(arg) => parse_vx(arg)?,
+    };
+
     match mnemonic.upper.as_str() {
-        "SHR"   => instrs.push(Box::new(ShrRegInstr::create(x))),
-        "SHL"   => instrs.push(Box::new(ShlRegInstr::create(x))),
+        "SHR"   => instrs.push(Box::new(ShrRegInstr::create(x, y))),
+        "SHL"   => instrs.push(Box::new(ShlRegInstr::create(x, y))),
         "SKP"   => instrs.push(Box::new(SkipKeyIfPressedInstr::create(x))),
         "SKNP"  => instrs.push(Box::new(SkipKeyIfNotPressedInstr::create(x))),
         _ => panic!("Unknown mnemonic {} with VX args", mnemonic.s),
@@ -506,6 +975,8 @@ fn handle_vxvy_mnemonic(instrs: &mut Vec<Box<dyn Instr>>,
         "AND"    => instrs.push(Box::new(AndRegInstr::create(x, y))),
         "SUB"    => instrs.push(Box::new(SubRegInstr::create(x, y))),
         "SUBN"   => instrs.push(Box::new(SubNRegInstr::create(x, y))),
+        "SAVE"   => instrs.push(Box::new(SaveRegRangeInstr::create(x, y))),
+        "LOAD"   => instrs.push(Box::new(LoadRegRangeInstr::create(x, y))),
         _ => panic!("Unknown mnemonic {} with VXVY args", mnemonic.s),
     };
 
@@ -521,18 +992,20 @@ enum ArgsType {
 fn get_args_type(mnemonic: &AsmArg) -> ArgsType {
     match mnemonic.upper.as_str() {
         "SHR" | "SHL" | "SKP" | "SKNP" => ArgsType::VX,
-        "OR" | "XOR" | "AND" | "SUB" | "SUBN" => ArgsType::VXVY,
+        "OR" | "XOR" | "AND" | "SUB" | "SUBN" | "SAVE" | "LOAD" => ArgsType::VXVY,
         _ => ArgsType::Custom,
     }
 }
 
 fn check_num_args(mnemonic: &AsmArg, num: usize) -> Result<usize, ErrInfo> {
     let expected: usize = match &mnemonic.upper[..] {
-        "CLS" | "RET" | "BRK" => 0,
-        "SYS" | "CALL" | "SHR" | "SHL" | "SKP" | "SKNP" | ".WORD" => 1,
+        "CLS" | "RET" | "BRK" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "AUDIO" => 0,
+        "SHR" | "SHL" if num == 2 => 2,
+        "SYS" | "CALL" | "SHR" | "SHL" | "SKP" | "SKNP" | ".WORD" | "SCD" | "SCU" | "PLANE" => 1,
         // Some variants of LD only have 1 variable arg, but for asm
         // purposes they all have two
-        "LD" | "ADD" | "SE" | "SNE" | "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "RND" => 2,
+        "LD" | "ADD" | "SE" | "SNE" | "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "RND" |
+        "SAVE" | "LOAD" | "LDL" => 2,
         "DRW" => 3,
         _ => return Err(ErrInfo::new(
                 format!("Unrecognised mnemonic: {}", mnemonic.s),
@@ -547,22 +1020,24 @@ fn check_num_args(mnemonic: &AsmArg, num: usize) -> Result<usize, ErrInfo> {
 }
 
 fn parse_vx(arg: &AsmArg) -> Result<u8, ErrInfo> {
-    let c1 = arg.s.chars().next().unwrap();
-    if (c1 != 'V') && (c1 != 'v') {
-        return Err(ErrInfo::new(
-                "VX arg does not begin with \"V\"".to_string(),
-                arg.pos, arg.len()));
+    match arg.register {
+        Some(v) => Ok(v),
+        None => parse_vx_str(&arg.s).map_err(|msg| ErrInfo::new(msg, arg.pos, arg.len())),
+    }
+}
+
+fn parse_vx_str(s: &str) -> Result<u8, String> {
+    if !s.starts_with('V') && !s.starts_with('v') {
+        return Err("VX arg does not begin with \"V\"".to_string());
     }
 
-    let num = &arg.s[1..];
+    let num = &s[1..];
     let idx: u8;
 
     match num.parse::<u8>() {
         Err(_) => {
-            match u8::from_str_radix(&arg.s[1..], 16) {
-                Err(_) => return Err(
-                    ErrInfo::new(format!("Invalid V register: \"{}\"", arg.s),
-                    arg.pos, arg.len())),
+            match u8::from_str_radix(num, 16) {
+                Err(_) => return Err(format!("Invalid V register: \"{}\"", s)),
                 Ok(v) => idx = v,
             }
         }
@@ -570,84 +1045,108 @@ fn parse_vx(arg: &AsmArg) -> Result<u8, ErrInfo> {
     }
 
     if idx > 0xF {
-        return Err(ErrInfo::new(
-                "V register index cannot be > 0xF".to_string(),
-                arg.pos, arg.len()));
+        return Err("V register index cannot be > 0xF".to_string());
     }
 
     Ok(idx)
 }
 
-fn parse_hex(arg: &AsmArg) -> Result<u16, ErrInfo> {
-    if arg.len() < 2 {
-        return Err(ErrInfo::new("Arg too short to be a hex number".to_string(), arg.pos, arg.len()));
-    }
-    if &arg.s[..2] != "0x" {
-        return Err(ErrInfo::new("Hex number must start with \"0x\"".to_string(), arg.pos, arg.len()));
+// The value of a number or expression. Ones using labels that aren't
+// defined yet are 0 for now, parse_asm assembles them again later.
+fn parse_number(arg: &AsmArg) -> Result<i64, ErrInfo> {
+    match arg.value {
+        Ok(v) => Ok(v),
+        Err(ExprError::Unresolved(_)) => Ok(0),
+        Err(ExprError::Invalid(ref msg)) => Err(ErrInfo::new(msg.clone(), arg.pos, arg.len())),
     }
-    match u16::from_str_radix(&arg.s[2..], 16) {
-        Err(e) => Err(ErrInfo::new(format!("Invalid hex number: {}", e.to_string()), arg.pos, arg.len())),
-        Ok(v) => Ok(v), 
+}
+
+// For args that can be a register or a byte but are neither. If it
+// isn't meant to be a register, say what's wrong with it as a number.
+fn byte_or_register_error(arg: &AsmArg, err: ErrInfo) -> ErrInfo {
+    match parse_xx(arg) {
+        Err(e) if !is_register_name(&arg.s) => e,
+        _ => err,
     }
 }
 
+// Bytes can be given as signed numbers too, e.g. -1 for 0xFF
 fn parse_xx(arg: &AsmArg) -> Result<u8, ErrInfo> {
-    let v = match parse_hex(arg) {
-        Err(_) => match arg.s.parse::<u16>() {
-                Err(_) => return Err(ErrInfo::new(
-                        "Inalid byte argument".to_string(),
-                        arg.pos, arg.len())),
-                Ok(v) => v,
-        },
-        Ok(v) => v,
-    };
-
-    // TODO: some out of range error, as opposed to not recognised
+    let v = parse_number(arg)?;
     if v > 0xff {
         return Err(ErrInfo::new("Byte argument larger than 0xFF".to_string(),
                     arg.pos, arg.len()));
     }
+    if v < -0x80 {
+        return Err(ErrInfo::new("Byte argument smaller than -0x80".to_string(),
+                    arg.pos, arg.len()));
+    }
     Ok(v as u8)
 }
 
-fn parse_nnn(arg: &AsmArg) -> Result<u16, ErrInfo> {
-    match parse_hex(arg) {
-        Err(e) => Err(e),
-        Ok(v) => {
-            if v > 0xfff {
-                return Err(ErrInfo::new(
-                        "Address argument larger than 0xFFF".to_string(),
-                        arg.pos, arg.len()));
-            }
-            Ok(v)
-        }
+// A 16 bit value, which can be signed like bytes
+fn parse_word(arg: &AsmArg) -> Result<u16, ErrInfo> {
+    let v = parse_number(arg)?;
+    if !(-0x8000..=0xffff).contains(&v) {
+        return Err(ErrInfo::new("Word argument must be from -0x8000 to 0xFFFF".to_string(),
+                    arg.pos, arg.len()));
     }
+    Ok(v as u16)
 }
 
-fn parse_extended_addr(arg: &AsmArg) -> Result<u16, ErrInfo> {
-    match parse_hex(arg) {
-        Err(e) => Err(e),
-        Ok(v) => Ok(v),
+fn check_addr(arg: &AsmArg, v: i64, max: u16) -> Result<u16, ErrInfo> {
+    if v < 0 {
+        return Err(ErrInfo::new("Address argument can't be negative".to_string(),
+                    arg.pos, arg.len()));
+    }
+    if v > i64::from(max) {
+        return Err(ErrInfo::new(format!("Address argument larger than 0x{:X}", max),
+                    arg.pos, arg.len()));
     }
+    Ok(v as u16)
 }
 
-fn parse_nnn_or_symbol(arg: &AsmArg) -> AddressOrSymbol {
-    match parse_nnn(arg) {
-        Ok(v) => AddressOrSymbol::Address(v),
-        // Try to lookup anything else as a symbol
-        Err(_) => AddressOrSymbol::Symbol(arg.s.to_owned()),
+fn parse_addr_or_symbol(arg: &AsmArg, max: u16) -> Result<AddressOrSymbol, ErrInfo> {
+    match arg.value {
+        Ok(v) => check_addr(arg, v, max).map(AddressOrSymbol::Address),
+        // Look up symbols, or expressions using them, once all the labels
+        // are known
+        Err(ExprError::Unresolved(_)) => Ok(AddressOrSymbol::Symbol(arg.s.to_owned())),
+        Err(_) if could_be_label(&arg.s) => Ok(AddressOrSymbol::Symbol(arg.s.to_owned())),
+        Err(ExprError::Invalid(ref msg)) => Err(ErrInfo::new(msg.clone(), arg.pos, arg.len())),
+    }
+}
+
+// Labels can be any text, so an arg that isn't an expression might still
+// be one, e.g. "JP bar:". Ones using operators or brackets can't be.
+fn could_be_label(s: &str) -> bool {
+    !s.starts_with(|c: char| c.is_ascii_digit()) &&
+        !s.contains(|c: char| c.is_whitespace() || "()|^&<>+-*/%~'\"".contains(c))
+}
+
+fn parse_nnn_or_symbol(arg: &AsmArg) -> Result<AddressOrSymbol, ErrInfo> {
+    parse_addr_or_symbol(arg, 0xFFF)
+}
+
+// For values needed straight away, which can only use what's defined
+// before them. user is what needs it, e.g. "constants".
+fn parse_value(arg: &AsmArg, user: &str) -> Result<i64, ErrInfo> {
+    match arg.value {
+        Ok(v) => Ok(v),
+        Err(ExprError::Unresolved(ref name)) => Err(ErrInfo::new(
+                format!("\"{}\" is not defined yet, {} can only use names \
+                         defined before them", name, user), arg.pos, arg.len())),
+        Err(ExprError::Invalid(ref msg)) => Err(ErrInfo::new(msg.clone(), arg.pos, arg.len())),
     }
 }
 
 fn parse_n(arg: &AsmArg) -> Result<u8, ErrInfo> {
-    match arg.s.parse::<u8>() {
-        Err(msg) => Err(ErrInfo::new(msg.to_string(), arg.pos, arg.len())),
-        Ok(v) => {
-            if v > 15 {
-                Err(ErrInfo::new("Nibble must be < 16".to_string(), arg.pos, arg.len()))
-            } else {
-                Ok(v)
-            }
-        }
+    let v = parse_number(arg)?;
+    if v > 15 {
+        Err(ErrInfo::new("Nibble must be < 16".to_string(), arg.pos, arg.len()))
+    } else if v < 0 {
+        Err(ErrInfo::new("Nibble can't be negative".to_string(), arg.pos, arg.len()))
+    } else {
+        Ok(v as u8)
     }
 }
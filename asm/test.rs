@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod test {
     use asm::*;
+    use system::instrs_to_rom;
 
     #[test]
     fn expected_parse_vx() {
@@ -54,6 +55,8 @@ mod test {
             "CALL 0x123",
             "SHR V0",
             "SHL V0",
+            "SHR V2, V3",
+            "SHL V4, V5",
             "SKP V0",
             "SKNP V0",
             "DRW V0, V1, 12",
@@ -82,6 +85,23 @@ mod test {
             "LD B, V0",
             "LD [I], V0",
             ".word 0x1234",
+            "SCD 3",
+            "SCR",
+            "SCL",
+            "EXIT",
+            "LOW",
+            "HIGH",
+            "DRW V0, V1, 0",
+            "LD HF, V0",
+            "LD R, V7",
+            "LD V7, R",
+            "SCU 2",
+            "SAVE V1, V5",
+            "LOAD V5, V1",
+            "PLANE 3",
+            "AUDIO",
+            "LD PITCH, V3",
+            "LDL I, 0x1234",
         ].iter().map(|x| x.to_string()).collect::<Vec<String>>();
         let asm_str = expected.iter().fold(String::from(""), |acc, n| acc + "\n" + n);
         let got = parse_asm_str(&asm_str).unwrap();
@@ -254,9 +274,9 @@ mod test {
         
         match parse_asm_str(&asm) {
             Err(msg) => assert_eq!(
-                "<str>:0:0: error: Could not resolve symbol \"aardvark\"\n\
-                \n\
-                ^",
+                "<str>:4:15: error: Could not resolve symbol \"aardvark\"\n            \
+                JP aardvark\n               \
+                ^~~~~~~~",
                 msg),
             Ok(_) => panic!("Should have failed!"),
         };
@@ -283,6 +303,18 @@ mod test {
         let expected: Vec<u16> = vec![0x0FFF];
         assert_asm_bitpatterns(&asm, &expected);
     }
+
+    #[test]
+    fn jump_plus_vx() {
+        // The BXNN form from the jump_uses_vx quirk
+        let asm = "
+            JP V0, 0x300
+            JP V3, 0x310
+            JP v2, table
+        table:".to_string();
+        let expected: Vec<u16> = vec![0xB300, 0xB310, 0xB206];
+        assert_asm_bitpatterns(&asm, &expected);
+    }
     
     #[test]
     #[should_panic(expected="Cannot get address for unresolved symbol \"xyz\"")]
@@ -352,6 +384,27 @@ mod test {
         let asm4 = "LD I, 0x123".to_string();
         let expected4: Vec<u16> = vec![0xA123];
         assert_asm_bitpatterns(&asm4, &expected4);
+
+        // Constants are numbers too
+        let asm5 = "ADDR = 0x1000+11\nLD I, ADDR".to_string();
+        assert_asm_bitpatterns(&asm5, &expected3);
+
+        // Labels never are, wherever they're defined
+        let asm6 = "JP start\nsprite:\n.byte 1, 2\nstart:\nLD I, sprite\nLD I, later\nlater:";
+        assert_eq!(vec![0x12, 0x04, 0x01, 0x02, 0xA2, 0x02, 0xA2, 0x08],
+                   instrs_to_rom(&parse_asm_str(asm6).unwrap()));
+    }
+
+    #[test]
+    fn long_load_is_four_bytes() {
+        let asm = "
+            LDL I, 0x1234
+            LDL I, end
+        end:
+            JP end".to_string();
+        let instrs = parse_asm_str(&asm).unwrap();
+        assert_eq!(vec![0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00, 0x02, 0x08, 0x12, 0x08],
+                   instrs_to_rom(&instrs));
     }
 
     #[test]
@@ -368,6 +421,152 @@ mod test {
         assert_asm_bitpatterns(&asm, &expected);
     }
 
+    #[test]
+    fn constants_and_aliases() {
+        let asm = "
+            .equ WIDTH 64
+            HEIGHT = 0x20
+            ROWS = HEIGHT
+            .EQU sprite_addr 0x300
+            .alias player_x V7
+            .alias x player_x
+            LD player_x, WIDTH
+            SE x, HEIGHT
+            ADD V1, ROWS
+            DRW player_x, v8, 4
+            LD I, sprite_addr
+            JP V0, sprite_addr
+            SHR x, player_x
+            .word WIDTH".to_string();
+        let expected: Vec<u16> = vec![0x6740, 0x3720, 0x7120, 0xD784, 0xA300, 0xB300,
+                                      0x8776, 0x0040];
+        assert_asm_bitpatterns(&asm, &expected);
+        assert_eq!(expected.len(), parse_asm_str(&asm).unwrap().len());
+
+        // Names are case sensitive
+        assert!(parse_asm_str("width = 1
+LD V0, WIDTH").is_err());
+    }
+
+    #[test]
+    fn expression_evaluation() {
+        let tests: Vec<(&str, i64)> = vec![
+            ("1+2*3", 7),
+            ("(1+2)*3", 9),
+            ("( 1 + 2 ) * 3", 9),
+            ("-2*-3", 6),
+            ("10-4-3", 3),
+            ("0x10>>2", 4),
+            ("1<<4|1", 0x11),
+            ("~0&0xF0", 0xF0),
+            ("7%4^1", 2),
+            ("0b1011_0000", 0xB0),
+            ("'A'+1", 66),
+            ("' '", 32),
+            ("hi(0x1234)", 0x12),
+            ("LO(0x1234)", 0x34),
+            ("hi(WIDTH*8)", 0x02),
+        ];
+        for (input, expected) in tests {
+            let mut lookup = |name: &str| if name == "WIDTH" { Some(64) } else { None };
+            assert_eq!(Ok(expected), expr::eval_expr(input, &mut lookup), "{}", input);
+        }
+
+        let errors = [
+            ("start+2", expr::ExprError::Unresolved("start".to_string())),
+            ("8/size", expr::ExprError::Unresolved("size".to_string())),
+            ("(start+2", expr::ExprError::Invalid("Expected \")\"".to_string())),
+            ("", expr::ExprError::Invalid("Expected a value".to_string())),
+            ("1+", expr::ExprError::Invalid("Expected a value".to_string())),
+            ("(1+2", expr::ExprError::Invalid("Expected \")\"".to_string())),
+            ("1 2", expr::ExprError::Invalid("Unexpected \"2\"".to_string())),
+            ("0x", expr::ExprError::Invalid("Invalid number \"0x\"".to_string())),
+            ("0b12", expr::ExprError::Invalid("Invalid number \"0b12\"".to_string())),
+            ("5/0", expr::ExprError::Invalid("Division by zero".to_string())),
+            ("1<<64", expr::ExprError::Invalid("Expression overflows".to_string())),
+            ("''", expr::ExprError::Invalid("Expected a character".to_string())),
+        ];
+        for &(input, ref expected) in errors.iter() {
+            assert_eq!(Err(expected.clone()), expr::eval_expr(input, &mut |_| None), "{}", input);
+        }
+    }
+
+    #[test]
+    fn expression_operands() {
+        let asm = "
+            WIDTH = 64
+            .equ HALF WIDTH/2
+        start:
+            LD V0, (WIDTH/2)-4
+            LD V1, 0b10110000
+            LD V2, 'A'
+            ADD V3, -1
+            LD V4, hi(sprites)
+            LD V5, lo( sprites )
+            JP start+2
+            LD I, sprites+2
+            SE V6, ((1 + 2) * 3)
+            LD V7, ' '
+            LD V8, ~0&0xF0
+            DRW V0, V1, lo(end-sprites)
+            RND V0, HALF
+            .word sprites
+            .word -2
+        sprites:
+            .word 0x1234
+        end:".to_string();
+        let expected: Vec<u16> = vec![0x601C, 0x61B0, 0x6241, 0x73FF, 0x6402, 0x651E, 0x1202,
+                                      0xA220, 0x3609, 0x6720, 0x68F0, 0xD012, 0xC020, 0x021E,
+                                      0xFFFE, 0x1234];
+        let mut warnings = vec![];
+        let instrs = parse_asm_str_with_warnings(&asm, &mut warnings).unwrap();
+        assert_eq!(expected, instrs.iter().map(|i| i.get_opcode()).collect::<Vec<u16>>());
+        assert_eq!(Vec::<String>::new(), warnings);
+    }
+
+    #[test]
+    fn data_directives() {
+        let asm = "
+            JP start
+        sprite:
+            .byte 0x80, 0b11000000, -1
+            .text \"Hi, \\\"you\\\"\\n\" // A comment
+            .align 2
+        start:
+            LD I, sprite
+            .fill 3, 0xAA
+            .space 2
+            .org 0x220
+        end:
+            JP end
+            .byte lo(end), hi(end)
+            .text \"a//b\"";
+        let mut warnings = vec![];
+        let (instrs, info) = parse_asm_with_info(asm, "<str>", &mut warnings).unwrap();
+        let mut expected = vec![0x12, 0x10, 0x80, 0xC0, 0xFF];
+        expected.extend_from_slice(b"Hi, \"you\"\n");
+        expected.extend_from_slice(&[0x00, 0xA2, 0x02, 0xAA, 0xAA, 0xAA, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x00; 9]);
+        expected.extend_from_slice(&[0x12, 0x20, 0x20, 0x02, b'a', b'/', b'/', b'b']);
+        assert_eq!(expected, instrs_to_rom(&instrs));
+
+        assert_eq!(Some(&0x210), info.labels.get("start"));
+        assert_eq!(Some(&0x220), info.labels.get("end"));
+        assert_eq!(Some(4), info.line_for_addr(0x202));
+        assert_eq!(Some(5), info.line_for_addr(0x205));
+        assert_eq!(".byte 0x80, 0xC0, 0xFF", instrs[1].repr());
+
+        // Nothing is added when already there
+        let instrs = parse_asm_str("CLS\n.align 2\n.org 0x202\n.space 0\nCLS").unwrap();
+        assert_eq!(vec![0x00, 0xE0, 0x00, 0xE0], instrs_to_rom(&instrs));
+
+        // Up to the very end of memory
+        let instrs = parse_asm_str(".org 0xFFF0\n.fill 16, 1").unwrap();
+        assert_eq!(vec![1; 16], instrs_to_rom(&instrs)[0xFFF0-0x200..]);
+        let instrs = parse_asm_str(".org 0xFFFE\nCLS").unwrap();
+        assert_eq!(vec![0x00, 0xE0], instrs_to_rom(&instrs)[0xFFFE-0x200..]);
+    }
+
     #[test]
     fn mnemonic_formatting_retained_err() {
         let asm = "CaTfOoD V0, V1".to_string();
@@ -480,6 +679,36 @@ OR V0, 3
 <str>:0:3: error: VX arg does not begin with \"V\"
 OR 1, vf
    ^"),
+("JP V1, 0x300",
+"\
+<str>:0:7: error: Jump plus V1 needs an address from 0x100 to 0x1FF
+JP V1, 0x300
+       ^~~~~"),
+(".org 0x1000\nfoo:\nLD I, foo",
+"\
+<str>:2:6: error: Address argument larger than 0xFFF
+LD I, foo
+      ^~~"),
+("LD I, foo\n.org 0x1000\nfoo:",
+"\
+<str>:0:6: error: Address 0x1000 from \"foo\" is out of range
+LD I, foo
+      ^~~"),
+("LD PITCH, pitch",
+"\
+<str>:0:10: error: VX arg does not begin with \"V\"
+LD PITCH, pitch
+          ^~~~~"),
+("LD HF, 5",
+"\
+<str>:0:7: error: VX arg does not begin with \"V\"
+LD HF, 5
+       ^"),
+("LD R, V16",
+"\
+<str>:0:6: error: V register index cannot be > 0xF
+LD R, V16
+      ^~~"),
 ("ADD I, nonsense",
 "\
 <str>:0:7: error: VX arg does not begin with \"V\"
@@ -504,6 +733,181 @@ thing:
 <str>:2:0: error: Label repeated
 thing:
 ^~~~~~"),
+("X = 1\nX = 2",
+"\
+<str>:1:0: error: \"X\" is already defined
+X = 2
+^"),
+(".alias player V1\n.equ player 2",
+"\
+<str>:1:5: error: \"player\" is already defined
+.equ player 2
+     ^~~~~~"),
+("VA = 3",
+"\
+<str>:0:0: error: \"VA\" is a register name
+VA = 3
+^~"),
+(".equ dt 60",
+"\
+<str>:0:5: error: \"dt\" is a register name
+.equ dt 60
+     ^~"),
+(".equ 2much 60",
+"\
+<str>:0:5: error: Invalid name \"2much\"
+.equ 2much 60
+     ^~~~~"),
+(".equ SIZE",
+"\
+<str>:0:0: error: Expected 2 args for .equ, got 1
+.equ SIZE
+^~~~"),
+("SIZE = 12abc",
+"\
+<str>:0:7: error: Invalid number \"12abc\"
+SIZE = 12abc
+       ^~~~~"),
+("SIZE = big",
+"\
+<str>:0:7: error: \"big\" is not defined yet, constants can only use names defined before them
+SIZE = big
+       ^~~"),
+(".alias pos 0x12",
+"\
+<str>:0:11: error: VX arg does not begin with \"V\"
+.alias pos 0x12
+           ^~~~"),
+("LD V0, 1/0",
+"\
+<str>:0:7: error: Division by zero
+LD V0, 1/0
+       ^~~"),
+("ADD V0, -129",
+"\
+<str>:0:8: error: Byte argument smaller than -0x80
+ADD V0, -129
+        ^~~~"),
+("JP -2",
+"\
+<str>:0:3: error: Address argument can't be negative
+JP -2
+   ^~"),
+("LDL I, 0x10000",
+"\
+<str>:0:7: error: Address argument larger than 0xFFFF
+LDL I, 0x10000
+       ^~~~~~~"),
+("LD V0, lo(nowhere)",
+"\
+<str>:0:7: error: Could not resolve symbol \"nowhere\"
+LD V0, lo(nowhere)
+       ^~~~~~~~~~~"),
+("JP 1+",
+"\
+<str>:0:3: error: Expected a value
+JP 1+
+   ^~"),
+("CALL (foo",
+"\
+<str>:0:5: error: Expected \")\"
+CALL (foo
+     ^~~~"),
+("JP nowhere+2",
+"\
+<str>:0:3: error: Could not resolve symbol \"nowhere\"
+JP nowhere+2
+   ^~~~~~~~~"),
+("JP end+0x1000\nend:",
+"\
+<str>:0:3: error: Address 0x1202 from \"end+0x1000\" is out of range
+JP end+0x1000
+   ^~~~~~~~~~"),
+("CALL far\n.org 0x1000\nfar:",
+"\
+<str>:0:5: error: Address 0x1000 from \"far\" is out of range
+CALL far
+     ^~~"),
+(".byte",
+"\
+<str>:0:0: error: Expected at least 1 args for .byte, got 0
+.byte
+^~~~~"),
+(".fill 1, 2, 3",
+"\
+<str>:0:0: error: Expected 1 to 2 args for .fill, got 3
+.fill 1, 2, 3
+^~~~~"),
+(".byte 1, 0x100",
+"\
+<str>:0:9: error: Byte argument larger than 0xFF
+.byte 1, 0x100
+         ^~~~~"),
+(".text hello",
+"\
+<str>:0:6: error: Expected a string in double quotes
+.text hello
+      ^~~~~"),
+(".text \"a\\qb\"",
+"\
+<str>:0:6: error: Unknown escape in string
+.text \"a\\qb\"
+      ^~~~~~"),
+(".space later\nlater:",
+"\
+<str>:0:7: error: \"later\" is not defined yet, sizes and addresses can only use names defined before them
+.space later
+       ^~~~~"),
+(".fill -1",
+"\
+<str>:0:6: error: Size can't be negative
+.fill -1
+      ^~"),
+(".align 0",
+"\
+<str>:0:7: error: Alignment must be > 0
+.align 0
+       ^"),
+("CLS\n.org 0x200",
+"\
+<str>:1:5: error: Can't go back to 0x200, already at 0x202
+.org 0x200
+     ^~~~~"),
+(".space 0xFE01",
+"\
+<str>:0:0: error: .space goes past the end of memory
+.space 0xFE01
+^~~~~~"),
+(".org 0xFFF0\n.fill 17",
+"\
+<str>:1:0: error: .fill goes past the end of memory
+.fill 17
+^~~~~"),
+(".org 0xFFFF\nCLS",
+"\
+<str>:1:0: error: CLS goes past the end of memory
+CLS
+^~~"),
+(".org 0xFFFE\nLDL I, 0",
+"\
+<str>:1:0: error: LDL goes past the end of memory
+LDL I, 0
+^~~"),
+(".org 0xFFFE\nCLS\nend:",
+"\
+<str>:2:0: error: Label is past the end of memory
+end:
+^~~~"),
+("BIG = 0x100\nRND V0, BIG",
+"\
+<str>:1:8: error: Byte argument larger than 0xFF
+RND V0, BIG
+        ^~~"),
+("ROWS = 16\nDRW V0, V1, ROWS",
+"\
+<str>:1:12: error: Nibble must be < 16
+DRW V0, V1, ROWS
+            ^~~~"),
         ];
         for (input, expected_err) in tests {
             match parse_asm_str(&String::from(input)) {
@@ -514,4 +918,38 @@ thing:
             }
         }
     }
+
+    #[test]
+    fn debug_info() {
+        let mut warnings = vec![];
+        let (_, info) = parse_asm_with_info("\
+start:
+    LD V0, 1
+
+    // A comment
+    LD I, 0x234
+loop:
+    JP loop
+    CLS", "<str>", &mut warnings).unwrap();
+
+        let lines: Vec<(u16, usize)> = info.lines.iter().map(|(a, l)| (*a, *l)).collect();
+        assert_eq!(vec![(0x200, 2), (0x202, 5), (0x204, 7), (0x206, 8)], lines);
+        assert_eq!(Some(7), info.line_for_addr(0x204));
+        assert_eq!(None, info.line_for_addr(0x203));
+
+        // Lines without instructions move to the next one that has one
+        assert_eq!(Some((0x200, 2)), info.addr_for_line(1));
+        assert_eq!(Some((0x202, 5)), info.addr_for_line(3));
+        assert_eq!(None, info.addr_for_line(9));
+
+        assert_eq!(Some(("start", 0x200)), info.label_for_addr(0x202));
+        assert_eq!(Some(("loop", 0x204)), info.label_for_addr(0x206));
+        assert_eq!(None, info.label_for_addr(0x100));
+
+        let map = info.symbol_map();
+        assert_eq!("0x0200 start\n0x0204 loop\n", map);
+        assert_eq!(info.labels, DebugInfo::parse_symbol_map(&map, "<str>").unwrap().labels);
+        assert_eq!(Err("a.sym:2: error: Expected \"<address> <label>\"".to_string()),
+                   DebugInfo::parse_symbol_map("0x200 start\n512 loop", "a.sym"));
+    }
 }
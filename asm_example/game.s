@@ -1,48 +1,59 @@
+// Registers used throughout
+.alias player_x V7
+.alias player_y V8
+.alias object_x V4
+.alias object_y V5
+.alias score V6
+
+WIN_SCORE = 11
+WIDTH = 64
+HEIGHT = 32
+
   JP setup
 
 player_data:
   // 4x4 player sprite
-  .word 0x0F0F
-  .word 0x0F0F
+  .byte 0x0F, 0x0F, 0x0F, 0x0F
 
 object_data:
-  .word 0x1000
+  .byte 0x10
 
-score_temp: 
+score_temp:
   // To store BCD
-  .word 0x0000
-  .word 0x0000
+  .space 3
+  // Keep the code after this at an even address
+  .align 2
 
 draw_player:
   LD I, player_data
-  DRW, V7, V8, 4
+  DRW, player_x, player_y, 4
   RET
 
 setup:
   //Put player in center of screen
   //(set pixels are on the right side of the sprite) 
-  LD V7, 26 // (64/2)-4-2
-  LD V8, 14 // (32/2)-2
+  LD player_x, (WIDTH/2)-4-2
+  LD player_y, (HEIGHT/2)-2
 
   //Timer reset value
   LD V10, 0xF0
   LD DT, V10
 
   //Score
-  LD V6, 0x00
+  LD score, 0x00
 
   CALL place_object
 
   JP game
 
 place_object:
-  RND V4, 0x3F
-  RND V5, 0x1F
+  RND object_x, 0x3F
+  RND object_y, 0x1F
   RET
 
 draw_object:
   LD I, object_data
-  DRW V4, V5, 1
+  DRW object_x, object_y, 1
   RET
 
 move_player:
@@ -55,7 +66,7 @@ move_player:
   LD V3, 5
   SKP V3
   JP left 
-  SUB V8, V2
+  SUB player_y, V2
   LD V0, 1
 
 left:
@@ -63,7 +74,7 @@ left:
   LD V3, 7
   SKP V3
   JP down
-  SUB V7, V2
+  SUB player_x, V2
   LD V0, 1
 
 down:
@@ -71,7 +82,7 @@ down:
   LD V3, 8
   SKP V3
   JP right
-  ADD V8, V2
+  ADD player_y, V2
   LD V0, 1
 
 right:
@@ -79,7 +90,7 @@ right:
   LD V3, 9
   SKP V3
   JP return
-  ADD V7, v2
+  ADD player_x, v2
   LD V0, 1
 
 return:
@@ -90,7 +101,7 @@ return:
 draw_score:
   LD I, score_temp
   // Writes BCD to memory
-  LD B, V6
+  LD B, score
   // Get it back in parts
   LD V2, [I]
   // Get digits and draw
@@ -158,10 +169,10 @@ game:
   CALL draw_player
   SE VF, 1
   JP no_hit
-  ADD V6, 1
+  ADD score, 1
   LD V0, 0x10
   LD ST, V0
-  SNE V6, 11
+  SNE score, WIN_SCORE
   JP game_win
   CALL place_object
 no_hit:
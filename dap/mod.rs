@@ -0,0 +1,685 @@
+extern crate serde_json;
+
+use dap::serde_json::{Value, json};
+use system::{Chip8System, make_system, instrs_to_rom};
+use system::debug::{Debugger, DebugStop, Register, ALL_REGISTERS};
+use system::quirks::{Quirks, QUIRKS_PRESET_NAMES};
+use asm::{parse_asm_with_info, DebugInfo};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+mod test;
+
+// There's only one thing running
+const THREAD_ID: u64 = 1;
+
+// variablesReference of each scope
+const REGISTERS_REF: u64 = 1;
+const STACK_REF: u64 = 2;
+const LABELS_REF: u64 = 3;
+
+// Files with these extensions are assembled, anything else is a ROM
+const ASM_EXTENSIONS: [&str; 2] = ["s", "asm"];
+
+// Where requests come from and where responses and events go
+pub trait Connection {
+    // Waits for the next message, None once the client has gone
+    fn receive(&mut self) -> Option<Value>;
+    // Doesn't wait, None if nothing has arrived
+    fn try_receive(&mut self) -> Option<Value>;
+    fn send(&mut self, message: Value);
+}
+
+// What to do once a request has been answered
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Resume {
+    // Stop at the entry point or run, depending on the launch request
+    Start,
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+    Pause,
+}
+
+// A Debug Adapter Protocol server, for debugging from an editor.
+// Assembly files get breakpoints by line, ROMs only have the
+// disassembly and instruction breakpoints.
+#[derive(Default)]
+pub struct DapServer {
+    seq: u64,
+    c8: Option<Chip8System>,
+    debugger: Debugger,
+    program: String,
+    info: Option<DebugInfo>,
+    stop_on_entry: bool,
+    // Running starts once the program is launched and
+    // the editor has sent its breakpoints, in either order
+    launched: bool,
+    configured: bool,
+    source_breakpoints: Vec<u16>,
+    instruction_breakpoints: Vec<u16>,
+    resume: Option<Resume>,
+    running: bool,
+    finished: bool,
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer::default()
+    }
+
+    // Set once the client has disconnected
+    pub fn has_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Handle messages until the client disconnects or goes away
+    pub fn serve(&mut self, connection: &mut dyn Connection) {
+        while !self.finished {
+            match connection.receive() {
+                None => break,
+                Some(message) => self.handle_message(&message, connection),
+            }
+        }
+    }
+
+    pub fn handle_message(&mut self, message: &Value, connection: &mut dyn Connection) {
+        // Clients don't send anything but requests
+        if message["type"] != "request" {
+            return
+        }
+        let command = message["command"].as_str().unwrap_or("");
+        let args = &message["arguments"];
+
+        let result = self.request(command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(msg) => response["message"] = json!(msg),
+        }
+        self.send(connection, response);
+
+        if command == "launch" && self.launched {
+            self.send_event(connection, "initialized", json!({}));
+        }
+        if let Some(resume) = self.resume.take() {
+            self.resume(resume, connection);
+        }
+    }
+
+    fn request(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        // Anything that would run the program is ignored while it already is
+        let resume = match command {
+            "continue" => Some(Resume::Continue),
+            "next" => Some(Resume::Next),
+            "stepIn" => Some(Resume::StepIn),
+            "stepOut" => Some(Resume::StepOut),
+            "pause" => Some(Resume::Pause),
+            _ => None,
+        };
+        if let Some(resume) = resume {
+            let _ = self.get_system()?;
+            if !self.running {
+                self.resume = Some(resume);
+            }
+            return Ok(match resume {
+                Resume::Continue => json!({ "allThreadsContinued": true }),
+                _ => json!({}),
+            })
+        }
+
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsSetVariable": true,
+                "supportsDisassembleRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => {
+                self.launch(args)?;
+                self.start_if_ready();
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.start_if_ready();
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            // No data breakpoints, or exceptions to choose from
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "Chip8" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => {
+                let mut scopes = vec![
+                    json!({ "name": "Registers", "variablesReference": REGISTERS_REF,
+                            "presentationHint": "registers", "expensive": false }),
+                    json!({ "name": "Stack", "variablesReference": STACK_REF,
+                            "expensive": false }),
+                ];
+                if self.info.is_some() {
+                    scopes.push(json!({ "name": "Labels", "variablesReference": LABELS_REF,
+                                        "expensive": false }));
+                }
+                Ok(json!({ "scopes": scopes }))
+            }
+            "variables" => self.variables(args),
+            "setVariable" => {
+                let c8 = self.get_system()?;
+                if args["variablesReference"] != REGISTERS_REF {
+                    return Err("Only registers can be changed".to_string())
+                }
+                let name = args["name"].as_str().unwrap_or("");
+                let reg = Register::from_name(name)
+                    .ok_or_else(|| format!("Unknown register \"{}\"", name))?;
+                c8.write_register(reg, parse_number(args["value"].as_str().unwrap_or(""))?)?;
+                Ok(json!({ "value": format_register(c8, reg) }))
+            }
+            "disassemble" => self.disassemble(args),
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                let result = evaluate(self.get_system()?, expression)?;
+                Ok(json!({ "result": result, "variablesReference": 0 }))
+            }
+            "disconnect" | "terminate" => {
+                self.finished = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request \"{}\"", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let program = args["program"].as_str()
+            .ok_or_else(|| "Launch needs a \"program\" to run".to_string())?;
+        let quirks = match args["quirks"].as_str() {
+            None => Quirks::default(),
+            Some(name) => Quirks::from_name(name).ok_or_else(|| {
+                format!("Unknown quirks preset \"{}\", expected one of: {}",
+                        name, QUIRKS_PRESET_NAMES.join(", "))
+            })?,
+        };
+
+        let is_asm = Path::new(program).extension()
+            .is_some_and(|ext| ASM_EXTENSIONS.iter().any(|e| ext == *e));
+        let rom = if is_asm {
+            let asm = fs::read_to_string(program)
+                .map_err(|e| format!("Couldn't read assembly file \"{}\": {}", program, e))?;
+            let mut warnings = vec![];
+            let (instrs, info) = parse_asm_with_info(&asm, program, &mut warnings)?;
+            self.info = Some(info);
+            instrs_to_rom(&instrs)
+        } else {
+            fs::read(program).map_err(|e| format!("Couldn't read ROM \"{}\": {}", program, e))?
+        };
+
+        let mut c8 = make_system(&rom, quirks);
+        if let Some(seed) = args["seed"].as_u64() {
+            c8.set_seed(seed);
+        }
+        self.c8 = Some(c8);
+        self.program = program.to_string();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.launched = true;
+        Ok(())
+    }
+
+    fn start_if_ready(&mut self) {
+        if self.launched && self.configured && !self.running {
+            self.resume = Some(Resume::Start);
+        }
+    }
+
+    fn get_system(&mut self) -> Result<&mut Chip8System, String> {
+        self.c8.as_mut().ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn is_program(&self, path: &str) -> bool {
+        match (fs::canonicalize(path), fs::canonicalize(&self.program)) {
+            (Ok(path), Ok(program)) => path == program,
+            _ => path == self.program,
+        }
+    }
+
+    // Each request replaces all the breakpoints for that file
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let lines: Vec<u64> = match args["breakpoints"].as_array() {
+            Some(breakpoints) => breakpoints.iter()
+                .filter_map(|bp| bp["line"].as_u64()).collect(),
+            // Older clients only send lines
+            None => args["lines"].as_array().map_or(vec![], |lines| {
+                lines.iter().filter_map(|l| l.as_u64()).collect()
+            }),
+        };
+
+        let mut addrs = vec![];
+        let mut breakpoints = vec![];
+        for line in lines {
+            let found = match self.info {
+                Some(ref info) if self.is_program(path) => info.addr_for_line(line as usize),
+                _ => None,
+            };
+            breakpoints.push(match found {
+                None => json!({ "verified": false, "line": line,
+                                "message": "No instructions at or after this line" }),
+                Some((addr, line)) => {
+                    addrs.push(addr);
+                    json!({ "verified": true, "line": line,
+                            "instructionReference": format_addr(addr) })
+                }
+            });
+        }
+
+        self.source_breakpoints = addrs;
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let mut addrs = vec![];
+        let mut breakpoints = vec![];
+        for bp in args["breakpoints"].as_array().unwrap_or(&vec![]) {
+            let reference = bp["instructionReference"].as_str().unwrap_or("");
+            let offset = bp["offset"].as_i64().unwrap_or(0);
+            let addr = parse_number(reference).ok()
+                .map(|addr| i64::from(addr) + offset)
+                .filter(|addr| (*addr >= 0) && (*addr <= 0xFFFF));
+            breakpoints.push(match addr {
+                None => json!({ "verified": false,
+                                "message": format!("Invalid address \"{}\"", reference) }),
+                Some(addr) => {
+                    addrs.push(addr as u16);
+                    json!({ "verified": true, "instructionReference": format_addr(addr as u16) })
+                }
+            });
+        }
+
+        self.instruction_breakpoints = addrs;
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+        for addr in self.source_breakpoints.iter().chain(self.instruction_breakpoints.iter()) {
+            let _ = self.debugger.add_breakpoint(*addr);
+        }
+    }
+
+    fn source(&self) -> Value {
+        let name = Path::new(&self.program).file_name()
+            .map_or(self.program.clone(), |name| name.to_string_lossy().into_owned());
+        json!({ "name": name, "path": self.program })
+    }
+
+    // The current instruction, then each call site back to the first
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let c8 = self.get_system()?;
+        let mut addrs = vec![c8.read_register(Register::PC)];
+        addrs.extend(c8.get_stack().iter().rev().map(|addr| addr.wrapping_sub(2)));
+
+        let frames: Vec<Value> = addrs.iter().enumerate().map(|(idx, addr)| {
+            let name = match self.info.as_ref().and_then(|info| info.label_for_addr(*addr)) {
+                Some((label, start)) if start == *addr => label.to_string(),
+                Some((label, start)) => format!("{}+0x{:x}", label, addr - start),
+                None => format_addr(*addr),
+            };
+            let mut frame = json!({
+                "id": idx,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format_addr(*addr),
+            });
+            if let Some(line) = self.info.as_ref().and_then(|info| info.line_for_addr(*addr)) {
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+                frame["source"] = self.source();
+            }
+            frame
+        }).collect();
+
+        Ok(json!({ "stackFrames": frames, "totalFrames": addrs.len() }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_u64().unwrap_or(0);
+        let labels: Vec<(String, u16)> = self.info.as_ref().map_or(vec![], |info| {
+            info.labels.iter().map(|(name, addr)| (name.clone(), *addr)).collect()
+        });
+        let c8 = self.get_system()?;
+
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => ALL_REGISTERS.iter().map(|reg| {
+                let mut variable = json!({
+                    "name": reg.name(),
+                    "value": format_register(c8, *reg),
+                    "variablesReference": 0,
+                });
+                if reg.size() == 2 {
+                    variable["memoryReference"] = json!(format_addr(c8.read_register(*reg)));
+                }
+                variable
+            }).collect(),
+            // Oldest first, like the SP counts them
+            STACK_REF => c8.get_stack().iter().enumerate().map(|(idx, addr)| json!({
+                "name": format!("{}", idx),
+                "value": format_addr(*addr),
+                "variablesReference": 0,
+                "memoryReference": format_addr(*addr),
+            })).collect(),
+            LABELS_REF => labels.iter().map(|&(ref name, addr)| json!({
+                "name": name,
+                "value": format_addr(addr),
+                "variablesReference": 0,
+                "memoryReference": format_addr(addr),
+            })).collect(),
+            _ => return Err(format!("Unknown variables reference {}", reference)),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // Instructions are assumed to be 2 bytes when counting backwards
+    fn disassemble(&mut self, args: &Value) -> Result<Value, String> {
+        let reference = parse_number(args["memoryReference"].as_str().unwrap_or(""))?;
+        let start = i64::from(reference) + args["offset"].as_i64().unwrap_or(0) +
+                    (args["instructionOffset"].as_i64().unwrap_or(0) * 2);
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        let info = self.info.take();
+        let source = self.source();
+        let c8 = self.get_system()?;
+
+        let mut instructions = vec![];
+        let mut addr = start;
+        for _ in 0..count {
+            if !(0..=0xFFFF).contains(&addr) {
+                let address = if addr < 0 {
+                    format!("-0x{:04x}", -addr)
+                } else {
+                    format!("0x{:04x}", addr)
+                };
+                instructions.push(json!({
+                    "address": address,
+                    "instruction": "",
+                    "presentationHint": "invalid",
+                }));
+                addr += 2;
+                continue
+            }
+
+            let (size, bytes, text) = match c8.decode_at(addr as u16) {
+                Err(_) => {
+                    let bytes: Vec<String> = c8.read_memory(addr as u16, 2).iter()
+                        .map(|b| format!("{:02X}", b)).collect();
+                    (2, bytes.join(""), "<unknown>".to_string())
+                }
+                Ok(instr) => {
+                    let bytes = if instr.size() == 4 {
+                        format!("{:04X}{:04X}", instr.opcode, instr.operand)
+                    } else {
+                        format!("{:04X}", instr.opcode)
+                    };
+                    (instr.size(), bytes, c8.repr_instr(instr))
+                }
+            };
+
+            let mut instruction = json!({
+                "address": format_addr(addr as u16),
+                "instructionBytes": bytes,
+                "instruction": text,
+            });
+            if let Some(ref info) = info {
+                if let Some(line) = info.line_for_addr(addr as u16) {
+                    instruction["line"] = json!(line);
+                    instruction["location"] = source.clone();
+                }
+                if let Some((label, start)) = info.label_for_addr(addr as u16) {
+                    if start == (addr as u16) {
+                        instruction["symbol"] = json!(label);
+                    }
+                }
+            }
+            instructions.push(instruction);
+            addr += i64::from(size);
+        }
+
+        self.info = info;
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn resume(&mut self, resume: Resume, connection: &mut dyn Connection) {
+        let mut resume = resume;
+        loop {
+            let mut queued: Vec<Value> = vec![];
+            let (stop, reason) = {
+                let debugger = &self.debugger;
+                let c8 = match self.c8.as_mut() {
+                    None => return,
+                    Some(c8) => c8,
+                };
+                // Continuing can be interrupted by anything, since it can pick up
+                // where it left off. The others only stop for a pause.
+                let mut interrupted = || {
+                    match connection.try_receive() {
+                        None => false,
+                        Some(message) => {
+                            let stops = (resume == Resume::Continue) ||
+                                        (resume == Resume::Start) ||
+                                        (message["command"] == "pause") ||
+                                        (message["command"] == "disconnect") ||
+                                        (message["command"] == "terminate");
+                            queued.push(message);
+                            stops
+                        }
+                    }
+                };
+
+                self.running = true;
+                match resume {
+                    Resume::Start if self.stop_on_entry => (DebugStop::Done, "entry"),
+                    Resume::Start | Resume::Continue => (debugger.cont(c8, &mut interrupted), ""),
+                    Resume::Next => (debugger.next(c8, &mut interrupted), "step"),
+                    Resume::StepIn => (debugger.step(c8), "step"),
+                    Resume::StepOut => (debugger.finish(c8, &mut interrupted), "step"),
+                    Resume::Pause => (DebugStop::Interrupted, "pause"),
+                }
+            };
+
+            // Answer whatever came in while running
+            let mut paused = false;
+            for message in queued {
+                paused |= message["command"] == "pause";
+                self.handle_message(&message, connection);
+            }
+            self.running = false;
+
+            if self.finished {
+                return
+            }
+            if (stop == DebugStop::Interrupted) && !paused && (resume != Resume::Pause) {
+                resume = Resume::Continue;
+                continue
+            }
+            self.report_stop(stop, reason, connection);
+            return
+        }
+    }
+
+    fn report_stop(&mut self, stop: DebugStop, reason: &str, connection: &mut dyn Connection) {
+        let (reason, description) = match stop {
+            DebugStop::Exited => {
+                self.send_event(connection, "exited", json!({ "exitCode": 0 }));
+                self.send_event(connection, "terminated", json!({}));
+                return
+            }
+            DebugStop::Done => (reason, None),
+            DebugStop::Breakpoint => ("breakpoint", None),
+            DebugStop::Watchpoint(_) => ("data breakpoint", None),
+            DebugStop::Interrupted => ("pause", None),
+            DebugStop::WaitKey => {
+                self.send_event(connection, "output", json!({
+                    "category": "console",
+                    "output": "Waiting for a key, hold one down with \"press <key>\" \
+                               then continue.\n",
+                }));
+                ("pause", Some(stop.describe()))
+            }
+            DebugStop::SelfJump => ("pause", Some(stop.describe())),
+            DebugStop::Error(e) => ("exception", Some(format!("{}", e))),
+        };
+
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description.clone());
+            body["text"] = json!(description);
+        }
+        self.send_event(connection, "stopped", body);
+    }
+
+    fn send_event(&mut self, connection: &mut dyn Connection, event: &str, body: Value) {
+        self.send(connection, json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, connection: &mut dyn Connection, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        connection.send(message);
+    }
+}
+
+// What the debug console understands
+fn evaluate(c8: &mut Chip8System, expression: &str) -> Result<String, String> {
+    let words: Vec<&str> = expression.split_whitespace().collect();
+    match words.as_slice() {
+        [name] if Register::from_name(name).is_some() => {
+            Ok(format_register(c8, Register::from_name(name).unwrap()))
+        }
+        ["press", key] | ["release", key] => {
+            match u8::from_str_radix(key, 16) {
+                Ok(k) if k < 16 => {
+                    c8.keys[k as usize] = words[0] == "press";
+                    Ok(format!("Key {:X} {}", k,
+                               if words[0] == "press" { "held" } else { "released" }))
+                }
+                _ => Err(format!("Invalid key \"{}\", expected 0-F", key)),
+            }
+        }
+        ["screen"] => Ok(c8.screen_to_str()),
+        _ => Err("Expected a register name, \"press <key>\", \
+                  \"release <key>\" or \"screen\"".to_string()),
+    }
+}
+
+fn format_addr(addr: u16) -> String {
+    format!("0x{:04x}", addr)
+}
+
+fn format_register(c8: &Chip8System, reg: Register) -> String {
+    match reg.size() {
+        2 => format_addr(c8.read_register(reg)),
+        _ => format!("0x{:02x}", c8.read_register(reg)),
+    }
+}
+
+// Decimal or "0x" prefixed hex
+fn parse_number(arg: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = arg.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        arg.parse::<u16>()
+    };
+    parsed.map_err(|e| format!("Invalid number \"{}\": {}", arg, e))
+}
+
+// Messages are JSON with a "Content-Length" header, over stdin and stdout
+struct StdioConnection {
+    receiver: mpsc::Receiver<Value>,
+}
+
+impl StdioConnection {
+    fn new() -> StdioConnection {
+        // Read on another thread so that a running program can be interrupted
+        let (sender, receiver) = mpsc::channel();
+        let _ = thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut input = stdin.lock();
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break
+                }
+            }
+        });
+        StdioConnection { receiver }
+    }
+}
+
+impl Connection for StdioConnection {
+    fn receive(&mut self) -> Option<Value> {
+        self.receiver.recv().ok()
+    }
+
+    fn try_receive(&mut self) -> Option<Value> {
+        self.receiver.try_recv().ok()
+    }
+
+    fn send(&mut self, message: Value) {
+        let stdout = io::stdout();
+        let mut output = stdout.lock();
+        let _ = output.write_all(format_message(&message).as_bytes());
+        let _ = output.flush();
+    }
+}
+
+pub fn format_message(message: &Value) -> String {
+    let content = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+}
+
+// Returns None at the end of the input
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None)
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Message has no Content-Length")
+    })?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Serve an editor over stdin and stdout until it disconnects
+pub fn run_dap_server() {
+    DapServer::new().serve(&mut StdioConnection::new());
+}
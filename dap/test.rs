@@ -0,0 +1,257 @@
+#[cfg(test)]
+mod test {
+    use dap::*;
+    use dap::serde_json::{Value, json};
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    // Requests are answered in order, anything in while_running
+    // arrives while the program runs
+    #[derive(Default)]
+    struct TestConnection {
+        requests: VecDeque<Value>,
+        while_running: VecDeque<Value>,
+        sent: Vec<Value>,
+    }
+
+    impl Connection for TestConnection {
+        fn receive(&mut self) -> Option<Value> {
+            self.requests.pop_front()
+        }
+
+        fn try_receive(&mut self) -> Option<Value> {
+            self.while_running.pop_front()
+        }
+
+        fn send(&mut self, message: Value) {
+            self.sent.push(message);
+        }
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    // Returns the body of the response
+    fn send(server: &mut DapServer, connection: &mut TestConnection,
+            command: &str, arguments: Value) -> Value {
+        connection.sent.clear();
+        server.handle_message(&request(command, arguments), connection);
+        let response = connection.sent.iter()
+            .find(|m| (m["type"] == "response") && (m["command"] == command))
+            .unwrap().clone();
+        assert_eq!(json!(true), response["success"], "{}", response);
+        response["body"].clone()
+    }
+
+    fn events(connection: &TestConnection) -> Vec<Value> {
+        connection.sent.iter().filter(|m| m["type"] == "event")
+            .map(|m| json!({ "event": m["event"], "body": m["body"] })).collect()
+    }
+
+    fn stopped(reason: &str) -> Value {
+        json!({ "event": "stopped",
+                "body": { "reason": reason, "threadId": 1, "allThreadsStopped": true } })
+    }
+
+    fn write_temp_file(name: &str, contents: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("rchip8_dap_{}", name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn launch(server: &mut DapServer, connection: &mut TestConnection, program: &str,
+              stop_on_entry: bool) {
+        let _ = send(server, connection, "initialize", json!({ "adapterID": "rchip8" }));
+        let _ = send(server, connection, "launch",
+                     json!({ "program": program, "stopOnEntry": stop_on_entry }));
+        assert_eq!(vec![json!({ "event": "initialized", "body": {} })], events(connection));
+    }
+
+    #[test]
+    fn assembly_source_breakpoints() {
+        let program = write_temp_file("source.s", b"\
+start:
+    LD V0, 1
+    CALL sub
+
+    LD V1, 2
+    EXIT
+sub:
+    ADD V0, 1
+    RET
+");
+        let mut server = DapServer::new();
+        let mut connection = TestConnection::default();
+        launch(&mut server, &mut connection, &program, false);
+
+        // The blank line moves to the next instruction
+        let body = send(&mut server, &mut connection, "setBreakpoints", json!({
+            "source": { "path": program },
+            "breakpoints": [{ "line": 4 }, { "line": 8 }, { "line": 20 }],
+        }));
+        let lines: Vec<Value> = body["breakpoints"].as_array().unwrap().iter()
+            .map(|bp| json!([bp["verified"], bp["line"]])).collect();
+        assert_eq!(vec![json!([true, 5]), json!([true, 8]), json!([false, 20])], lines);
+
+        let _ = send(&mut server, &mut connection, "configurationDone", json!({}));
+        assert_eq!(vec![stopped("breakpoint")], events(&connection));
+
+        let body = send(&mut server, &mut connection, "stackTrace", json!({ "threadId": 1 }));
+        let frames: Vec<Value> = body["stackFrames"].as_array().unwrap().iter()
+            .map(|f| json!([f["name"], f["line"], f["instructionPointerReference"]])).collect();
+        assert_eq!(vec![json!(["sub", 8, "0x0208"]), json!(["start+0x2", 3, "0x0202"])],
+                   frames);
+        assert_eq!(json!(program), body["stackFrames"][0]["source"]["path"]);
+
+        let body = send(&mut server, &mut connection, "scopes", json!({ "frameId": 0 }));
+        assert_eq!(3, body["scopes"].as_array().unwrap().len());
+        let body = send(&mut server, &mut connection, "variables",
+                        json!({ "variablesReference": 1 }));
+        assert_eq!(json!({ "name": "V0", "value": "0x01", "variablesReference": 0 }),
+                   body["variables"][0]);
+        assert_eq!(json!("0x0208"), body["variables"][17]["value"]);
+        let body = send(&mut server, &mut connection, "variables",
+                        json!({ "variablesReference": 2 }));
+        assert_eq!(json!("0x0204"), body["variables"][0]["value"]);
+        let body = send(&mut server, &mut connection, "variables",
+                        json!({ "variablesReference": 3 }));
+        assert_eq!(json!(["start", "sub"]), json!([body["variables"][0]["name"],
+                                                    body["variables"][1]["name"]]));
+
+        let body = send(&mut server, &mut connection, "setVariable",
+                        json!({ "variablesReference": 1, "name": "V0", "value": "0x10" }));
+        assert_eq!(json!({ "value": "0x10" }), body);
+
+        let _ = send(&mut server, &mut connection, "stepOut", json!({ "threadId": 1 }));
+        assert_eq!(vec![stopped("step")], events(&connection));
+        let body = send(&mut server, &mut connection, "evaluate", json!({ "expression": "v0" }));
+        assert_eq!(json!("0x11"), body["result"]);
+
+        let _ = send(&mut server, &mut connection, "next", json!({ "threadId": 1 }));
+        let body = send(&mut server, &mut connection, "evaluate", json!({ "expression": "PC" }));
+        assert_eq!(json!("0x0206"), body["result"]);
+
+        let _ = send(&mut server, &mut connection, "continue", json!({ "threadId": 1 }));
+        assert_eq!(vec![json!({ "event": "exited", "body": { "exitCode": 0 } }),
+                        json!({ "event": "terminated", "body": {} })], events(&connection));
+
+        let _ = send(&mut server, &mut connection, "disconnect", json!({}));
+        assert!(server.has_finished());
+    }
+
+    #[test]
+    fn rom_disassembly() {
+        // CLS, LD V0, 0x12, LD V0, K
+        let program = write_temp_file("disassembly.ch8", &[0x00, 0xE0, 0x60, 0x12, 0xF0, 0x0A]);
+        let mut server = DapServer::new();
+        let mut connection = TestConnection::default();
+        launch(&mut server, &mut connection, &program, true);
+
+        let _ = send(&mut server, &mut connection, "configurationDone", json!({}));
+        assert_eq!(vec![stopped("entry")], events(&connection));
+
+        let body = send(&mut server, &mut connection, "stepIn", json!({ "threadId": 1 }));
+        assert_eq!(json!({}), body);
+        let body = send(&mut server, &mut connection, "disassemble", json!({
+            "memoryReference": "0x0202", "instructionOffset": -2, "instructionCount": 4,
+        }));
+        assert_eq!(json!([
+            { "address": "0x01fe", "instructionBytes": "0000", "instruction": "SYS 0x000" },
+            { "address": "0x0200", "instructionBytes": "00E0", "instruction": "CLS" },
+            { "address": "0x0202", "instructionBytes": "6012", "instruction": "LD V0, 0x12" },
+            { "address": "0x0204", "instructionBytes": "F00A", "instruction": "LD V0, K" },
+        ]), body["instructions"]);
+        let body = send(&mut server, &mut connection, "disassemble", json!({
+            "memoryReference": "0xfffe", "instructionOffset": -1, "instructionCount": 4,
+            "offset": -0xfffe,
+        }));
+        let addresses: Vec<Value> = body["instructions"].as_array().unwrap().iter()
+            .map(|i| json!([i["address"], i["presentationHint"]])).collect();
+        assert_eq!(vec![json!(["-0x0002", "invalid"]), json!(["0x0000", null]),
+                        json!(["0x0002", null]), json!(["0x0004", null])], addresses);
+
+        // No source to set breakpoints in
+        let body = send(&mut server, &mut connection, "setBreakpoints", json!({
+            "source": { "path": program }, "breakpoints": [{ "line": 1 }],
+        }));
+        assert_eq!(json!(false), body["breakpoints"][0]["verified"]);
+
+        let body = send(&mut server, &mut connection, "setInstructionBreakpoints", json!({
+            "breakpoints": [{ "instructionReference": "0x0202", "offset": 2 }],
+        }));
+        assert_eq!(json!([{ "verified": true, "instructionReference": "0x0204" }]),
+                   body["breakpoints"]);
+        let _ = send(&mut server, &mut connection, "continue", json!({ "threadId": 1 }));
+        assert_eq!(vec![stopped("breakpoint")], events(&connection));
+
+        // Key waits stop until a key is held
+        let _ = send(&mut server, &mut connection, "continue", json!({ "threadId": 1 }));
+        let sent = events(&connection);
+        assert_eq!(json!("output"), sent[0]["event"]);
+        assert_eq!(json!("waiting for a key press"), sent[1]["body"]["description"]);
+
+        let body = send(&mut server, &mut connection, "evaluate",
+                        json!({ "expression": "press a", "context": "repl" }));
+        assert_eq!(json!("Key A held"), body["result"]);
+        let _ = send(&mut server, &mut connection, "stepIn", json!({ "threadId": 1 }));
+        let body = send(&mut server, &mut connection, "evaluate", json!({ "expression": "V0" }));
+        assert_eq!(json!("0x0a"), body["result"]);
+    }
+
+    #[test]
+    fn pause_and_errors() {
+        // ADD V0, 1 then jump back to it
+        let program = write_temp_file("pause.ch8", &[0x70, 0x01, 0x12, 0x00]);
+        let mut server = DapServer::new();
+        let mut connection = TestConnection::default();
+
+        connection.sent.clear();
+        server.handle_message(&request("stackTrace", json!({})), &mut connection);
+        assert_eq!(json!(false), connection.sent[0]["success"]);
+        assert_eq!(json!("No program has been launched"), connection.sent[0]["message"]);
+
+        launch(&mut server, &mut connection, &program, false);
+        // Breakpoints can be changed while running, then pause stops it
+        connection.while_running.push_back(request("setInstructionBreakpoints",
+                                                   json!({ "breakpoints": [] })));
+        connection.while_running.push_back(request("pause", json!({ "threadId": 1 })));
+        let _ = send(&mut server, &mut connection, "configurationDone", json!({}));
+        let commands: Vec<Value> = connection.sent.iter()
+            .map(|m| json!([m["type"], m["command"], m["event"]])).collect();
+        assert_eq!(vec![json!(["response", "configurationDone", null]),
+                        json!(["response", "setInstructionBreakpoints", null]),
+                        json!(["response", "pause", null]),
+                        json!(["event", null, "stopped"])], commands);
+        assert_eq!(json!("pause"), connection.sent[3]["body"]["reason"]);
+
+        // Every message has its own sequence number
+        let seqs: Vec<Value> = connection.sent.iter().map(|m| m["seq"].clone()).collect();
+        assert_eq!(vec![json!(5), json!(6), json!(7), json!(8)], seqs);
+
+        connection.sent.clear();
+        server.handle_message(&request("launch", json!({ "program": program, "quirks": "bad" })),
+                              &mut connection);
+        assert_eq!(json!("Unknown quirks preset \"bad\", expected one of: \
+                          default, vip, chip48, schip"),
+                   connection.sent[0]["message"]);
+    }
+
+    #[test]
+    fn messages() {
+        let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+        let formatted = format_message(&message);
+        assert_eq!(format!("Content-Length: {}\r\n\r\n{}", message.to_string().len(), message),
+                   formatted);
+
+        let mut input = Cursor::new(format!("{}{}", formatted, formatted).into_bytes());
+        assert_eq!(Some(message.clone()), read_message(&mut input).unwrap());
+        assert_eq!(Some(message), read_message(&mut input).unwrap());
+        assert_eq!(None, read_message(&mut input).unwrap());
+
+        let mut input = Cursor::new(b"Content-Type: json\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut input).is_err());
+    }
+}
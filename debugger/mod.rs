@@ -0,0 +1,382 @@
+use system::Chip8System;
+use system::debug::{Debugger, DebugStop, Register, Watchpoint, WatchKind};
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+
+mod test;
+
+const HELP: &str = "\
+step, s [n]          : run n instructions (default 1), or to a breakpoint
+next, n              : step, running over subroutine calls
+continue, c          : run until a breakpoint, press enter to interrupt
+break, b [addr]      : add a breakpoint, or list them
+watch <addr> [len]   : stop after memory is written (also rwatch for reads,
+                       awatch for either), or list watchpoints
+delete, d [addr]     : remove breakpoints and watchpoints at addr, or all of them
+regs, r              : show registers and the stack
+set <reg> <value>    : set V0-VF, I, PC, SP, DT or ST
+set <addr> <byte>... : write bytes to memory
+x <addr> [len]       : show len bytes of memory (default 16)
+dis [addr] [n]       : disassemble n instructions (default around PC)
+bt                   : show the call stack
+press/release <key>  : hold or let go of key 0-F
+keys                 : show which keys are held
+screen               : show the screen
+quit, q              : exit
+An empty line repeats the last command.";
+
+// The number of instructions shown either side of the PC by "dis"
+const DIS_CONTEXT: u16 = 4;
+// The number of bytes "x" shows per line
+const BYTES_PER_LINE: usize = 8;
+
+// A gdb style command line on top of the shared debugger
+#[derive(Default)]
+pub struct CommandLine {
+    debugger: Debugger,
+    last_command: String,
+    quit: bool,
+}
+
+impl CommandLine {
+    pub fn new() -> CommandLine {
+        CommandLine { debugger: Debugger::new(), last_command: String::new(), quit: false }
+    }
+
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+
+    // Returns what to show the user.
+    // interrupted is polled while the program runs.
+    pub fn execute(&mut self, c8: &mut Chip8System, line: &str,
+                   interrupted: &mut dyn FnMut() -> bool) -> String {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            None => return String::new(),
+            Some((command, args)) => (*command, args),
+        };
+
+        match self.run_command(c8, command, args, interrupted) {
+            Err(msg) => msg,
+            Ok(output) => output,
+        }
+    }
+
+    fn run_command(&mut self, c8: &mut Chip8System, command: &str, args: &[&str],
+                   interrupted: &mut dyn FnMut() -> bool) -> Result<String, String> {
+        match command {
+            "help" | "h" => Ok(HELP.to_string()),
+            "step" | "s" => {
+                check_num_args(command, args, 0, 1)?;
+                let count = match args.first() {
+                    None => 1,
+                    Some(arg) => parse_number(arg)?,
+                };
+                let stop = self.debugger.step_n(c8, count);
+                Ok(describe_stop(c8, stop))
+            }
+            "next" | "n" => {
+                check_num_args(command, args, 0, 0)?;
+                let stop = self.debugger.next(c8, interrupted);
+                Ok(describe_stop(c8, stop))
+            }
+            "continue" | "c" => {
+                check_num_args(command, args, 0, 0)?;
+                let stop = self.debugger.cont(c8, interrupted);
+                Ok(describe_stop(c8, stop))
+            }
+            "break" | "b" => {
+                check_num_args(command, args, 0, 1)?;
+                match args.first() {
+                    None => {
+                        let breakpoints = self.debugger.get_breakpoints();
+                        if breakpoints.is_empty() {
+                            return Ok("No breakpoints".to_string())
+                        }
+                        let addrs: Vec<String> = breakpoints.iter()
+                            .map(|addr| format!("0x{:04x}", addr)).collect();
+                        Ok(format!("Breakpoints: {}", addrs.join(", ")))
+                    }
+                    Some(arg) => {
+                        let addr = parse_number(arg)?;
+                        if self.debugger.add_breakpoint(addr) {
+                            Ok(format!("Breakpoint set at 0x{:04x}", addr))
+                        } else {
+                            Err(format!("There is already a breakpoint at 0x{:04x}", addr))
+                        }
+                    }
+                }
+            }
+            "delete" | "d" => {
+                check_num_args(command, args, 0, 1)?;
+                match args.first() {
+                    None => {
+                        self.debugger.clear_breakpoints();
+                        self.debugger.clear_watchpoints();
+                        Ok("Deleted all breakpoints and watchpoints".to_string())
+                    }
+                    Some(arg) => {
+                        let addr = parse_number(arg)?;
+                        let mut deleted = vec![];
+                        if self.debugger.remove_breakpoint(addr) {
+                            deleted.push(format!("Deleted breakpoint at 0x{:04x}", addr));
+                        }
+                        let watchpoints: Vec<Watchpoint> = self.debugger.get_watchpoints().iter()
+                            .filter(|wp| wp.addr == addr).cloned().collect();
+                        for wp in watchpoints {
+                            let _ = self.debugger.remove_watchpoint(wp);
+                            deleted.push(format!("Deleted {}", wp.describe()));
+                        }
+
+                        if deleted.is_empty() {
+                            Err(format!("No breakpoint or watchpoint at 0x{:04x}", addr))
+                        } else {
+                            Ok(deleted.join("\n"))
+                        }
+                    }
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                check_num_args(command, args, 0, 2)?;
+                match args.first() {
+                    None => {
+                        let watchpoints = self.debugger.get_watchpoints();
+                        if watchpoints.is_empty() {
+                            return Ok("No watchpoints".to_string())
+                        }
+                        let lines: Vec<String> = watchpoints.iter()
+                            .map(|wp| wp.describe()).collect();
+                        Ok(lines.join("\n"))
+                    }
+                    Some(arg) => {
+                        let len = match args.get(1) {
+                            None => 1,
+                            Some(arg) => parse_number(arg)?,
+                        };
+                        if len == 0 {
+                            return Err("Watchpoint length must be at least 1".to_string())
+                        }
+                        let kind = match command {
+                            "rwatch" => WatchKind::Read,
+                            "awatch" => WatchKind::Access,
+                            _ => WatchKind::Write,
+                        };
+                        let wp = Watchpoint { addr: parse_number(arg)?, len, kind };
+                        if self.debugger.add_watchpoint(wp) {
+                            Ok(format!("Set {}", wp.describe()))
+                        } else {
+                            Err(format!("There is already a {}", wp.describe()))
+                        }
+                    }
+                }
+            }
+            "regs" | "r" => {
+                check_num_args(command, args, 0, 0)?;
+                Ok(c8.dump_to_str().trim_end().to_string())
+            }
+            "set" => {
+                if args.len() < 2 {
+                    return Err("Usage: set <reg> <value> or set <addr> <byte>...".to_string())
+                }
+                match Register::from_name(args[0]) {
+                    Some(reg) => {
+                        check_num_args(command, args, 2, 2)?;
+                        c8.write_register(reg, parse_number(args[1])?)?;
+                        Ok(format!("{} = 0x{:x}", reg.name(), c8.read_register(reg)))
+                    }
+                    None => {
+                        let addr = parse_number(args[0])?;
+                        let mut data = vec![];
+                        for arg in &args[1..] {
+                            let byte = parse_number(arg)?;
+                            if byte > 0xFF {
+                                return Err(format!("Value {} does not fit in a byte", arg))
+                            }
+                            data.push(byte as u8);
+                        }
+                        c8.write_memory(addr, &data)?;
+                        Ok(format!("Wrote {} bytes at 0x{:04x}", data.len(), addr))
+                    }
+                }
+            }
+            "x" => {
+                check_num_args(command, args, 1, 2)?;
+                let addr = parse_number(args[0])?;
+                let len = match args.get(1) {
+                    None => 16,
+                    Some(arg) => parse_number(arg)? as usize,
+                };
+                Ok(format_memory(c8, addr, len))
+            }
+            "dis" => {
+                check_num_args(command, args, 0, 2)?;
+                let (start, count) = match args.first() {
+                    None => (c8.read_register(Register::PC).saturating_sub(DIS_CONTEXT*2), (DIS_CONTEXT*2)+1),
+                    Some(arg) => {
+                        let count = match args.get(1) {
+                            None => 10,
+                            Some(arg) => parse_number(arg)?,
+                        };
+                        (parse_number(arg)?, count)
+                    }
+                };
+                Ok(disassemble(c8, start, count))
+            }
+            "bt" => {
+                check_num_args(command, args, 0, 0)?;
+                let mut lines = vec![format!("#0  0x{:04x}", c8.read_register(Register::PC))];
+                for (idx, addr) in c8.get_stack().iter().rev().enumerate() {
+                    lines.push(format!("#{:<2} 0x{:04x}", idx+1, addr));
+                }
+                Ok(lines.join("\n"))
+            }
+            "press" | "release" => {
+                check_num_args(command, args, 1, 1)?;
+                let key = match u8::from_str_radix(args[0], 16) {
+                    Ok(key) if key < 16 => key as usize,
+                    _ => return Err(format!("Invalid key \"{}\", expected 0-F", args[0])),
+                };
+                c8.keys[key] = command == "press";
+                Ok(format_keys(c8))
+            }
+            "keys" => {
+                check_num_args(command, args, 0, 0)?;
+                Ok(format_keys(c8))
+            }
+            "screen" => {
+                check_num_args(command, args, 0, 0)?;
+                Ok(c8.screen_to_str())
+            }
+            "quit" | "q" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!("Unknown command \"{}\", try \"help\"", command)),
+        }
+    }
+}
+
+fn check_num_args(command: &str, args: &[&str], min: usize, max: usize) -> Result<(), String> {
+    if (args.len() < min) || (args.len() > max) {
+        let expected = if min == max {
+            format!("{}", min)
+        } else {
+            format!("{} to {}", min, max)
+        };
+        return Err(format!("\"{}\" takes {} arguments, got {}", command, expected, args.len()))
+    }
+    Ok(())
+}
+
+// Decimal or "0x" prefixed hex
+fn parse_number(arg: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = arg.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        arg.parse::<u16>()
+    };
+    parsed.map_err(|e| format!("Invalid number \"{}\": {}", arg, e))
+}
+
+fn describe_stop(c8: &Chip8System, stop: DebugStop) -> String {
+    let location = disassemble(c8, c8.read_register(Register::PC), 1);
+    match stop {
+        DebugStop::Done => location,
+        _ => format!("Stopped: {}\n{}", stop.describe(), location),
+    }
+}
+
+// "=>" marks the PC
+fn disassemble(c8: &Chip8System, start: u16, count: u16) -> String {
+    let mut lines = vec![];
+    let mut addr = start;
+    for _ in 0..count {
+        let marker = if addr == c8.read_register(Register::PC) { "=>" } else { "  " };
+        let (size, text) = match c8.decode_at(addr) {
+            Err(_) => {
+                let bytes: Vec<String> = c8.read_memory(addr, 2).iter()
+                    .map(|b| format!("{:02X}", b)).collect();
+                (2, format!("{:<9}  <unknown>", bytes.join("")))
+            }
+            Ok(instr) => {
+                let words = if instr.size() == 4 {
+                    format!("{:04X} {:04X}", instr.opcode, instr.operand)
+                } else {
+                    format!("{:04X}     ", instr.opcode)
+                };
+                (instr.size(), format!("{}  {}", words, c8.repr_instr(instr)))
+            }
+        };
+        lines.push(format!("{} 0x{:04x}: {}", marker, addr, text));
+
+        addr = match addr.checked_add(size) {
+            None => break,
+            Some(next) => next,
+        };
+    }
+    lines.join("\n")
+}
+
+fn format_memory(c8: &Chip8System, addr: u16, len: usize) -> String {
+    let data = c8.read_memory(addr, len);
+    let lines: Vec<String> = data.chunks(BYTES_PER_LINE).enumerate().map(|(idx, bytes)| {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("0x{:04x}: {}", (addr as usize) + (idx*BYTES_PER_LINE), hex.join(" "))
+    }).collect();
+    lines.join("\n")
+}
+
+fn format_keys(c8: &Chip8System) -> String {
+    let held: Vec<String> = c8.keys.iter().enumerate()
+        .filter(|&(_, pressed)| *pressed)
+        .map(|(idx, _)| format!("{:X}", idx)).collect();
+    if held.is_empty() {
+        "No keys held".to_string()
+    } else {
+        format!("Keys held: {}", held.join(" "))
+    }
+}
+
+// Read commands from stdin until the user quits
+pub fn run_debugger(c8: &mut Chip8System) {
+    let mut command_line = CommandLine::new();
+    println!("Type \"help\" for a list of commands.");
+    println!("{}", disassemble(c8, c8.read_register(Register::PC), 1));
+
+    // Lines are read on another thread so that
+    // a running program can be interrupted with one
+    let (sender, receiver) = mpsc::channel();
+    let _ = thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Err(_) => break,
+                Ok(line) => if sender.send(line).is_err() { break },
+            }
+        }
+    });
+
+    while !command_line.has_quit() {
+        print!("(rchip8) ");
+        let _ = io::stdout().flush();
+        let line = match receiver.recv() {
+            // End of input
+            Err(_) => break,
+            Ok(line) => line,
+        };
+
+        let output = command_line.execute(c8, &line, &mut || receiver.try_recv().is_ok());
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+#[cfg(test)]
+mod test {
+    use debugger::*;
+    use system::{make_system, instrs_to_rom};
+    use system::quirks::Quirks;
+    use asm::parse_asm_str;
+
+    fn make_debug_system(asm: &str) -> Chip8System {
+        let instrs = parse_asm_str(asm).unwrap();
+        make_system(&instrs_to_rom(&instrs), Quirks::default())
+    }
+
+    fn run(command_line: &mut CommandLine, c8: &mut Chip8System, line: &str) -> String {
+        command_line.execute(c8, line, &mut || false)
+    }
+
+    #[test]
+    fn step_next_and_continue() {
+        let mut c8 = make_debug_system("\
+            LD V0, 1
+            CALL sub
+            LD V1, 2
+        end:
+            JP end
+        sub:
+            ADD V0, 1
+            RET");
+        let mut cl = CommandLine::new();
+
+        assert_eq!("=> 0x0202: 2208       CALL 0x208", run(&mut cl, &mut c8, "step"));
+        // Runs the whole subroutine
+        assert_eq!("=> 0x0204: 6102       LD V1, 0x02", run(&mut cl, &mut c8, "next"));
+        assert_eq!(2, c8.read_register(Register::V(0)));
+        assert_eq!("Stopped: jump to self\n=> 0x0206: 1206       JP 0x206",
+                   run(&mut cl, &mut c8, "c"));
+
+        // An empty line repeats the last command
+        let mut c8 = make_debug_system("LD V0, 1\nLD V0, 2\nLD V0, 0x03");
+        assert_eq!("=> 0x0204: 6003       LD V0, 0x03", run(&mut cl, &mut c8, "s 2"));
+        assert_eq!("=> 0x0208: 0000       SYS 0x000", run(&mut cl, &mut c8, ""));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut c8 = make_debug_system("\
+        loop:
+            ADD V0, 1
+            CALL sub
+            JP loop
+        sub:
+            RET");
+        let mut cl = CommandLine::new();
+
+        assert_eq!("No breakpoints", run(&mut cl, &mut c8, "break"));
+        assert_eq!("Breakpoint set at 0x0206", run(&mut cl, &mut c8, "b 0x206"));
+        assert_eq!("Breakpoint set at 0x0202", run(&mut cl, &mut c8, "b 514"));
+        assert_eq!("There is already a breakpoint at 0x0202", run(&mut cl, &mut c8, "b 0x202"));
+        assert_eq!("Breakpoints: 0x0202, 0x0206", run(&mut cl, &mut c8, "b"));
+
+        assert_eq!("Stopped: breakpoint\n=> 0x0202: 2206       CALL 0x206",
+                   run(&mut cl, &mut c8, "continue"));
+        // Next doesn't skip breakpoints inside the call
+        assert_eq!("Stopped: breakpoint\n=> 0x0206: 00EE       RET",
+                   run(&mut cl, &mut c8, "n"));
+        assert_eq!("#0  0x0206\n#1  0x0204", run(&mut cl, &mut c8, "bt"));
+
+        assert_eq!("Deleted breakpoint at 0x0206", run(&mut cl, &mut c8, "d 0x206"));
+        assert_eq!("No breakpoint or watchpoint at 0x0206", run(&mut cl, &mut c8, "d 0x206"));
+        assert_eq!("Stopped: breakpoint\n=> 0x0202: 2206       CALL 0x206",
+                   run(&mut cl, &mut c8, "c"));
+        assert_eq!(2, c8.read_register(Register::V(0)));
+
+        // Interrupting is the only way out now
+        assert_eq!("Deleted all breakpoints and watchpoints", run(&mut cl, &mut c8, "delete"));
+        let mut polls = 0;
+        let out = cl.execute(&mut c8, "c", &mut || { polls += 1; polls == 3 });
+        assert!(out.starts_with("Stopped: interrupted\n"));
+
+        // Stepping several instructions stops at breakpoints on the way
+        let mut c8 = make_debug_system("LD V0, 1\nLD V0, 2\nLD V0, 3\nLD V0, 4");
+        assert_eq!("Breakpoint set at 0x0204", run(&mut cl, &mut c8, "b 0x204"));
+        assert_eq!("Stopped: breakpoint\n=> 0x0204: 6003       LD V0, 0x03",
+                   run(&mut cl, &mut c8, "s 3"));
+        assert_eq!("=> 0x0206: 6004       LD V0, 0x04", run(&mut cl, &mut c8, "s"));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut c8 = make_debug_system("\
+            LD I, 0x300
+            LD V0, [I]
+            LD B, V0
+            LD I, 0x302
+            LD [I], V0
+        end:
+            JP end");
+        let mut cl = CommandLine::new();
+
+        assert_eq!("No watchpoints", run(&mut cl, &mut c8, "watch"));
+        assert_eq!("Set write watchpoint at 0x0302 length 1", run(&mut cl, &mut c8, "watch 0x302"));
+        assert_eq!("Set read watchpoint at 0x0300 length 2", run(&mut cl, &mut c8, "rwatch 0x300 2"));
+        assert_eq!("There is already a read watchpoint at 0x0300 length 2",
+                   run(&mut cl, &mut c8, "rwatch 0x300 2"));
+        assert_eq!("Watchpoint length must be at least 1", run(&mut cl, &mut c8, "awatch 0x300 0"));
+
+        // Stops after the instruction that did the access
+        assert_eq!("Stopped: read watchpoint at 0x0300 length 2\n\
+                    => 0x0204: F033       LD B, V0",
+                   run(&mut cl, &mut c8, "c"));
+        // BCD writes 3 bytes, the last of which is watched
+        assert_eq!("Stopped: write watchpoint at 0x0302 length 1\n\
+                    => 0x0206: A302       LD I, 0x302",
+                   run(&mut cl, &mut c8, "c"));
+
+        assert_eq!("Deleted read watchpoint at 0x0300 length 2", run(&mut cl, &mut c8, "d 0x300"));
+        assert_eq!("write watchpoint at 0x0302 length 1", run(&mut cl, &mut c8, "watch"));
+        assert_eq!("Stopped: write watchpoint at 0x0302 length 1\n\
+                    => 0x020a: 120A       JP 0x20A",
+                   run(&mut cl, &mut c8, "c"));
+    }
+
+    #[test]
+    fn inspect_and_modify() {
+        let mut c8 = make_debug_system("LD V0, K\nLDL I, 0xABCD");
+        let mut cl = CommandLine::new();
+
+        assert_eq!("V10 = 0x12", run(&mut cl, &mut c8, "set va 0x12"));
+        assert_eq!("I = 0x300", run(&mut cl, &mut c8, "set I 768"));
+        assert_eq!("Value 0x100 is too large for DT, maximum is 0xff",
+                   run(&mut cl, &mut c8, "set DT 256"));
+        assert!(run(&mut cl, &mut c8, "regs").contains("V10: 0x12"));
+
+        assert_eq!("Wrote 3 bytes at 0x0300", run(&mut cl, &mut c8, "set 0x300 1 0x2 255"));
+        assert_eq!("0x0300: 01 02 ff", run(&mut cl, &mut c8, "x 0x300 3"));
+        assert_eq!("0x02fe: 00 00 01 02 ff 00 00 00\n0x0306: 00 00",
+                   run(&mut cl, &mut c8, "x 0x2fe 10"));
+        assert_eq!("Value 256 does not fit in a byte", run(&mut cl, &mut c8, "set 0x300 256"));
+
+        assert_eq!("\
+=> 0x0200: F00A       LD V0, K
+   0x0202: F000 ABCD  LDL I, 0xABCD
+   0x0206: 0000       SYS 0x000", run(&mut cl, &mut c8, "dis 0x200 3"));
+
+        // Key waits need a key held
+        assert_eq!("Stopped: waiting for a key press\n=> 0x0200: F00A       LD V0, K",
+                   run(&mut cl, &mut c8, "s"));
+        assert_eq!("Keys held: 5", run(&mut cl, &mut c8, "press 5"));
+        assert_eq!("Keys held: 5 C", run(&mut cl, &mut c8, "press c"));
+        assert_eq!("=> 0x0202: F000 ABCD  LDL I, 0xABCD", run(&mut cl, &mut c8, "s"));
+        assert_eq!(5, c8.read_register(Register::V(0)));
+        assert_eq!("Keys held: C", run(&mut cl, &mut c8, "release 5"));
+        assert_eq!("Invalid key \"G\", expected 0-F", run(&mut cl, &mut c8, "press G"));
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut c8 = make_debug_system("RET");
+        let mut cl = CommandLine::new();
+
+        assert_eq!("Unknown command \"foo\", try \"help\"", run(&mut cl, &mut c8, "foo"));
+        assert_eq!("\"next\" takes 0 arguments, got 1", run(&mut cl, &mut c8, "next 1"));
+        assert_eq!("\"x\" takes 1 to 2 arguments, got 0", run(&mut cl, &mut c8, "x"));
+        assert_eq!("Invalid number \"0xZZ\": invalid digit found in string",
+                   run(&mut cl, &mut c8, "b 0xZZ"));
+        assert_eq!("Stopped: error: Stack is empty!\n=> 0x0200: 00EE       RET",
+                   run(&mut cl, &mut c8, "s"));
+
+        assert!(!cl.has_quit());
+        assert_eq!("", run(&mut cl, &mut c8, "q"));
+        assert!(cl.has_quit());
+    }
+}
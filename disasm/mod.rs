@@ -0,0 +1,159 @@
+use system::instr::{Instruction, InstrKind, decode};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+mod test;
+
+const ROM_START: u16 = 0x200;
+
+// How an address was reached, which decides its label's name
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Target {
+    Jump,
+    Call,
+}
+
+fn is_skip(kind: InstrKind) -> bool {
+    matches!(kind, InstrKind::SkipEqual | InstrKind::SkipNotEqual |
+                   InstrKind::SkipIfRegsEqual | InstrKind::SkipIfRegsNotEqual |
+                   InstrKind::SkipKeyIfPressed | InstrKind::SkipKeyIfNotPressed)
+}
+
+// Turns a ROM back into assembly that parse_asm builds into the same bytes.
+// Control flow is followed from the start of the ROM to find the code,
+// anything it doesn't reach is data.
+pub fn disassemble(rom: &[u8], filename: &str, warnings: &mut Vec<String>) -> String {
+    let end = u32::from(ROM_START) + (rom.len() as u32);
+    let in_rom = |addr: u16, size: u16| {
+        (addr >= ROM_START) && (u32::from(addr) + u32::from(size) <= end)
+    };
+    let read_word = |addr: u16| {
+        let offset = (addr - ROM_START) as usize;
+        (u16::from(rom[offset]) << 8) | u16::from(rom[offset + 1])
+    };
+    let mut warn = |addr: u16, msg: String| {
+        warnings.push(format!("{}: warning: 0x{:04x}: {}", filename, addr, msg));
+    };
+
+    let mut code: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut targets: BTreeMap<u16, Target> = BTreeMap::new();
+    let mut queue: VecDeque<u16> = VecDeque::new();
+    queue.push_back(ROM_START);
+
+    while let Some(addr) = queue.pop_front() {
+        if code.contains_key(&addr) || !in_rom(addr, 2) {
+            continue
+        }
+        let instr = match decode(read_word(addr)) {
+            Ok(instr) if in_rom(addr, instr.size()) => {
+                match instr.kind {
+                    InstrKind::LongLoadI => Instruction { operand: read_word(addr + 2), ..instr },
+                    _ => instr,
+                }
+            }
+            _ => {
+                warn(addr, format!("Reached unknown opcode 0x{:04X}, treating it as data",
+                                   read_word(addr)));
+                continue
+            }
+        };
+        let _ = code.insert(addr, instr);
+
+        let next = addr.wrapping_add(instr.size());
+        let target = instr.opcode & 0xFFF;
+        match instr.kind {
+            InstrKind::Jump => {
+                let _ = targets.entry(target).or_insert(Target::Jump);
+                queue.push_back(target);
+            }
+            InstrKind::Call => {
+                let _ = targets.insert(target, Target::Call);
+                queue.push_back(target);
+                queue.push_back(next);
+            }
+            InstrKind::JumpPlusVZero => {
+                // Usually a table of jumps, so follow those
+                let _ = targets.entry(target).or_insert(Target::Jump);
+                let mut entries = 0;
+                let mut entry = target;
+                while in_rom(entry, 2) && ((read_word(entry) >> 12) == 0x1) {
+                    queue.push_back(entry);
+                    entries += 1;
+                    entry = entry.wrapping_add(2);
+                }
+                if entries == 0 {
+                    queue.push_back(target);
+                }
+                warn(addr, format!("Jump to a computed address, following {} table \
+                                    entries at 0x{:04x}", entries, target));
+            }
+            InstrKind::Ret | InstrKind::Exit => {}
+            kind if is_skip(kind) => {
+                queue.push_back(next);
+                let skipped = if in_rom(next, 2) && (read_word(next) == 0xF000) { 4 } else { 2 };
+                queue.push_back(next.wrapping_add(skipped));
+            }
+            _ => queue.push_back(next),
+        }
+    }
+
+    // Code is only shown where it lines up with the instructions before
+    // it. Anything else is data, which is shown a byte at a time.
+    let mut lines: Vec<(u16, Option<Instruction>)> = vec![];
+    let mut addr = u32::from(ROM_START);
+    while addr < end {
+        match code.get(&(addr as u16)) {
+            Some(instr) => {
+                lines.push((addr as u16, Some(*instr)));
+                addr += u32::from(instr.size());
+            }
+            None => {
+                lines.push((addr as u16, None));
+                addr += 1;
+            }
+        }
+    }
+
+    let line_starts: BTreeSet<u16> = lines.iter().map(|&(addr, _)| addr).collect();
+    for addr in code.keys().filter(|addr| !line_starts.contains(addr)) {
+        warn(*addr, "Code overlaps other instructions, showing it as data".to_string());
+    }
+    let labels: BTreeMap<u16, String> = targets.iter()
+        .filter(|&(addr, _)| line_starts.contains(addr))
+        .map(|(addr, target)| (*addr, match *target {
+            Target::Jump => format!("label_{:03x}", addr),
+            Target::Call => format!("sub_{:03x}", addr),
+        }))
+        .collect();
+
+    let mut output = format!("// Disassembled from {}\n", filename);
+    // Data bytes waiting to be shown, up to 8 to a line
+    let mut data: Vec<String> = vec![];
+    let flush = |output: &mut String, data: &mut Vec<String>| {
+        if !data.is_empty() {
+            *output += &format!("    .byte {}\n", data.join(", "));
+            data.clear();
+        }
+    };
+    for (addr, instr) in lines {
+        if labels.contains_key(&addr) || instr.is_some() || (data.len() == 8) {
+            flush(&mut output, &mut data);
+        }
+        if let Some(label) = labels.get(&addr) {
+            output += &format!("\n{}:\n", label);
+        }
+        match instr {
+            None => data.push(format!("0x{:02X}", rom[(addr - ROM_START) as usize])),
+            Some(instr) => {
+                let label = labels.get(&(instr.opcode & 0xFFF));
+                let text = match (instr.kind, label) {
+                    (InstrKind::Jump, Some(label)) => format!("JP {}", label),
+                    (InstrKind::Call, Some(label)) => format!("CALL {}", label),
+                    (InstrKind::JumpPlusVZero, Some(label)) => format!("JP V0, {}", label),
+                    _ => instr.to_instr().repr(),
+                };
+                output += &format!("    {}\n", text);
+            }
+        }
+    }
+    flush(&mut output, &mut data);
+    output
+}
@@ -0,0 +1,102 @@
+#[cfg(test)]
+mod test {
+    use disasm::*;
+    use asm::parse_asm_str;
+    use system::{instrs_to_rom, read_rom};
+    use std::path::PathBuf;
+
+    fn reassemble(asm: &str) -> Vec<u8> {
+        instrs_to_rom(&parse_asm_str(asm).unwrap())
+    }
+
+    #[test]
+    fn code_and_data() {
+        let rom = reassemble("\
+            CALL draw
+            SE V0, 1
+            LDL I, 0x1234
+            JP V0, table
+        table:
+            JP end
+            JP end
+        draw:
+            LD I, sprite
+            DRW V0, V1, 2
+            RET
+        end:
+            EXIT
+        sprite:
+            .word 0xF090
+            .word 0x5001");
+
+        let mut warnings = vec![];
+        let asm = disassemble(&rom, "test.ch8", &mut warnings);
+        assert_eq!("\
+// Disassembled from test.ch8
+    CALL sub_20e
+    SE V0, 0x01
+    LDL I, 0x1234
+    JP V0, label_20a
+
+label_20a:
+    JP label_214
+    JP label_214
+
+sub_20e:
+    LD I, 0x216
+    DRW V0, V1, 2
+    RET
+
+label_214:
+    EXIT
+    .byte 0xF0, 0x90, 0x50, 0x01
+", asm);
+        assert_eq!(vec!["test.ch8: warning: 0x0208: Jump to a computed address, \
+                         following 2 table entries at 0x020a"], warnings);
+        assert_eq!(rom, reassemble(&asm));
+    }
+
+    #[test]
+    fn unknown_opcodes_and_overlaps() {
+        // Jumps to an unknown opcode, and into the middle of an LDL
+        let rom = [0x12, 0x04, 0x12, 0x08, 0x50, 0x01, 0xF0, 0x00, 0x12, 0x06, 0xAB];
+        let mut warnings = vec![];
+        let asm = disassemble(&rom, "test.ch8", &mut warnings);
+        assert_eq!(vec![
+            "test.ch8: warning: 0x0204: Reached unknown opcode 0x5001, treating it as data",
+        ], warnings);
+        assert_eq!("\
+// Disassembled from test.ch8
+    JP label_204
+    .byte 0x12, 0x08
+
+label_204:
+    .byte 0x50, 0x01, 0xF0, 0x00, 0x12, 0x06, 0xAB
+", asm);
+        // Odd lengths are kept as they are
+        assert_eq!(rom.to_vec(), reassemble(&asm));
+
+        // CALL 0x204, which is the second word of the LDL after it
+        let rom = [0x22, 0x04, 0xF0, 0x00, 0x00, 0xFD, 0x00, 0xEE];
+        warnings.clear();
+        let asm = disassemble(&rom, "test.ch8", &mut warnings);
+        assert_eq!(vec!["test.ch8: warning: 0x0204: Code overlaps other instructions, \
+                         showing it as data"], warnings);
+        assert_eq!("\
+// Disassembled from test.ch8
+    CALL 0x204
+    LDL I, 0x00FD
+    RET
+", asm);
+        assert_eq!(rom.to_vec(), reassemble(&asm));
+    }
+
+    #[test]
+    fn round_trip_test_rom() {
+        let mut rom_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        rom_path.push("roms/BC_test.ch8");
+        let rom = read_rom(rom_path.to_str().unwrap());
+        let asm = disassemble(&rom, "BC_test.ch8", &mut vec![]);
+        assert_eq!(rom, reassemble(&asm));
+    }
+}
@@ -0,0 +1,470 @@
+use system::{Chip8System, EmulatorError, MEMORY_SIZE};
+use system::debug::{Debugger, DebugStop, Register, Watchpoint, WatchKind, ALL_REGISTERS};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+mod test;
+
+// Signal numbers for stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+
+// Sent by gdb to stop a running program
+const INTERRUPT: u8 = 0x03;
+
+// The remote serial protocol side of the shared debugger.
+// Registers are numbered in the order of ALL_REGISTERS. Their values
+// are sent little endian, since the target description doesn't give an
+// architecture and gdb then assumes the host's byte order, which is
+// almost always little endian.
+#[derive(Default)]
+pub struct GdbStub {
+    debugger: Debugger,
+    last_stop: Option<DebugStop>,
+    no_ack: bool,
+    detached: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub { debugger: Debugger::new(), last_stop: None, no_ack: false, detached: false }
+    }
+
+    // Set once gdb has disconnected, or killed the program
+    pub fn has_detached(&self) -> bool {
+        self.detached
+    }
+
+    // Packets don't need acknowledging
+    pub fn no_ack(&self) -> bool {
+        self.no_ack
+    }
+
+    // Takes a packet's data, returns the reply's data if there is one.
+    // interrupted is polled while the program runs.
+    pub fn handle_packet(&mut self, c8: &mut Chip8System, packet: &str,
+                         interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        if packet == "k" {
+            self.detached = true;
+            return None
+        }
+        match self.reply_to(c8, packet, interrupted) {
+            Err(err) => Some(err),
+            Ok(reply) => Some(reply),
+        }
+    }
+
+    // Errors are replies too, e.g. "E01"
+    fn reply_to(&mut self, c8: &mut Chip8System, packet: &str,
+                interrupted: &mut dyn FnMut() -> bool) -> Result<String, String> {
+        if packet.is_empty() {
+            return Ok(String::new())
+        }
+        let (command, args) = packet.split_at(1);
+
+        match command {
+            "?" => Ok(stop_reply(self.last_stop.unwrap_or(DebugStop::Done))),
+            "g" => Ok(ALL_REGISTERS.iter().map(|reg| register_to_hex(c8, *reg)).collect()),
+            "G" => self.write_all_registers(c8, args),
+            "p" => {
+                let reg = parse_register(args)?;
+                Ok(register_to_hex(c8, reg))
+            }
+            "P" => {
+                let (num, value) = split_pair(args, '=')?;
+                let reg = parse_register(num)?;
+                let value = from_hex(value)?;
+                if value.len() != reg.size() {
+                    return Err("E01".to_string())
+                }
+                write_register(c8, reg, register_from_bytes(&value))?;
+                Ok("OK".to_string())
+            }
+            "m" => {
+                let (addr, len) = split_pair(args, ',')?;
+                let (addr, len) = (parse_word(addr)?, parse_hex(len)? as usize);
+                if (addr as usize) + len > MEMORY_SIZE {
+                    return Err("E02".to_string())
+                }
+                Ok(to_hex(&c8.read_memory(addr, len)))
+            }
+            "M" => {
+                let (location, data) = split_pair(args, ':')?;
+                let (addr, len) = split_pair(location, ',')?;
+                let data = from_hex(data)?;
+                if data.len() != (parse_hex(len)? as usize) {
+                    return Err("E01".to_string())
+                }
+                match c8.write_memory(parse_word(addr)?, &data) {
+                    Err(_) => Ok("E02".to_string()),
+                    Ok(_) => Ok("OK".to_string()),
+                }
+            }
+            "s" | "c" => {
+                // Optionally resuming from a new address
+                if !args.is_empty() {
+                    write_register(c8, Register::PC, parse_word(args)?)?;
+                }
+                let stop = if command == "s" {
+                    self.debugger.step(c8)
+                } else {
+                    self.debugger.cont(c8, interrupted)
+                };
+                self.last_stop = Some(stop);
+                Ok(stop_reply(stop))
+            }
+            "Z" | "z" => self.update_point(command == "Z", args),
+            "q" => Ok(self.query(c8, args)),
+            "Q" => {
+                if args == "StartNoAckMode" {
+                    self.no_ack = true;
+                    Ok("OK".to_string())
+                } else {
+                    Ok(String::new())
+                }
+            }
+            // There's only one thread
+            "H" | "T" => Ok("OK".to_string()),
+            "D" => {
+                self.detached = true;
+                Ok("OK".to_string())
+            }
+            // Empty means we don't support it
+            _ => Ok(String::new()),
+        }
+    }
+
+    fn write_all_registers(&mut self, c8: &mut Chip8System, args: &str)
+                           -> Result<String, String> {
+        let data = from_hex(args)?;
+        let expected: usize = ALL_REGISTERS.iter().map(|reg| reg.size()).sum();
+        if data.len() != expected {
+            return Err("E01".to_string())
+        }
+
+        let mut values = vec![];
+        let mut pos = 0;
+        for reg in ALL_REGISTERS.iter() {
+            values.push((*reg, register_from_bytes(&data[pos..pos+reg.size()])));
+            pos += reg.size();
+        }
+        // Either all of them are written or none are
+        let old: Vec<u16> = ALL_REGISTERS.iter().map(|reg| c8.read_register(*reg)).collect();
+        for (reg, value) in values {
+            if let Err(err) = write_register(c8, reg, value) {
+                for (reg, value) in ALL_REGISTERS.iter().zip(old) {
+                    let _ = c8.write_register(*reg, value);
+                }
+                return Err(err)
+            }
+        }
+        Ok("OK".to_string())
+    }
+
+    // Z/z<type>,<addr>,<kind>, where kind is the length for watchpoints
+    fn update_point(&mut self, insert: bool, args: &str) -> Result<String, String> {
+        let parts: Vec<&str> = args.split(',').collect();
+        if parts.len() != 3 {
+            return Err("E01".to_string())
+        }
+        let addr = parse_word(parts[1])?;
+        let len = parse_word(parts[2])?;
+
+        let watch_kind = match parts[0] {
+            "0" => {
+                if insert {
+                    let _ = self.debugger.add_breakpoint(addr);
+                } else {
+                    let _ = self.debugger.remove_breakpoint(addr);
+                }
+                return Ok("OK".to_string())
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            // Hardware breakpoints would be the same as software ones,
+            // so let gdb use those
+            _ => return Ok(String::new()),
+        };
+
+        let watchpoint = Watchpoint { addr, len: len.max(1), kind: watch_kind };
+        if insert {
+            let _ = self.debugger.add_watchpoint(watchpoint);
+        } else {
+            let _ = self.debugger.remove_watchpoint(watchpoint);
+        }
+        Ok("OK".to_string())
+    }
+
+    fn query(&mut self, c8: &mut Chip8System, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        }
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            return match split_pair(range, ',').and_then(|(off, len)| {
+                Ok((parse_hex(off)? as usize, parse_hex(len)? as usize))
+            }) {
+                Err(err) => err,
+                Ok((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = (start + len).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, &xml[start..end])
+                }
+            }
+        }
+        if let Some(command) = args.strip_prefix("Rcmd,") {
+            return match from_hex(command) {
+                Err(err) => err,
+                Ok(command) => {
+                    let output = monitor_command(c8, &String::from_utf8_lossy(&command));
+                    to_hex(format!("{}\n", output).as_bytes())
+                }
+            }
+        }
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+// "monitor" commands, for things gdb can't do itself
+fn monitor_command(c8: &mut Chip8System, command: &str) -> String {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["press", key] | ["release", key] => {
+            match u8::from_str_radix(key, 16) {
+                Ok(k) if k < 16 => {
+                    c8.keys[k as usize] = words[0] == "press";
+                    format!("Key {:X} {}", k, if words[0] == "press" { "held" } else { "released" })
+                }
+                _ => format!("Invalid key \"{}\", expected 0-F", key),
+            }
+        }
+        ["screen"] => c8.screen_to_str(),
+        _ => "Commands are \"press <key>\", \"release <key>\" and \"screen\"".to_string(),
+    }
+}
+
+fn stop_reply(stop: DebugStop) -> String {
+    let signal = match stop {
+        DebugStop::Exited => return "W00".to_string(),
+        DebugStop::Watchpoint(wp) => {
+            let kind = match wp.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            return format!("T{:02x}{}:{:x};", SIGTRAP, kind, wp.addr)
+        }
+        DebugStop::Interrupted => SIGINT,
+        DebugStop::Error(EmulatorError::UnknownInstruction { .. }) => SIGILL,
+        DebugStop::Error(EmulatorError::Breakpoint { .. }) => SIGTRAP,
+        DebugStop::Error(EmulatorError::InvalidKey(_)) => SIGABRT,
+        DebugStop::Error(_) => SIGSEGV,
+        DebugStop::Done | DebugStop::Breakpoint | DebugStop::WaitKey |
+            DebugStop::SelfJump => SIGTRAP,
+    };
+    format!("S{:02x}", signal)
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\
+                                <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+                                <target version=\"1.0\"><feature name=\"org.rchip8.chip8\">");
+    for reg in ALL_REGISTERS.iter() {
+        let kind = match *reg {
+            Register::PC => "code_ptr",
+            Register::I => "data_ptr",
+            _ => "uint8",
+        };
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+                              reg.name().to_lowercase(), reg.size()*8, kind));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn register_to_hex(c8: &Chip8System, reg: Register) -> String {
+    let value = c8.read_register(reg);
+    match reg.size() {
+        1 => format!("{:02x}", value),
+        _ => format!("{:02x}{:02x}", value & 0xFF, value >> 8),
+    }
+}
+
+// Little endian, like register_to_hex
+fn register_from_bytes(data: &[u8]) -> u16 {
+    data.iter().rev().fold(0, |value, b| (value << 8) | u16::from(*b))
+}
+
+fn write_register(c8: &mut Chip8System, reg: Register, value: u16) -> Result<(), String> {
+    c8.write_register(reg, value).map_err(|_| "E03".to_string())
+}
+
+fn parse_register(num: &str) -> Result<Register, String> {
+    match ALL_REGISTERS.get(parse_hex(num)? as usize) {
+        None => Err("E03".to_string()),
+        Some(reg) => Ok(*reg),
+    }
+}
+
+fn split_pair(args: &str, separator: char) -> Result<(&str, &str), String> {
+    match args.find(separator) {
+        None => Err("E01".to_string()),
+        Some(pos) => Ok((&args[..pos], &args[pos+1..])),
+    }
+}
+
+fn parse_hex(num: &str) -> Result<u32, String> {
+    u32::from_str_radix(num, 16).map_err(|_| "E01".to_string())
+}
+
+fn parse_word(num: &str) -> Result<u16, String> {
+    u16::from_str_radix(num, 16).map_err(|_| "E01".to_string())
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex.as_bytes().chunks(2).map(|pair| {
+        match pair {
+            [high, low] => u8::from_str_radix(&format!("{}{}", *high as char, *low as char), 16)
+                               .map_err(|_| "E01".to_string()),
+            _ => Err("E01".to_string()),
+        }
+    }).collect()
+}
+
+// "$<data>#<checksum>" with the special characters escaped
+pub fn make_packet(data: &str) -> String {
+    let mut escaped = String::new();
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push(((c as u8) ^ 0x20) as char);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    let checksum = escaped.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", escaped, checksum)
+}
+
+fn read_byte(stream: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Returns the next packet's data, or None when the connection closes.
+// Acks are sent unless no_ack is set, and ignored when received.
+pub fn read_packet<S: Read + Write>(stream: &mut S, no_ack: bool) -> io::Result<Option<String>> {
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(None),
+            Some(b'$') => {},
+            // Acks, or an interrupt when there's nothing to interrupt
+            Some(_) => continue,
+        }
+
+        let mut data = vec![];
+        let mut checksum: u8 = 0;
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => {
+                    checksum = checksum.wrapping_add(b);
+                    data.push(b);
+                }
+            }
+        }
+        let mut sent = [0; 2];
+        for b in sent.iter_mut() {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(c) => *b = c,
+            }
+        }
+
+        let sent = u8::from_str_radix(&String::from_utf8_lossy(&sent), 16).ok();
+        if sent != Some(checksum) {
+            if !no_ack {
+                stream.write_all(b"-")?;
+            }
+            continue
+        }
+        if !no_ack {
+            stream.write_all(b"+")?;
+        }
+
+        let mut unescaped = vec![];
+        let mut bytes = data.into_iter();
+        while let Some(b) = bytes.next() {
+            if b == b'}' {
+                if let Some(next) = bytes.next() {
+                    unescaped.push(next ^ 0x20);
+                }
+            } else {
+                unescaped.push(b);
+            }
+        }
+        return Ok(Some(String::from_utf8_lossy(&unescaped).into_owned()))
+    }
+}
+
+// Answer packets from one connection until gdb goes away
+pub fn serve_gdb(c8: &mut Chip8System, mut stream: TcpStream) -> io::Result<()> {
+    let mut stub = GdbStub::new();
+
+    while !stub.has_detached() {
+        let packet = match read_packet(&mut stream, stub.no_ack())? {
+            None => break,
+            Some(packet) => packet,
+        };
+
+        let poll_stream = stream.try_clone()?;
+        let mut interrupted = || {
+            // Anything but an interrupt is dropped, gdb shouldn't send
+            // anything else while the program is running.
+            if poll_stream.set_nonblocking(true).is_err() {
+                return false
+            }
+            let mut byte = [0];
+            let got = (&poll_stream).read(&mut byte);
+            let _ = poll_stream.set_nonblocking(false);
+            match got {
+                Ok(1) => byte[0] == INTERRUPT,
+                _ => false,
+            }
+        };
+
+        if let Some(reply) = stub.handle_packet(c8, &packet, &mut interrupted) {
+            stream.write_all(make_packet(&reply).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Wait for gdb to connect to the port on localhost, then debug with it
+pub fn run_gdb_server(c8: &mut Chip8System, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for gdb on port {}", listener.local_addr()?.port());
+    let (stream, addr) = listener.accept()?;
+    println!("gdb connected from {}", addr);
+    serve_gdb(c8, stream)
+}
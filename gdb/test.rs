@@ -0,0 +1,200 @@
+#[cfg(test)]
+mod test {
+    use gdb::*;
+    use system::{Chip8System, make_system, instrs_to_rom};
+    use system::debug::Register;
+    use system::quirks::Quirks;
+    use asm::parse_asm_str;
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn make_debug_system(asm: &str) -> Chip8System {
+        let instrs = parse_asm_str(asm).unwrap();
+        make_system(&instrs_to_rom(&instrs), Quirks::default())
+    }
+
+    fn send(stub: &mut GdbStub, c8: &mut Chip8System, packet: &str) -> String {
+        stub.handle_packet(c8, packet, &mut || false).unwrap()
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut c8 = make_debug_system("LD V1, 0x12\nLD I, 0x345");
+        let mut stub = GdbStub::new();
+
+        assert_eq!("S05", send(&mut stub, &mut c8, "?"));
+        assert_eq!("S05", send(&mut stub, &mut c8, "s"));
+        assert_eq!("S05", send(&mut stub, &mut c8, "s"));
+
+        // V0-VF, I, PC, SP, DT, ST
+        // I and PC are little endian
+        assert_eq!(format!("00120000000000000000000000000000{}", "45030402000000"),
+                   send(&mut stub, &mut c8, "g"));
+        assert_eq!("0402", send(&mut stub, &mut c8, "p11"));
+        assert_eq!("E03", send(&mut stub, &mut c8, "p15"));
+
+        assert_eq!("OK", send(&mut stub, &mut c8, "P2=ab"));
+        assert_eq!(0xab, c8.read_register(Register::V(2)));
+        assert_eq!("E01", send(&mut stub, &mut c8, "P2=0001"));
+        assert_eq!("OK", send(&mut stub, &mut c8, "P10=0003"));
+        assert_eq!(0x300, c8.read_register(Register::I));
+
+        // A bad value leaves all the registers alone
+        let regs = send(&mut stub, &mut c8, "g");
+        assert_eq!("E03", send(&mut stub, &mut c8,
+                               &format!("G{}ff0000", &regs[..40])));
+        assert_eq!(regs, send(&mut stub, &mut c8, "g"));
+
+        assert_eq!("6112a3", send(&mut stub, &mut c8, "m200,3"));
+        assert_eq!("OK", send(&mut stub, &mut c8, "M300,2:beef"));
+        assert_eq!("beef", send(&mut stub, &mut c8, "m300,2"));
+        assert_eq!("E02", send(&mut stub, &mut c8, "mffff,2"));
+        assert_eq!("E02", send(&mut stub, &mut c8, "Mffff,2:0102"));
+        assert_eq!("E01", send(&mut stub, &mut c8, "M300,2:01"));
+        assert_eq!("E01", send(&mut stub, &mut c8, "mxyz"));
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut c8 = make_debug_system("\
+            LD V0, 1
+            LD I, 0x300
+            LD [I], V0
+        end:
+            JP end");
+        let mut stub = GdbStub::new();
+
+        assert_eq!("OK", send(&mut stub, &mut c8, "Z0,202,2"));
+        assert_eq!("S05", send(&mut stub, &mut c8, "c"));
+        assert_eq!(0x202, c8.read_register(Register::PC));
+        assert_eq!("OK", send(&mut stub, &mut c8, "z0,202,2"));
+
+        assert_eq!("OK", send(&mut stub, &mut c8, "Z2,300,1"));
+        assert_eq!("T05watch:300;", send(&mut stub, &mut c8, "c"));
+        assert_eq!("T05watch:300;", send(&mut stub, &mut c8, "?"));
+        assert_eq!(0x206, c8.read_register(Register::PC));
+        assert_eq!("OK", send(&mut stub, &mut c8, "z2,300,1"));
+
+        // Hardware breakpoints aren't supported
+        assert_eq!("", send(&mut stub, &mut c8, "Z1,200,2"));
+        assert_eq!("E01", send(&mut stub, &mut c8, "Z0,200"));
+
+        // Resuming from a new address
+        assert_eq!("S05", send(&mut stub, &mut c8, "s200"));
+        assert_eq!(0x202, c8.read_register(Register::PC));
+    }
+
+    #[test]
+    fn queries_and_errors() {
+        let mut c8 = make_debug_system("EXIT");
+        let mut stub = GdbStub::new();
+
+        assert!(send(&mut stub, &mut c8, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert_eq!("1", send(&mut stub, &mut c8, "qAttached"));
+        assert_eq!("", send(&mut stub, &mut c8, "vMustReplyEmpty"));
+
+        let xml = send(&mut stub, &mut c8, "qXfer:features:read:target.xml:0,10000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+        assert_eq!("m<?xml", send(&mut stub, &mut c8, "qXfer:features:read:target.xml:0,5"));
+
+        // "monitor press 5", the reply is hex encoded text
+        assert_eq!("4b657920352068656c640a", send(&mut stub, &mut c8, "qRcmd,70726573732035"));
+        assert!(c8.keys[5]);
+
+        assert_eq!("W00", send(&mut stub, &mut c8, "c"));
+
+        let mut c8 = make_debug_system("CLS");
+        c8.write_memory(0x200, &[0x80, 0x08]).unwrap();
+        assert_eq!("S04", send(&mut stub, &mut c8, "s"));
+
+        assert!(!stub.has_detached());
+        assert_eq!(None, stub.handle_packet(&mut c8, "k", &mut || false));
+        assert!(stub.has_detached());
+    }
+
+    // Reads from one buffer and writes to another
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!("$OK#9a", make_packet("OK"));
+        assert_eq!("$}]#da", make_packet("}"));
+
+        let input = b"+$m200,2#5d$bad#00$}]#da".to_vec();
+        let mut stream = Loopback { input: Cursor::new(input), output: vec![] };
+        assert_eq!(Some("m200,2".to_string()), read_packet(&mut stream, false).unwrap());
+        // The bad checksum is skipped
+        assert_eq!(Some("}".to_string()), read_packet(&mut stream, false).unwrap());
+        assert_eq!(None, read_packet(&mut stream, false).unwrap());
+        assert_eq!(b"+-+", stream.output.as_slice());
+    }
+
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut reply = vec![];
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+            if reply.len() > 3 && reply[reply.len()-3] == b'#' {
+                break
+            }
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn tcp_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut c8 = make_debug_system("LD V3, 0x45\nloop:\nADD V0, 1\nJP loop");
+            let (stream, _) = listener.accept().unwrap();
+            serve_gdb(&mut c8, stream).unwrap();
+            c8
+        });
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(make_packet("s").as_bytes()).unwrap();
+        assert_eq!("+$S05#b8", read_reply(&mut client));
+        client.write_all(b"+").unwrap();
+
+        client.write_all(make_packet("QStartNoAckMode").as_bytes()).unwrap();
+        assert_eq!("+$OK#9a", read_reply(&mut client));
+        client.write_all(b"+").unwrap();
+
+        // No more acks
+        client.write_all(make_packet("p3").as_bytes()).unwrap();
+        assert_eq!(make_packet("45"), read_reply(&mut client));
+
+        // Stopped by an interrupt
+        client.write_all(make_packet("c").as_bytes()).unwrap();
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(make_packet("S02"), read_reply(&mut client));
+
+        client.write_all(make_packet("D").as_bytes()).unwrap();
+        assert_eq!(make_packet("OK"), read_reply(&mut client));
+
+        let c8 = server.join().unwrap();
+        assert_eq!(0x45, c8.read_register(Register::V(3)));
+    }
+}
@@ -0,0 +1,22 @@
+//! The Chip8 emulator core and assembler behind rchip8.
+//!
+//! ```
+//! use rchip8::{make_system, parse_asm_str, instrs_to_rom, Quirks};
+//!
+//! let instrs = parse_asm_str("LD V0, 0x12").unwrap();
+//! let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+//! let instr = c8.fetch_and_decode().unwrap();
+//! c8.execute(&instr).unwrap();
+//! ```
+
+pub mod system;
+pub mod asm;
+pub mod disasm;
+pub mod debugger;
+pub mod gdb;
+pub mod dap;
+
+pub use system::{Chip8System, EmulatorError, InstrFlags, make_system, read_rom, instrs_to_rom};
+pub use system::instr::{Instr, Instruction, InstrKind, AddressOrSymbol, DecodeError, decode, decode_or_undef};
+pub use system::quirks::Quirks;
+pub use asm::{parse_asm, parse_asm_str, parse_asm_with_info, DebugInfo};
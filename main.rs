@@ -1,31 +1,116 @@
-mod system;
-mod asm;
+extern crate rchip8;
+#[cfg(feature = "sdl")]
 mod sdl;
-use system::{make_system, read_rom, instrs_to_rom};
-use asm::parse_asm;
-use sdl::{sdl_init, process_events, draw_screen, read_keys, wait_on_key};
+use rchip8::{system, asm};
+use system::{Chip8System, make_system, read_rom, instrs_to_rom, DEFAULT_CYCLES_PER_FRAME};
+use system::quirks::{Quirks, QUIRKS_PRESET_NAMES};
+use asm::{parse_asm_with_info, DebugInfo};
+use system::headless::{RunLimits, run_headless};
+use system::movie::{Movie, MoviePlayer};
+use system::trace::{Tracer, TraceFilter, parse_trace};
+use system::tracediff::diff_traces;
+use system::profile::Profiler;
+use system::coverage::Coverage;
+use system::romdb::RomDatabase;
+use rchip8::disasm::disassemble;
+use rchip8::debugger::run_debugger;
+use rchip8::gdb::run_gdb_server;
+use rchip8::dap::run_dap_server;
+#[cfg(feature = "sdl")]
+use system::{EmulatorError, FRAMES_PER_SECOND};
+#[cfg(feature = "sdl")]
+use system::rewind::RewindBuffer;
+#[cfg(feature = "sdl")]
+use system::romdb::RomInfo;
+#[cfg(feature = "sdl")]
+use sdl::{sdl_init, process_events, draw_screen, read_keys, wait_on_key, rewind_held,
+          make_key_map, UserEvent};
 use std::{env, process};
 use std::path::Path;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
 use std::io::Read;
 use std::fs::OpenOptions;
 use std::io::Write;
+#[cfg(feature = "sdl")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "sdl")]
+use std::thread;
+
+// One snapshot per emulated frame, 30 seconds worth
+#[cfg(feature = "sdl")]
+const REWIND_CAPACITY: usize = (FRAMES_PER_SECOND as usize) * 30;
 
 pub fn main() {
     let help = "\
-            rchip8 <mode> <file> <scaling factor (-i) / output file name (-a)> -s (-i only)\n\
+            rchip8 <mode> <file> <scaling factor (-i) / output file name (-a)> [options]\n\
+            rchip8 -d <ROM file> [output file name]\n\
+            rchip8 -t <trace file> <other trace file> [--context <n>]\n\
+            rchip8 --dap\n\
+            \n\
+            The scaling factor can be left out if options follow.\n\
+            \n\
+            The modes are:\n\
+                -a : assembler, where <file> is an assembly file. --symbols <file>\n\
+                     also writes the address of each label to file.\n\
+                -i : interpret, where <file> is a ROM file\n\
+                -d : disassemble, where <file> is a ROM file. Follows the program\n\
+                     from 0x200 to find its code, anything it doesn't reach is shown\n\
+                     as .byte data. Prints the assembly unless an output file is\n\
+                     given, which -a will build back into the same ROM.\n\
+                -g : debug, where <file> is a ROM file. Takes the --cpf, --quirks,\n\
+                     --seed, --romdb and --gdb options, type \"help\" at the prompt for commands.\n\
+                     --gdb <port> waits for gdb to connect to that port on localhost\n\
+                     instead (\"target remote localhost:<port>\").\n\
+                -t : compare two files written by --trace and show where they first\n\
+                     differ. --context <n> sets how many instructions to show either\n\
+                     side. (default 3) Exits with 1 if they differ.\n\
             \n\
-            The two modes are:\n\
-                -a : assembler, where <file> is an assembly file\n\
-                -i : interpret, where <file> is a ROM file and '-s' enables sound\n\
+            --dap serves the Debug Adapter Protocol over stdin and stdout, for editors.\n\
+            The program to debug is given by the editor's launch request.\n\
             \n\
             Scaling factor multiplies the size of each Chip8 pixel. (default 1)\n\
-            e.g. 2 means each block is 2x2 pixels in the final output.";
+            e.g. 2 means each block is 2x2 pixels in the final output.\n\
+            \n\
+            Interpreter options:\n\
+                -s                : enable sound\n\
+                --cpf <n>         : instructions executed per 60Hz frame (default 10)\n\
+                --quirks <preset> : emulate another interpreter's behaviour,\n\
+                                    one of default, vip, chip48 or schip\n\
+                --seed <n>        : seed for RND, to make runs repeatable\n\
+                --romdb <file>    : also look up ROMs in this database. Known ROMs\n\
+                                    get their platform, quirks, speed and key\n\
+                                    layout from it, unless the options above are given\n\
+                --record <file>   : record keys pressed to a movie file\n\
+                --play <file>     : play a movie file's keys, also works headless\n\
+                --trace <file>    : write each instruction run and what it changed to file\n\
+                --trace-pc <range>: only trace instructions in an address range,\n\
+                                    like 0x200-0x2ff. Can be given more than once.\n\
+                --trace-addr <range> : only trace instructions that use memory in a range\n\
+                --profile         : when the run ends, print how often each address\n\
+                                    and subroutine ran, and which subroutines call which\n\
+                --symbols <file>  : label addresses in the profile with a symbol map\n\
+                                    from -a\n\
+                --coverage <file> : when the run ends, write which ROM bytes were\n\
+                                    executed, read or written to file\n\
+                --source <file>   : show coverage on the assembly source the ROM\n\
+                                    was built from\n\
+            \n\
+            Headless options, to run without a window and print the final state:\n\
+                --headless        : run headless, at least one limit below or --play\n\
+                                    is required\n\
+                --instrs <n>      : stop after n instructions\n\
+                --frames <n>      : stop after n frames\n\
+                --until-pc <addr> : stop before executing the instruction at addr\n\
+                --until-self-jump : stop after a jump to itself\n\
+                --output <file>   : write the final state to file instead of stdout";
 
     enum Mode {
         Interpret,
         Assemble,
+        Debug,
+        TraceDiff,
+        Disassemble,
     };
     let mut mode = Mode::Interpret;
 
@@ -33,6 +118,30 @@ pub fn main() {
     let mut output_file: Option<String> = None;
     let mut scaling_factor = 1;
     let mut sound_enable = false;
+    let mut sys_options = SystemOptions {
+        cycles_per_frame: None,
+        quirks: None,
+        rom_db: RomDatabase::built_in(),
+        seed: None,
+        movie: None,
+        trace_path: None,
+        trace_filter: TraceFilter::default(),
+        profile: false,
+        symbols: None,
+        coverage_path: None,
+        coverage_source: None,
+    };
+    // Whether any of the settings a movie brings with it were given
+    let mut settings_given = false;
+    let mut record_path: Option<String> = None;
+    let mut gdb_port: Option<u16> = None;
+    let mut headless = false;
+    let mut limits = RunLimits::default();
+    let mut headless_output: Option<String> = None;
+    let mut other_trace: Option<String> = None;
+    let mut context_lines: Option<usize> = None;
+    let mut symbols_path: Option<String> = None;
+    let mut source_path: Option<String> = None;
 
     let args = env::args().collect::<Vec<String>>();
 
@@ -41,8 +150,14 @@ pub fn main() {
         process::exit(0);
     }
 
+    // The editor says what to run once it's connected
+    if (args.len() == 2) && (args[1] == "--dap") {
+        run_dap_server();
+        process::exit(0);
+    }
+
     if args.len() < 2 {
-        println!("Mode argument required, one of '-i' (interpret) or '-a' (assemble).");
+        println!("Mode argument required, one of '-i' (interpret), '-a' (assemble), '-d' (disassemble), '-g' (debug) or '-t' (trace diff).");
         process::exit(1);
     }
     if args.len() < 3 {
@@ -50,13 +165,20 @@ pub fn main() {
         process::exit(1);
     }
 
-    for (pos, argument) in args.iter().enumerate() {
+    // So that the scaling factor is optional, debugging doesn't have one
+    let num_positional = if (args[1] == "-g") ||
+                            ((args.len() > 3) && args[3].starts_with('-')) { 3 } else { 4 };
+
+    for (pos, argument) in args.iter().enumerate().take(num_positional) {
         match pos {
             0 => {},
             1 => { // Mode
                 mode = match argument.as_str() {
                     "-i" => Mode::Interpret,
                     "-a" => Mode::Assemble,
+                    "-g" => Mode::Debug,
+                    "-t" => Mode::TraceDiff,
+                    "-d" => Mode::Disassemble,
                     _ => {
                         println!("Unknown mode argument: \"{}\"", argument);
                         process::exit(1);
@@ -68,8 +190,9 @@ pub fn main() {
                     rom_path = Some(argument.to_string());
                 } else {
                     let file_type = match mode {
-                        Mode::Interpret => "ROM",
+                        Mode::Interpret | Mode::Debug | Mode::Disassemble => "ROM",
                         Mode::Assemble => "Assembly",
+                        Mode::TraceDiff => "Trace",
                     };
                     println!("{} file \"{}\" not found.", file_type, argument);
                     process::exit(1);
@@ -86,40 +209,465 @@ pub fn main() {
                             Ok(v) => v,
                         };
                     },
-                    Mode::Assemble => output_file = Some(argument.to_string()),
+                    Mode::Assemble | Mode::Disassemble => output_file = Some(argument.to_string()),
+                    Mode::Debug => {},
+                    Mode::TraceDiff => {
+                        if !Path::new(&argument).exists() {
+                            println!("Trace file \"{}\" not found.", argument);
+                            process::exit(1);
+                        }
+                        other_trace = Some(argument.to_string());
+                    }
                 };
             },
-            4 => {
-                match mode {
-                    Mode::Interpret => {
-                        match argument.as_str() {
-                            "-s" => sound_enable = true,
-                            _ => {
-                                println!("Expected \"-s\", got \"{}\"", argument);
-                                process::exit(1);
-                            }
-                        };
-                    }
-                    Mode::Assemble => {
-                        println!("Too many arguments to assemble mode.");
+            _ => {},
+        }
+    }
+
+    // Anything after the positional arguments is an option
+    let mut options = args.iter().skip(num_positional);
+    while let Some(argument) = options.next() {
+        if let Mode::Assemble = mode {
+            if argument != "--symbols" {
+                println!("Only --symbols can be used with -a.");
+                process::exit(1);
+            }
+        }
+        if let Mode::Disassemble = mode {
+            println!("Too many arguments to disassemble mode.");
+            process::exit(1);
+        }
+        if let Mode::TraceDiff = mode {
+            if argument != "--context" {
+                println!("Only --context can be used with -t.");
+                process::exit(1);
+            }
+        }
+
+        match argument.as_str() {
+            "-s" => sound_enable = true,
+            "--cpf" => {
+                let cycles = parse_option_number(argument, options.next());
+                if (cycles == 0) || (cycles > u64::from(u32::MAX)) {
+                    println!("Option \"{}\" must be at least 1", argument);
+                    process::exit(1);
+                }
+                sys_options.cycles_per_frame = Some(cycles as u32);
+                settings_given = true;
+            }
+            "--quirks" => {
+                let name = parse_option_string(argument, options.next());
+                settings_given = true;
+                sys_options.quirks = match Quirks::from_name(&name) {
+                    None => {
+                        println!("Unknown quirks preset \"{}\", expected one of: {}",
+                                 name, QUIRKS_PRESET_NAMES.join(", "));
                         process::exit(1);
                     }
+                    Some(q) => Some(q),
+                };
+            }
+            "--seed" => {
+                sys_options.seed = Some(parse_option_number(argument, options.next()));
+                settings_given = true;
+            }
+            "--romdb" => {
+                let path = parse_option_string(argument, options.next());
+                sys_options.rom_db.extend(load_rom_db(&path));
+            }
+            "--record" => record_path = Some(parse_option_string(argument, options.next())),
+            "--play" => {
+                let path = parse_option_string(argument, options.next());
+                sys_options.movie = Some(load_movie(&path));
+            }
+            "--headless" => headless = true,
+            "--instrs" => limits.max_instrs = Some(parse_option_number(argument, options.next())),
+            "--frames" => limits.max_frames = Some(parse_option_number(argument, options.next())),
+            "--until-pc" => {
+                let addr = parse_option_number(argument, options.next());
+                if addr > 0xFFFF {
+                    println!("Option \"{}\" must be a 16 bit address", argument);
+                    process::exit(1);
+                }
+                limits.until_pc = Some(addr as u16);
+            }
+            "--until-self-jump" => limits.until_self_jump = true,
+            "--output" => headless_output = Some(parse_option_string(argument, options.next())),
+            "--trace" => sys_options.trace_path = Some(parse_option_string(argument, options.next())),
+            "--trace-pc" => {
+                let range = parse_option_range(argument, options.next());
+                sys_options.trace_filter.pc_ranges.push(range);
+            }
+            "--trace-addr" => {
+                let range = parse_option_range(argument, options.next());
+                sys_options.trace_filter.addr_ranges.push(range);
+            }
+            "--context" => {
+                context_lines = Some(parse_option_number(argument, options.next()) as usize);
+            }
+            "--profile" => sys_options.profile = true,
+            "--symbols" => symbols_path = Some(parse_option_string(argument, options.next())),
+            "--coverage" => {
+                sys_options.coverage_path = Some(parse_option_string(argument, options.next()));
+            }
+            "--source" => source_path = Some(parse_option_string(argument, options.next())),
+            "--gdb" => {
+                let port = parse_option_number(argument, options.next());
+                if port > u64::from(u16::MAX) {
+                    println!("Option \"{}\" must be a port number", argument);
+                    process::exit(1);
                 }
+                gdb_port = Some(port as u16);
             }
             _ => {
-                println!("Unexpected argument \"{}\" in position {}", argument, pos);
+                println!("Unknown option \"{}\"", argument);
                 process::exit(1);
-            },
+            }
+        }
+    }
+
+    if let Mode::Assemble = mode {
+        if output_file.is_none() {
+            println!("Output file name is required.");
+            process::exit(1);
+        }
+    }
+    if let Mode::TraceDiff = mode {
+        if other_trace.is_none() {
+            println!("Two trace files are required.");
+            process::exit(1);
+        }
+    } else if context_lines.is_some() {
+        println!("--context can only be used with -t.");
+        process::exit(1);
+    }
+    if let Mode::Debug = mode {
+        if headless || sound_enable || record_path.is_some() || sys_options.movie.is_some() ||
+           !limits.is_unlimited() || headless_output.is_some() ||
+           sys_options.trace_path.is_some() || sys_options.profile || symbols_path.is_some() ||
+           sys_options.coverage_path.is_some() || source_path.is_some() {
+            println!("Only --cpf, --quirks, --seed, --romdb and --gdb can be used with -g.");
+            process::exit(1);
+        }
+    } else if gdb_port.is_some() {
+        println!("--gdb can only be used with -g.");
+        process::exit(1);
+    }
+    if let Mode::Interpret = mode {
+        if let Some(ref path) = symbols_path {
+            if !sys_options.profile {
+                println!("--symbols needs --profile.");
+                process::exit(1);
+            }
+            sys_options.symbols = Some(load_symbols(path));
+        }
+        if let Some(ref path) = source_path {
+            if sys_options.coverage_path.is_none() {
+                println!("--source needs --coverage.");
+                process::exit(1);
+            }
+            sys_options.coverage_source = Some(load_coverage_source(path, rom_path.as_ref().unwrap()));
         }
     }
+    if sys_options.trace_path.is_none() && (sys_options.trace_filter != TraceFilter::default()) {
+        println!("--trace-pc and --trace-addr need --trace.");
+        process::exit(1);
+    }
+    if headless && limits.is_unlimited() && sys_options.movie.is_none() {
+        println!("Headless mode needs at least one of --instrs, --frames, \
+                  --until-pc, --until-self-jump or --play.");
+        process::exit(1);
+    }
+    if sys_options.movie.is_some() && settings_given {
+        println!("--cpf, --quirks and --seed can't be used with --play, \
+                  the movie's own settings are used.");
+        process::exit(1);
+    }
+    if record_path.is_some() && (headless || sys_options.movie.is_some()) {
+        println!("--record can't be used with --headless or --play.");
+        process::exit(1);
+    }
+    if !headless && (!limits.is_unlimited() || headless_output.is_some()) {
+        println!("Run limits and --output are only used with --headless.");
+        process::exit(1);
+    }
 
     match mode {
-        Mode::Interpret => interpret_file(scaling_factor, &rom_path.unwrap(), sound_enable),
-        Mode::Assemble => assemble_file(&rom_path.unwrap(), &output_file.unwrap()),
+        Mode::Interpret if headless => run_headless_file(&rom_path.unwrap(), &sys_options,
+                                                         &limits, headless_output),
+        Mode::Interpret => interpret_file(scaling_factor, &rom_path.unwrap(), sound_enable,
+                                          &sys_options, record_path),
+        Mode::Assemble => assemble_file(&rom_path.unwrap(), &output_file.unwrap(), symbols_path),
+        Mode::Disassemble => disassemble_file(&rom_path.unwrap(), output_file),
+        Mode::TraceDiff => diff_trace_files(&rom_path.unwrap(), &other_trace.unwrap(),
+                                            context_lines.unwrap_or(3)),
+        Mode::Debug => {
+            let mut c8 = sys_options.make_system(&rom_path.unwrap());
+            match gdb_port {
+                None => run_debugger(&mut c8),
+                Some(port) => {
+                    if let Err(e) = run_gdb_server(&mut c8, port) {
+                        println!("gdb server failed: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
     }
 }
 
-fn assemble_file(asm_path: &str, output_file: &str) {
+// How to set up the system, whichever way it's going to be run
+// Settings not given fall back to the ROM database, then the defaults.
+struct SystemOptions {
+    cycles_per_frame: Option<u32>,
+    quirks: Option<Quirks>,
+    seed: Option<u64>,
+    rom_db: RomDatabase,
+    // A movie to play, which has its own settings for the above
+    movie: Option<Movie>,
+    trace_path: Option<String>,
+    trace_filter: TraceFilter,
+    profile: bool,
+    // Labels for the profile
+    symbols: Option<DebugInfo>,
+    coverage_path: Option<String>,
+    // The assembly the ROM came from, to show coverage on
+    coverage_source: Option<(String, DebugInfo)>,
+}
+
+impl SystemOptions {
+    fn make_system(&self, rom_path: &str) -> Chip8System {
+        let rom = read_rom(rom_path);
+        let mut c8 = match self.movie {
+            Some(ref movie) => movie.make_system(&rom),
+            None => {
+                let info = self.rom_db.lookup(&rom);
+                let quirks = self.quirks.or_else(|| info.and_then(|i| i.get_quirks()));
+                let mut c8 = make_system(&rom, quirks.unwrap_or_default());
+                c8.set_cycles_per_frame(self.cycles_per_frame
+                                        .or_else(|| info.and_then(|i| i.cycles_per_frame))
+                                        .unwrap_or(DEFAULT_CYCLES_PER_FRAME));
+                if let Some(seed) = self.seed {
+                    c8.set_seed(seed);
+                }
+                c8
+            }
+        };
+
+        if let Some(ref path) = self.trace_path {
+            match File::create(path) {
+                Err(why) => {
+                    println!("Couldn't create trace file \"{}\": {}", path, why);
+                    process::exit(1);
+                }
+                Ok(file) => c8.set_tracer(Some(Tracer::new(Box::new(BufWriter::new(file)),
+                                                           self.trace_filter.clone()))),
+            }
+        }
+        if self.profile {
+            c8.set_profiler(Some(Profiler::new()));
+        }
+        if self.coverage_path.is_some() {
+            c8.set_coverage(Some(Coverage::new(0x200, rom.len())));
+        }
+        c8
+    }
+
+    // What make_system and the key map take from the ROM's database entry,
+    // which is only what the command line and any movie don't give
+    #[cfg(feature = "sdl")]
+    fn rom_db_settings(&self, info: &RomInfo) -> Vec<&'static str> {
+        let mut settings = vec![];
+        if self.movie.is_none() {
+            if self.quirks.is_none() && info.get_quirks().is_some() {
+                settings.push("quirks");
+            }
+            if self.cycles_per_frame.is_none() && info.cycles_per_frame.is_some() {
+                settings.push("cycles per frame");
+            }
+        }
+        if !info.keys.is_empty() {
+            settings.push("keys");
+        }
+        settings
+    }
+
+    // Write out everything collected during the run
+    fn finish_run(&self, c8: &mut Chip8System) {
+        c8.flush_trace();
+        if let Some(profiler) = c8.get_profiler() {
+            println!("{}", profiler.report(c8, self.symbols.as_ref(), 20));
+        }
+        if let (Some(coverage), Some(path)) = (c8.get_coverage(), self.coverage_path.as_ref()) {
+            let listing = match self.coverage_source {
+                Some((ref source, ref info)) => coverage.source_listing(source, info),
+                None => coverage.listing(c8),
+            };
+            let report = format!("{}\n\n{}\n", coverage.summary(), listing);
+            if let Err(why) = File::create(path).and_then(|mut f| f.write_all(report.as_bytes())) {
+                println!("Couldn't write coverage to \"{}\": {}", path, why);
+            }
+        }
+    }
+}
+
+fn load_rom_db(path: &str) -> RomDatabase {
+    let text = match std::fs::read_to_string(path) {
+        Err(why) => {
+            println!("Couldn't read ROM database \"{}\": {}", path, why);
+            process::exit(1);
+        }
+        Ok(text) => text,
+    };
+    match RomDatabase::parse(&text, path) {
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        }
+        Ok(db) => db,
+    }
+}
+
+// Assembles the source to find which lines the ROM's bytes came from
+fn load_coverage_source(path: &str, rom_path: &str) -> (String, DebugInfo) {
+    let source = match std::fs::read_to_string(path) {
+        Err(why) => {
+            println!("Couldn't read assembly file \"{}\": {}", path, why);
+            process::exit(1);
+        }
+        Ok(source) => source,
+    };
+    match parse_asm_with_info(&source, path, &mut vec![]) {
+        Err(msgs) => {
+            println!("{}", msgs);
+            process::exit(1);
+        }
+        Ok((instrs, info)) => {
+            if instrs_to_rom(&instrs) != read_rom(rom_path) {
+                println!("\"{}\" doesn't assemble to the ROM being run.", path);
+                process::exit(1);
+            }
+            (source, info)
+        }
+    }
+}
+
+fn load_symbols(path: &str) -> DebugInfo {
+    let text = match std::fs::read_to_string(path) {
+        Err(why) => {
+            println!("Couldn't read symbol map \"{}\": {}", path, why);
+            process::exit(1);
+        }
+        Ok(text) => text,
+    };
+    match DebugInfo::parse_symbol_map(&text, path) {
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        }
+        Ok(info) => info,
+    }
+}
+
+fn parse_option_string(option: &str, value: Option<&String>) -> String {
+    match value {
+        None => {
+            println!("Option \"{}\" requires a value", option);
+            process::exit(1);
+        }
+        Some(v) => v.to_string(),
+    }
+}
+
+// <start>-<end>, inclusive, or a single address
+fn parse_option_range(option: &str, value: Option<&String>) -> (u16, u16) {
+    let value = parse_option_string(option, value);
+    let (start, end) = match value.find('-') {
+        None => (value.clone(), value.clone()),
+        Some(pos) => (value[..pos].to_string(), value[pos+1..].to_string()),
+    };
+    let start = parse_option_number(option, Some(&start));
+    let end = parse_option_number(option, Some(&end));
+    if (start > end) || (end > 0xFFFF) {
+        println!("Option \"{}\" must be an address range like 0x200-0x2ff", option);
+        process::exit(1);
+    }
+    (start as u16, end as u16)
+}
+
+// Accepts decimal or "0x" prefixed hex
+fn parse_option_number(option: &str, value: Option<&String>) -> u64 {
+    let value = match value {
+        None => {
+            println!("Option \"{}\" requires a value", option);
+            process::exit(1);
+        }
+        Some(v) => v,
+    };
+
+    let parsed = if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        value.parse::<u64>()
+    };
+
+    match parsed {
+        Err(msg) => {
+            println!("Invalid value \"{}\" for option \"{}\": {}", value, option, msg);
+            process::exit(1);
+        }
+        Ok(v) => v,
+    }
+}
+
+fn read_trace_file(path: &str) -> Vec<system::trace::TraceEntry> {
+    let text = match std::fs::read_to_string(path) {
+        Err(why) => {
+            println!("Couldn't read trace file \"{}\": {}", path, why);
+            process::exit(1);
+        }
+        Ok(text) => text,
+    };
+    match parse_trace(&text, path) {
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        }
+        Ok(entries) => entries,
+    }
+}
+
+fn diff_trace_files(a_path: &str, b_path: &str, context: usize) {
+    let a = read_trace_file(a_path);
+    let b = read_trace_file(b_path);
+    match diff_traces(&a, &b, a_path, b_path, context) {
+        None => println!("Traces match for {} instructions.", a.len()),
+        Some(report) => {
+            println!("{}", report);
+            process::exit(1);
+        }
+    }
+}
+
+fn disassemble_file(rom_path: &str, output_file: Option<String>) {
+    let mut warnings = vec![];
+    let asm = disassemble(&read_rom(rom_path), rom_path, &mut warnings);
+    for w in warnings {
+        println!("{}", w);
+    }
+
+    match output_file {
+        None => print!("{}", asm),
+        Some(path) => {
+            if let Err(why) = File::create(&path).and_then(|mut f| f.write_all(asm.as_bytes())) {
+                println!("Couldn't write to output file \"{}\": {}", path, why);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn assemble_file(asm_path: &str, output_file: &str, symbols_path: Option<String>) {
     let file = match File::open(asm_path) {
         Err(why) => panic!("Couldn't open assembly file: {}",why.to_string()),
         Ok(file) => file,
@@ -132,11 +680,11 @@ fn assemble_file(asm_path: &str, output_file: &str) {
     };
 
     let mut warnings: Vec<String> = vec![];
-    let res = parse_asm(&contents, asm_path, &mut warnings);
+    let res = parse_asm_with_info(&contents, asm_path, &mut warnings);
     for w in warnings {
         println!("{}", w);
     }
-    let instrs = match res {
+    let (instrs, info) = match res {
         Err(msgs) => {
             println!("{}", msgs);
             process::exit(1);
@@ -156,33 +704,179 @@ fn assemble_file(asm_path: &str, output_file: &str) {
         panic!("Couldn't write to output file: {}",
         why.to_string())
     }
+
+    if let Some(path) = symbols_path {
+        if let Err(why) = File::create(&path).and_then(|mut f| {
+            f.write_all(info.symbol_map().as_bytes())
+        }) {
+            println!("Couldn't write symbol map \"{}\": {}", path, why);
+            process::exit(1);
+        }
+    }
 }
 
-fn interpret_file(scaling_factor: i32, rom_path: &str, sound: bool) {
-    let (mut canvas, mut event_pump, audio_controller) = sdl_init(scaling_factor);
-    let mut c8 = make_system(&read_rom(rom_path));
+fn run_headless_file(rom_path: &str, sys_options: &SystemOptions,
+                     limits: &RunLimits, output_file: Option<String>) {
+    let mut c8 = sys_options.make_system(rom_path);
+
+    let mut player = sys_options.movie.clone().map(MoviePlayer::new);
+    let result = run_headless(&mut c8, limits, player.as_mut());
+    let reason = match result {
+        Ok(reason) => reason.describe().to_string(),
+        Err(e) => format!("error: {}", e),
+    };
+    let report = format!("Stopped: {}\nFrames: {}\n{}{}\n",
+                         reason, c8.get_frame_count(),
+                         c8.dump_to_str(), c8.screen_to_str());
 
-    'running: loop {
-        if process_events(&mut event_pump) {
-            break 'running
+    match output_file {
+        None => print!("{}", report),
+        Some(path) => {
+            if let Err(why) = File::create(&path).and_then(|mut f| f.write_all(report.as_bytes())) {
+                println!("Couldn't write to output file \"{}\": {}", path, why);
+                process::exit(1);
+            }
         }
- 
-        let instr = c8.fetch_and_decode();
-        let flags = instr.get_flags();
-        match flags {
-            system::InstrFlags::Keys => c8.update_keys(read_keys(&event_pump)),
-            system::InstrFlags::WaitKey => {
-                c8.pressed_key = wait_on_key(&mut event_pump);
-                if c8.pressed_key == 16 {
-                    break 'running
+    }
+    sys_options.finish_run(&mut c8);
+
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn exit_with_error(c8: &Chip8System, error: EmulatorError) -> ! {
+    println!("Error: {}", error);
+    print!("{}", c8.dump_to_str());
+    process::exit(1);
+}
+
+#[cfg(not(feature = "sdl"))]
+fn interpret_file(_scaling_factor: i32, _rom_path: &str, _sound: bool,
+                  _sys_options: &SystemOptions, _record_path: Option<String>) {
+    println!("rchip8 was built without SDL support, only --headless can run programs.");
+    process::exit(1);
+}
+
+#[cfg(feature = "sdl")]
+fn interpret_file(scaling_factor: i32, rom_path: &str, sound: bool,
+                  sys_options: &SystemOptions, record_path: Option<String>) {
+    let (mut canvas, mut event_pump, mut audio_controller) = sdl_init(scaling_factor);
+    let mut c8 = sys_options.make_system(rom_path);
+    let rom_info = sys_options.rom_db.lookup(&read_rom(rom_path));
+    if let Some(info) = rom_info {
+        let settings = sys_options.rom_db_settings(info);
+        if !settings.is_empty() {
+            println!("Using ROM database {} for \"{}\"", settings.join(", "), info.title);
+        }
+    }
+    let key_map = match make_key_map(rom_info.map_or(&[], |info| &info.keys)) {
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        }
+        Ok(key_map) => key_map,
+    };
+    let mut recording = record_path.as_ref().map(|_| Movie::new(&c8));
+    let mut playing = sys_options.movie.clone().map(MoviePlayer::new);
+    // Going back in time would leave the movie out of step with the system
+    let movie_active = recording.is_some() || playing.is_some();
+
+    // Emulated time is paced to the host's clock one frame at a time
+    let frame_duration = Duration::from_secs(1) / FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now() + frame_duration;
+    let mut frame = c8.get_frame_count();
+    let mut rewind = RewindBuffer::new(REWIND_CAPACITY);
+    rewind.push(c8.save_state());
+    let mut keys_frame: Option<u64> = None;
+
+    let error = 'running: loop {
+        for event in process_events(&mut event_pump) {
+            match event {
+                UserEvent::Quit => break 'running None,
+                UserEvent::SaveState(slot) => save_state_slot(&c8, rom_path, slot),
+                UserEvent::LoadState(_) if movie_active => {
+                    println!("Save states can't be loaded while recording or playing a movie");
+                }
+                UserEvent::LoadState(slot) => {
+                    if load_state_slot(&mut c8, rom_path, slot) {
+                        // Nothing else would bring these up to date until
+                        // the program next changed them
+                        draw_screen(&mut canvas, &c8.screen,
+                                    c8.get_screen_width(), c8.get_screen_height());
+                        if sound {
+                            audio_controller.set_pattern(c8.get_audio_pattern(),
+                                                         c8.get_pitch());
+                        }
+                    }
                 }
             }
-            _ => {},
         }
 
-        c8.execute(&instr);
+        if !movie_active && rewind_held(&event_pump) {
+            // Step back one frame per host frame, until we run out of history
+            if let Some(state) = rewind.pop_before(&c8.save_state()) {
+                if let Err(msg) = c8.load_state(&state) {
+                    panic!("Couldn't restore rewind snapshot: {}", msg);
+                }
+                draw_screen(&mut canvas, &c8.screen,
+                            c8.get_screen_width(), c8.get_screen_height());
+                if sound {
+                    audio_controller.set_pattern(c8.get_audio_pattern(), c8.get_pitch());
+                }
+            }
+            if sound {
+                audio_controller.pause();
+            }
+
+            thread::sleep(frame_duration);
+            next_frame = Instant::now() + frame_duration;
+            frame = c8.get_frame_count();
+            continue 'running
+        }
+
+        // Keys are sampled once per frame, so a movie can repeat them
+        if keys_frame != Some(c8.get_frame_count()) {
+            keys_frame = Some(c8.get_frame_count());
+            let keys = match playing.as_ref().and_then(|p| p.keys_for_frame(c8.get_frame_count())) {
+                Some(keys) => keys,
+                None => read_keys(&event_pump, &key_map),
+            };
+            c8.update_keys(keys);
+            if let Some(ref mut movie) = recording {
+                movie.record_frame(&keys);
+            }
+        }
+
+        let instr = match c8.fetch_and_decode() {
+            Err(e) => break 'running Some(e),
+            Ok(instr) => instr,
+        };
+        let flags = instr.get_flags();
+        if flags == system::InstrFlags::WaitKey {
+            // Once the movie runs out, the keyboard takes over
+            let key = match playing.as_mut().and_then(|p| p.next_wait_key()) {
+                Some(key) => key as usize,
+                None => wait_on_key(&mut event_pump, &key_map),
+            };
+            if key == 16 {
+                break 'running None
+            }
+            c8.pressed_key = key;
+            if let Some(ref mut movie) = recording {
+                movie.record_wait_key(key as u8);
+            }
+        }
+
+        if let Err(e) = c8.execute(&instr) {
+            break 'running Some(e)
+        }
 
         if sound {
+            if flags == system::InstrFlags::Sound {
+                audio_controller.set_pattern(c8.get_audio_pattern(), c8.get_pitch());
+            }
             match c8.sound_timer {
                 0 => audio_controller.pause(),
                 _ => audio_controller.resume(),
@@ -190,7 +884,94 @@ fn interpret_file(scaling_factor: i32, rom_path: &str, sound: bool) {
         }
 
         if flags == system::InstrFlags::Screen {
-            draw_screen(scaling_factor, &mut canvas, &c8.screen);
+            draw_screen(&mut canvas, &c8.screen,
+                        c8.get_screen_width(), c8.get_screen_height());
+        }
+
+        if c8.has_exited() {
+            break 'running None
+        }
+
+        if c8.get_frame_count() != frame {
+            frame = c8.get_frame_count();
+            rewind.push(c8.save_state());
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+                next_frame += frame_duration;
+            } else {
+                // Running behind, don't try to catch up
+                next_frame = now + frame_duration;
+            }
+        }
+    };
+
+    // Save these even if there was an error, that's likely what they're for
+    sys_options.finish_run(&mut c8);
+    if let (Some(movie), Some(path)) = (recording, record_path) {
+        save_movie(&movie, &path);
+    }
+    if let Some(e) = error {
+        exit_with_error(&c8, e);
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn save_movie(movie: &Movie, path: &str) {
+    match File::create(path).and_then(|mut f| f.write_all(&movie.to_bytes())) {
+        Err(why) => println!("Couldn't save movie to \"{}\": {}", path, why),
+        Ok(_) => println!("Saved movie to \"{}\"", path),
+    }
+}
+
+fn load_movie(path: &str) -> Movie {
+    let mut data = vec![];
+    if let Err(why) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        println!("Couldn't read movie \"{}\": {}", path, why);
+        process::exit(1);
+    }
+    match Movie::from_bytes(&data) {
+        Err(msg) => {
+            println!("Couldn't load movie \"{}\": {}", path, msg);
+            process::exit(1);
+        }
+        Ok(movie) => movie,
+    }
+}
+
+// Save states live next to the ROM, e.g. "pong.ch8.state1"
+#[cfg(feature = "sdl")]
+fn state_slot_path(rom_path: &str, slot: usize) -> String {
+    format!("{}.state{}", rom_path, slot)
+}
+
+#[cfg(feature = "sdl")]
+fn save_state_slot(c8: &Chip8System, rom_path: &str, slot: usize) {
+    let path = state_slot_path(rom_path, slot);
+    match File::create(&path).and_then(|mut f| f.write_all(&c8.save_state())) {
+        Err(why) => println!("Couldn't save state to \"{}\": {}", path, why),
+        Ok(_) => println!("Saved state to \"{}\"", path),
+    }
+}
+
+// Returns true if the state was loaded
+#[cfg(feature = "sdl")]
+fn load_state_slot(c8: &mut Chip8System, rom_path: &str, slot: usize) -> bool {
+    let path = state_slot_path(rom_path, slot);
+    let mut data = vec![];
+    if let Err(why) = File::open(&path).and_then(|mut f| f.read_to_end(&mut data)) {
+        println!("Couldn't read state from \"{}\": {}", path, why);
+        return false;
+    }
+
+    match c8.load_state(&data) {
+        Err(msg) => {
+            println!("Couldn't load state from \"{}\": {}", path, msg);
+            false
+        }
+        Ok(_) => {
+            println!("Loaded state from \"{}\"", path);
+            true
         }
     }
 }
@@ -8,29 +8,48 @@ use sdl::sdl2::rect::Rect;
 use sdl::sdl2::render::WindowCanvas;
 use sdl::sdl2::EventPump;
 use sdl::sdl2::audio::{AudioCallback, AudioSpecDesired, AudioDevice};
-use system::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use system::{SCREEN_WIDTH, SCREEN_HEIGHT, AUDIO_PATTERN_SIZE};
 
-struct SquareWave {
+const AUDIO_FREQ: i32 = 44_100;
+
+struct Buzzer {
+    // XO-CHIP audio pattern, plain square wave if there isn't one
+    pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    pattern_phase_inc: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Buzzer {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 { self.volume } else { -self.volume };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+        match self.pattern {
+            None => {
+                // Generate a square wave
+                for x in out.iter_mut() {
+                    *x = if self.phase <= 0.5 { self.volume } else { -self.volume };
+                    self.phase = (self.phase + self.phase_inc) % 1.0;
+                }
+            }
+            Some(pattern) => {
+                // Play the pattern one bit at a time, looping
+                let num_bits = (AUDIO_PATTERN_SIZE*8) as f32;
+                for x in out.iter_mut() {
+                    let bit = self.phase as usize;
+                    let set = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                    *x = if set { self.volume } else { -self.volume };
+                    self.phase = (self.phase + self.pattern_phase_inc) % num_bits;
+                }
+            }
         }
     }
 }
 
 // So we don't have to leak the template type
 pub struct AudioController {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Buzzer>,
 }
 
 impl AudioController {
@@ -41,6 +60,17 @@ impl AudioController {
     pub fn pause(&self) {
         self.device.pause();
     }
+
+    pub fn set_pattern(&mut self, pattern: Option<[u8; AUDIO_PATTERN_SIZE]>, pitch: u8) {
+        let mut buzzer = self.device.lock();
+        if buzzer.pattern.is_none() {
+            buzzer.phase = 0.0;
+        }
+        buzzer.pattern = pattern;
+        // Pitch 64 is 4000 bits per second
+        let bits_per_sec = 4000.0 * 2_f32.powf((f32::from(pitch) - 64.0) / 48.0);
+        buzzer.pattern_phase_inc = bits_per_sec / (AUDIO_FREQ as f32);
+    }
 }
 
 pub fn sdl_init(pixel_size: i32) -> (WindowCanvas, EventPump, AudioController) {
@@ -61,13 +91,15 @@ pub fn sdl_init(pixel_size: i32) -> (WindowCanvas, EventPump, AudioController) {
     let audio_subsystem = sdl_context.audio().unwrap();
 
     let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
+        freq: Some(AUDIO_FREQ),
         channels: Some(1),  // mono
         samples: Some(128),
     };
 
     let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-        SquareWave {
+        Buzzer {
+            pattern: None,
+            pattern_phase_inc: 0.0,
             phase_inc: 440.0 / spec.freq as f32,
             phase: 0.0,
             volume: 0.25
@@ -85,11 +117,43 @@ static CHIP8_KEYS : [Scancode; 16] = [
     Scancode::Num4, Scancode::R,    Scancode::F,    Scancode::V,
 ];
 
-pub fn wait_on_key(event_pump: &mut EventPump) -> usize {
+// Host key for each chip8 key
+pub type KeyMap = [Scancode; 16];
+
+// The default layout with some keys moved, e.g. (5, "Up") for 5 on the up arrow
+pub fn make_key_map(keys: &[(u8, String)]) -> Result<KeyMap, String> {
+    let mut key_map = CHIP8_KEYS;
+    for &(key, ref name) in keys {
+        key_map[key as usize] = Scancode::from_name(name)
+            .ok_or_else(|| format!("Unknown key \"{}\" in key mapping", name))?;
+    }
+    Ok(key_map)
+}
+
+pub const NUM_STATE_SLOTS: usize = 4;
+
+// Things the user asked for outside of the emulated keypad
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UserEvent {
+    Quit,
+    // Slots are numbered from 1
+    SaveState(usize),
+    LoadState(usize),
+}
+
+// F1-F4 save to a slot, F5-F8 load from the same slots
+static SAVE_KEYS : [Keycode; NUM_STATE_SLOTS] = [
+    Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4,
+];
+static LOAD_KEYS : [Keycode; NUM_STATE_SLOTS] = [
+    Keycode::F5, Keycode::F6, Keycode::F7, Keycode::F8,
+];
+
+pub fn wait_on_key(event_pump: &mut EventPump, key_map: &KeyMap) -> usize {
     loop {
         {
             let key_state = event_pump.keyboard_state();
-            for (idx, scancode) in CHIP8_KEYS.iter().enumerate() {
+            for (idx, scancode) in key_map.iter().enumerate() {
                 if key_state.is_scancode_pressed(*scancode) {
                     return idx
                 }
@@ -98,43 +162,70 @@ pub fn wait_on_key(event_pump: &mut EventPump) -> usize {
         
         // Need this here so application still responds while waiting
         // This is ugly but will do for now, allow us to quit during key waits
-        if process_events(event_pump) {
+        if process_events(event_pump).contains(&UserEvent::Quit) {
             return 16; // aka non existent key
         }
     }
 }
 
-pub fn read_keys(event_pump: &EventPump) -> [bool; 16] {
+// Time runs backwards while this is held
+pub fn rewind_held(event_pump: &EventPump) -> bool {
+    event_pump.keyboard_state().is_scancode_pressed(Scancode::Backspace)
+}
+
+pub fn read_keys(event_pump: &EventPump, key_map: &KeyMap) -> [bool; 16] {
     let mut chip8_keystate: [bool; 16] = [false; 16];
     let key_state = event_pump.keyboard_state();
 
-    for (scancode, chip8key) in CHIP8_KEYS.iter().zip(chip8_keystate.iter_mut()) {
+    for (scancode, chip8key) in key_map.iter().zip(chip8_keystate.iter_mut()) {
         *chip8key = key_state.is_scancode_pressed(*scancode);
     }
 
     chip8_keystate
 }
 
-pub fn process_events(event_pump: &mut EventPump) -> bool{
+pub fn process_events(event_pump: &mut EventPump) -> Vec<UserEvent> {
+    let mut events = vec![];
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), ..} => {
-                return true
+                events.push(UserEvent::Quit);
+            }
+            Event::KeyDown { keycode: Some(key), repeat: false, ..} => {
+                if let Some(slot) = SAVE_KEYS.iter().position(|k| *k == key) {
+                    events.push(UserEvent::SaveState(slot+1));
+                } else if let Some(slot) = LOAD_KEYS.iter().position(|k| *k == key) {
+                    events.push(UserEvent::LoadState(slot+1));
+                }
             }
             _ => {},
         };
     }
 
-    false
+    events
 }
 
-pub fn draw_screen(pixel_size: i32, canvas: &mut WindowCanvas, screen: &[bool]) {
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
+// Colour for each combination of XO-CHIP planes
+static PALETTE : [(u8, u8, u8); 4] = [
+    (0, 0, 0), (0, 255, 0), (255, 128, 0), (255, 255, 255),
+];
+
+// The window is always sized for the low resolution screen, SDL scales
+// whatever resolution we're in to fit it.
+pub fn draw_screen(canvas: &mut WindowCanvas, screen: &[u8], width: usize, height: usize) {
+    if canvas.logical_size() != (width as u32, height as u32) {
+        if let Err(why) = canvas.set_logical_size(width as u32, height as u32) {
+            panic!("couldn't set screen size!: {}", why);
+        }
+    }
+
+    let (r, g, b) = PALETTE[0];
+    canvas.set_draw_color(Color::RGB(r, g, b));
     canvas.clear();
 
     /*
     // Slow path with blur effect
-    let pixels = scale_pixels(apply_blur(screen_to_pixels(*screen)), pixel_size);
+    let pixels = scale_pixels(apply_blur(screen_to_pixels(screen, width)), pixel_size);
     
     for (y, row) in pixels.iter().enumerate() {
         for (x, pixel) in row.iter().enumerate() {
@@ -148,15 +239,14 @@ pub fn draw_screen(pixel_size: i32, canvas: &mut WindowCanvas, screen: &[bool])
     }
     */
     
-    canvas.set_draw_color(Color::RGB(0, 255, 0));
     for (idx, pixel) in screen.iter().enumerate() {
-        if *pixel {
-            let x = ((idx as i32) % (SCREEN_WIDTH as i32)) * pixel_size;
-            let y = ((idx as i32) / (SCREEN_WIDTH as i32)) * pixel_size;
+        if *pixel != 0 {
+            let (r, g, b) = PALETTE[(*pixel as usize) % PALETTE.len()];
+            canvas.set_draw_color(Color::RGB(r, g, b));
+            let x = (idx % width) as i32;
+            let y = (idx / width) as i32;
 
-            if let Err(why) = canvas.fill_rect(
-                Rect::new(x, y, pixel_size as u32,
-                pixel_size as u32)) {
+            if let Err(why) = canvas.fill_rect(Rect::new(x, y, 1, 1)) {
                 panic!("couldn't draw to screen!: {}", why);
             }
         }
@@ -166,15 +256,15 @@ pub fn draw_screen(pixel_size: i32, canvas: &mut WindowCanvas, screen: &[bool])
 }
 
 #[allow(dead_code)]
-fn screen_to_pixels(screen: [bool; SCREEN_WIDTH*SCREEN_HEIGHT]) -> Vec<Vec<u8>> {
+fn screen_to_pixels(screen: &[u8], width: usize) -> Vec<Vec<u8>> {
     let mut ret: Vec<Vec<u8>> = vec![];
     let mut row: Vec<u8> = vec![];
     for (idx, pixel) in screen.iter().enumerate() {
-        if (idx != 0) && ((idx % SCREEN_WIDTH) == 0) {
+        if (idx != 0) && ((idx % width) == 0) {
             ret.push(row.clone());
             row.clear();
         }
-        if *pixel {
+        if *pixel != 0 {
             row.push(255);
         } else {
             row.push(0);
@@ -0,0 +1,153 @@
+use system::{Chip8System, AccessKind, MEMORY_SIZE};
+use asm::DebugInfo;
+
+// How a byte of memory has been used so far
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ByteUsage {
+    // Fetched as part of an instruction
+    pub executed: bool,
+    // Through I, e.g. sprites, LD Vx, [I]
+    pub read: bool,
+    // Through I, e.g. LD [I], Vx or LD B, Vx
+    pub written: bool,
+}
+
+impl ByteUsage {
+    // e.g. "X-W" for executed and written
+    fn flags(self) -> String {
+        [(self.executed, 'X'), (self.read, 'R'), (self.written, 'W')].iter()
+            .map(|&(used, c)| if used { c } else { '-' }).collect()
+    }
+
+    fn is_used(self) -> bool {
+        self.executed || self.read || self.written
+    }
+}
+
+// Records how every byte is used, and reports on the ROM's bytes
+pub struct Coverage {
+    usage: Vec<ByteUsage>,
+    rom_start: u16,
+    rom_len: usize,
+}
+
+impl Coverage {
+    pub fn new(rom_start: u16, rom_len: usize) -> Coverage {
+        Coverage { usage: vec![ByteUsage::default(); MEMORY_SIZE], rom_start, rom_len }
+    }
+
+    pub fn usage(&self, addr: u16) -> ByteUsage {
+        self.usage[addr as usize]
+    }
+
+    pub fn record_executed(&mut self, addr: u16, size: u16) {
+        for offset in 0..size {
+            self.usage[addr.wrapping_add(offset) as usize].executed = true;
+        }
+    }
+
+    pub fn record_access(&mut self, addr: u16, len: u16, kind: AccessKind) {
+        for offset in 0..len {
+            let usage = &mut self.usage[addr.wrapping_add(offset) as usize];
+            match kind {
+                AccessKind::Read => usage.read = true,
+                AccessKind::Write => usage.written = true,
+            }
+        }
+    }
+
+    fn rom_addrs(&self) -> impl Iterator<Item=u16> {
+        let start = self.rom_start;
+        (0..self.rom_len).map(move |offset| start.wrapping_add(offset as u16))
+    }
+
+    // Runs of ROM addresses that were never used, inclusive
+    fn unused_ranges(&self) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for addr in self.rom_addrs().filter(|a| !self.usage(*a).is_used()) {
+            match ranges.last_mut() {
+                Some(range) if range.1.wrapping_add(1) == addr => range.1 = addr,
+                _ => ranges.push((addr, addr)),
+            }
+        }
+        ranges
+    }
+
+    pub fn summary(&self) -> String {
+        let count = |f: &dyn Fn(ByteUsage) -> bool| {
+            self.rom_addrs().filter(|a| f(self.usage(*a))).count()
+        };
+        let percent = |n: usize| match self.rom_len {
+            0 => 0.0,
+            len => (n as f64) * 100.0 / (len as f64),
+        };
+        let executed = count(&|u| u.executed);
+        let data = count(&|u| !u.executed && (u.read || u.written));
+        let unused = count(&|u| !u.is_used());
+
+        let mut lines = vec![
+            format!("Coverage of {} ROM bytes at 0x{:04x}", self.rom_len, self.rom_start),
+            format!("  Code:   {:6} bytes {:5.1}%", executed, percent(executed)),
+            format!("  Data:   {:6} bytes {:5.1}%", data, percent(data)),
+            format!("  Unused: {:6} bytes {:5.1}%", unused, percent(unused)),
+        ];
+        let ranges = self.unused_ranges();
+        if !ranges.is_empty() {
+            let ranges: Vec<String> = ranges.iter().map(|&(start, end)| match end - start {
+                0 => format!("0x{:04x}", start),
+                _ => format!("0x{:04x}-0x{:04x}", start, end),
+            }).collect();
+            lines.push(format!("Never used: {}", ranges.join(", ")));
+        }
+        lines.join("\n")
+    }
+
+    // The ROM, disassembling what was executed and showing the rest as
+    // bytes, up to 8 per line. Each line starts with its usage flags.
+    pub fn listing(&self, c8: &Chip8System) -> String {
+        let mut lines = vec![];
+        let end = u32::from(self.rom_start) + (self.rom_len as u32);
+        let mut addr = u32::from(self.rom_start);
+        while addr < end {
+            let usage = self.usage(addr as u16);
+            if usage.executed {
+                if let Ok(instr) = c8.decode_at(addr as u16) {
+                    let size = u32::from(instr.size()).min(end - addr);
+                    let bytes: String = c8.read_memory(addr as u16, size as usize).iter()
+                        .map(|b| format!("{:02X}", b)).collect();
+                    lines.push(format!("{}  0x{:04x}  {:<8}  {}",
+                                       usage.flags(), addr, bytes, c8.repr_instr(instr)));
+                    addr += size;
+                    continue
+                }
+            }
+
+            let mut len = 1;
+            while (len < 8) && (addr + len < end) && (self.usage((addr + len) as u16) == usage) {
+                len += 1;
+            }
+            let bytes: Vec<String> = c8.read_memory(addr as u16, len as usize).iter()
+                .map(|b| format!("{:02X}", b)).collect();
+            lines.push(format!("{}  0x{:04x}  {}", usage.flags(), addr, bytes.join(" ")));
+            addr += len;
+        }
+        lines.join("\n")
+    }
+
+    // The assembly source, with the usage flags of each line that has an instruction
+    pub fn source_listing(&self, source: &str, info: &DebugInfo) -> String {
+        let mut line_addrs: Vec<Option<u16>> = vec![None; source.lines().count()];
+        for (addr, line) in &info.lines {
+            if let Some(slot) = line_addrs.get_mut(line - 1) {
+                *slot = Some(*addr);
+            }
+        }
+
+        source.lines().zip(line_addrs).map(|(text, addr)| {
+            match addr {
+                None => format!("             | {}", text),
+                Some(addr) => format!("{}  0x{:04x} | {}", self.usage(addr).flags(), addr, text),
+            }
+        }).collect::<Vec<String>>().join("\n")
+    }
+}
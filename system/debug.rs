@@ -0,0 +1,372 @@
+use std::collections::BTreeSet;
+use system::{Chip8System, EmulatorError, InstrFlags, MemoryAccess, AccessKind,
+             MEMORY_SIZE, STACK_SIZE};
+use system::instr::InstrKind;
+
+// Everything a debugger front end can look at, whichever protocol it speaks
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Register {
+    V(u8),
+    I,
+    PC,
+    // The number of return addresses on the stack
+    SP,
+    DT,
+    ST,
+}
+
+pub const ALL_REGISTERS: [Register; 21] = [
+    Register::V(0),  Register::V(1),  Register::V(2),  Register::V(3),
+    Register::V(4),  Register::V(5),  Register::V(6),  Register::V(7),
+    Register::V(8),  Register::V(9),  Register::V(10), Register::V(11),
+    Register::V(12), Register::V(13), Register::V(14), Register::V(15),
+    Register::I, Register::PC, Register::SP, Register::DT, Register::ST,
+];
+
+impl Register {
+    // Any case, V registers in decimal or hex like the assembler
+    pub fn from_name(name: &str) -> Option<Register> {
+        let upper = name.to_uppercase();
+        match upper.as_str() {
+            "I"  => Some(Register::I),
+            "PC" => Some(Register::PC),
+            "SP" => Some(Register::SP),
+            "DT" => Some(Register::DT),
+            "ST" => Some(Register::ST),
+            _ => {
+                if !upper.starts_with('V') {
+                    return None
+                }
+                let num = &upper[1..];
+                let idx = match num.parse::<u8>() {
+                    Ok(v) => v,
+                    Err(_) => u8::from_str_radix(num, 16).ok()?,
+                };
+                if idx > 0xF { None } else { Some(Register::V(idx)) }
+            }
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match *self {
+            Register::V(idx) => format!("V{}", idx),
+            Register::I  => "I".to_string(),
+            Register::PC => "PC".to_string(),
+            Register::SP => "SP".to_string(),
+            Register::DT => "DT".to_string(),
+            Register::ST => "ST".to_string(),
+        }
+    }
+
+    // In bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            Register::I | Register::PC => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Chip8System {
+    pub fn read_register(&self, reg: Register) -> u16 {
+        match reg {
+            Register::V(idx) => u16::from(self.v_regs[idx as usize]),
+            Register::I  => self.i_reg,
+            Register::PC => self.pc,
+            Register::SP => self.stack.len() as u16,
+            Register::DT => u16::from(self.delay_timer),
+            Register::ST => u16::from(self.sound_timer),
+        }
+    }
+
+    // Moving SP up pushes 0s onto the stack
+    pub fn write_register(&mut self, reg: Register, value: u16) -> Result<(), String> {
+        let max = match reg {
+            Register::I | Register::PC => 0xFFFF,
+            Register::SP => STACK_SIZE as u16,
+            _ => 0xFF,
+        };
+        if value > max {
+            return Err(format!("Value 0x{:x} is too large for {}, maximum is 0x{:x}",
+                               value, reg.name(), max));
+        }
+
+        match reg {
+            Register::V(idx) => self.v_regs[idx as usize] = value as u8,
+            Register::I  => self.i_reg = value,
+            Register::PC => self.pc = value,
+            Register::SP => self.stack.resize(value as usize, 0),
+            Register::DT => self.delay_timer = value as u8,
+            Register::ST => self.sound_timer = value as u8,
+        }
+        Ok(())
+    }
+
+    // Stops at the end of memory, so may return less than asked for
+    pub fn read_memory(&self, addr: u16, len: usize) -> Vec<u8> {
+        let start = addr as usize;
+        let end = MEMORY_SIZE.min(start + len);
+        self.memory[start..end].to_vec()
+    }
+
+    pub fn write_memory(&mut self, addr: u16, data: &[u8]) -> Result<(), String> {
+        let start = addr as usize;
+        if (start + data.len()) > MEMORY_SIZE {
+            return Err(format!("Writing {} bytes at 0x{:04x} would go past the end of memory",
+                               data.len(), addr));
+        }
+        self.memory[start..start+data.len()].clone_from_slice(data);
+        Ok(())
+    }
+
+    // Return addresses, oldest first
+    pub fn get_stack(&self) -> &[u16] {
+        &self.stack
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    // Either of the above
+    Access,
+}
+
+// Stops the program after an instruction uses any of len bytes from addr
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn is_hit_by(&self, access: &MemoryAccess) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Write => access.kind == AccessKind::Write,
+            WatchKind::Read => access.kind == AccessKind::Read,
+            WatchKind::Access => true,
+        };
+        let (start, end) = (u32::from(self.addr), u32::from(self.addr) + u32::from(self.len));
+        let (access_start, access_end) = (u32::from(access.addr),
+                                          u32::from(access.addr) + u32::from(access.len));
+        kind_matches && (access_start < end) && (start < access_end)
+    }
+
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            WatchKind::Write => "write",
+            WatchKind::Read => "read",
+            WatchKind::Access => "access",
+        };
+        format!("{} watchpoint at 0x{:04x} length {}", kind, self.addr, self.len)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugStop {
+    // Finished what was asked, e.g. a single step
+    Done,
+    Breakpoint,
+    Watchpoint(Watchpoint),
+    // No keys are pressed for a key wait to use
+    WaitKey,
+    SelfJump,
+    Exited,
+    // The front end asked us to stop
+    Interrupted,
+    Error(EmulatorError),
+}
+
+impl DebugStop {
+    pub fn describe(&self) -> String {
+        match *self {
+            DebugStop::Done => "stopped".to_string(),
+            DebugStop::Breakpoint => "breakpoint".to_string(),
+            DebugStop::Watchpoint(wp) => wp.describe(),
+            DebugStop::WaitKey => "waiting for a key press".to_string(),
+            DebugStop::SelfJump => "jump to self".to_string(),
+            DebugStop::Exited => "program exited".to_string(),
+            DebugStop::Interrupted => "interrupted".to_string(),
+            DebugStop::Error(e) => format!("error: {}", e),
+        }
+    }
+}
+
+// How often a running program checks whether it has been interrupted
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+// The run control shared by the debugger front ends
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { breakpoints: BTreeSet::new(), watchpoints: vec![] }
+    }
+
+    // Returns false if there was already one there
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    // Returns false if there wasn't one there
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // In address order
+    pub fn get_breakpoints(&self) -> Vec<u16> {
+        self.breakpoints.iter().cloned().collect()
+    }
+
+    // Returns false if there was already the same one
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        if self.watchpoints.contains(&watchpoint) {
+            return false
+        }
+        self.watchpoints.push(watchpoint);
+        true
+    }
+
+    // Returns false if there wasn't one like it
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|wp| *wp != watchpoint);
+        self.watchpoints.len() != before
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // In the order they were added
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Run one instruction. A key wait uses the lowest numbered key that
+    // is pressed, if there is one, since nothing can be pressed meanwhile.
+    pub fn step(&self, c8: &mut Chip8System) -> DebugStop {
+        let addr = c8.pc;
+        if let Ok(instr) = c8.decode_at(addr) {
+            if instr.get_flags() == InstrFlags::WaitKey {
+                match c8.keys.iter().position(|k| *k) {
+                    None => return DebugStop::WaitKey,
+                    Some(key) => c8.pressed_key = key,
+                }
+            }
+        }
+
+        // Watchpoints are checked after the instruction has run,
+        // so only log accesses when we need them.
+        c8.set_access_log(!self.watchpoints.is_empty());
+
+        // On an error the PC is left at the instruction that
+        // caused it, so that you can see (and fix) what went wrong
+        let instr = match c8.fetch_and_decode() {
+            Err(e) => {
+                c8.pc = addr;
+                return DebugStop::Error(e)
+            }
+            Ok(instr) => instr,
+        };
+        if let Err(e) = c8.execute(&instr) {
+            c8.pc = addr;
+            return DebugStop::Error(e)
+        }
+
+        for access in c8.take_accesses() {
+            if let Some(wp) = self.watchpoints.iter().find(|wp| wp.is_hit_by(&access)) {
+                return DebugStop::Watchpoint(*wp)
+            }
+        }
+
+        if c8.has_exited() {
+            DebugStop::Exited
+        } else if (addr < 0x1000) && (instr.opcode == (0x1000 | addr)) {
+            DebugStop::SelfJump
+        } else {
+            DebugStop::Done
+        }
+    }
+
+    // Step count times, stopping early at breakpoints on the way
+    pub fn step_n(&self, c8: &mut Chip8System, count: u16) -> DebugStop {
+        for i in 0..count {
+            let stop = self.step(c8);
+            if stop != DebugStop::Done {
+                return stop
+            }
+            if (i+1 < count) && self.breakpoints.contains(&c8.pc) {
+                return DebugStop::Breakpoint
+            }
+        }
+        DebugStop::Done
+    }
+
+    // Step, but run a whole subroutine if the instruction is a call
+    pub fn next(&self, c8: &mut Chip8System,
+                interrupted: &mut dyn FnMut() -> bool) -> DebugStop {
+        match c8.decode_at(c8.pc) {
+            Ok(ref instr) if instr.kind == InstrKind::Call => {
+                let return_to = (c8.pc.wrapping_add(instr.size()), c8.stack.len());
+                self.run(c8, Some(return_to), interrupted)
+            }
+            _ => self.step(c8),
+        }
+    }
+
+    // Run until the current subroutine returns, or step if there isn't one
+    pub fn finish(&self, c8: &mut Chip8System,
+                  interrupted: &mut dyn FnMut() -> bool) -> DebugStop {
+        match c8.stack.last() {
+            None => self.step(c8),
+            Some(&return_to) => {
+                let depth = c8.stack.len() - 1;
+                self.run(c8, Some((return_to, depth)), interrupted)
+            }
+        }
+    }
+
+    // Run until a breakpoint or anything else that stops the program.
+    // A breakpoint at the current PC is stepped over.
+    pub fn cont(&self, c8: &mut Chip8System,
+                interrupted: &mut dyn FnMut() -> bool) -> DebugStop {
+        self.run(c8, None, interrupted)
+    }
+
+    // until is a PC and stack depth to stop at
+    fn run(&self, c8: &mut Chip8System, until: Option<(u16, usize)>,
+           interrupted: &mut dyn FnMut() -> bool) -> DebugStop {
+        let mut count: u32 = 0;
+        loop {
+            let stop = self.step(c8);
+            if stop != DebugStop::Done {
+                return stop
+            }
+            if until == Some((c8.pc, c8.stack.len())) {
+                return DebugStop::Done
+            }
+            if self.breakpoints.contains(&c8.pc) {
+                return DebugStop::Breakpoint
+            }
+
+            count += 1;
+            if count == INTERRUPT_CHECK_INTERVAL {
+                count = 0;
+                if interrupted() {
+                    return DebugStop::Interrupted
+                }
+            }
+        }
+    }
+}
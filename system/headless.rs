@@ -0,0 +1,100 @@
+use system::{Chip8System, InstrFlags, EmulatorError};
+use system::movie::MoviePlayer;
+
+// When to stop a run without a frontend. Each limit is optional
+// but with none of them set you may be waiting a while.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct RunLimits {
+    pub max_instrs: Option<u64>,
+    pub max_frames: Option<u64>,
+    pub until_pc: Option<u16>,
+    pub until_self_jump: bool,
+}
+
+impl RunLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_instrs.is_none() && self.max_frames.is_none() &&
+            self.until_pc.is_none() && !self.until_self_jump
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopReason {
+    MaxInstrs,
+    MaxFrames,
+    ReachedPc,
+    SelfJump,
+    // There's nobody to press a key
+    WaitKey,
+    Exited,
+    MovieEnded,
+}
+
+impl StopReason {
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            StopReason::MaxInstrs => "instruction limit reached",
+            StopReason::MaxFrames => "frame limit reached",
+            StopReason::ReachedPc => "reached target PC",
+            StopReason::SelfJump  => "jump to self",
+            StopReason::WaitKey   => "waiting for a key press",
+            StopReason::Exited    => "program exited",
+            StopReason::MovieEnded => "end of movie",
+        }
+    }
+}
+
+// Run until one of the limits is hit, or the movie runs out.
+// Without a movie all keys are released.
+// A self jump is executed before stopping, a key wait is not.
+pub fn run_headless(c8: &mut Chip8System, limits: &RunLimits,
+                    mut movie: Option<&mut MoviePlayer>)
+                    -> Result<StopReason, EmulatorError> {
+    let start_frame = c8.get_frame_count();
+    let mut instrs: u64 = 0;
+
+    loop {
+        if let Some(max) = limits.max_instrs {
+            if instrs >= max {
+                return Ok(StopReason::MaxInstrs)
+            }
+        }
+        if let Some(max) = limits.max_frames {
+            if (c8.get_frame_count() - start_frame) >= max {
+                return Ok(StopReason::MaxFrames)
+            }
+        }
+        if limits.until_pc == Some(c8.pc) {
+            return Ok(StopReason::ReachedPc)
+        }
+        if let Some(ref player) = movie {
+            match player.keys_for_frame(c8.get_frame_count()) {
+                None => return Ok(StopReason::MovieEnded),
+                Some(keys) => c8.update_keys(keys),
+            }
+        }
+
+        let addr = c8.pc;
+        let instr = c8.fetch_and_decode()?;
+        if instr.get_flags() == InstrFlags::WaitKey {
+            match movie.as_mut().and_then(|p| p.next_wait_key()) {
+                None => {
+                    c8.pc = addr;
+                    return Ok(StopReason::WaitKey)
+                }
+                Some(key) => c8.pressed_key = key as usize,
+            }
+        }
+
+        c8.execute(&instr)?;
+        instrs += 1;
+
+        if c8.has_exited() {
+            return Ok(StopReason::Exited)
+        }
+        if limits.until_self_jump && (addr < 0x1000) &&
+           (instr.opcode == (0x1000 | addr)) {
+            return Ok(StopReason::SelfJump)
+        }
+    }
+}
@@ -1,8 +1,7 @@
 use system::Chip8System;
-use system::InstrFlags;
-
-extern crate rand;
-use system::instr::rand::Rng;
+use system::{InstrFlags, EmulatorError, AccessKind};
+use system::{FONT_ADDR, BIG_FONT_ADDR, NUM_PLANES, AUDIO_PATTERN_SIZE, STACK_SIZE};
+use std::fmt;
 
 mod instr_builder {
     fn check_v_reg(num: u8, name: &str) {
@@ -43,6 +42,11 @@ mod instr_builder {
             (u16::from(n) & 0xF)
     }
 
+    pub fn arg_n(base: u16, n: u8) -> u16 {
+        if n >= 16 { panic!("n cannot be >= 16 !"); }
+        (base & 0xFFF0) | (u16::from(n) & 0xF)
+    }
+
     pub fn arg_x(base: u16, x: u8) -> u16 {
         check_v_reg(x, "X");
         (base & 0xF0FF) | ((u16::from(x) & 0xF) << 8)
@@ -65,6 +69,10 @@ fn op_to_vy(opcode: u16) -> u8 {
     ((opcode >> 4) & 0xF) as u8
 }
 
+fn op_to_n(opcode: u16) -> u8 {
+    (opcode & 0xF) as u8
+}
+
 pub trait Instr {
     fn repr(&self) -> String {
         let mut ret = self.get_mnemonic().to_string();
@@ -75,7 +83,18 @@ pub trait Instr {
         ret
     }
 
-    fn exec(&self, c8: &mut Chip8System);
+    // Size in bytes once assembled
+    fn get_size(&self) -> u16 {
+        2
+    }
+
+    fn get_bytes(&self) -> Vec<u8> {
+        let opc = self.get_opcode();
+        vec![(opc >> 8) as u8, opc as u8]
+    }
+
+    // The system runs Instructions, these are for everything else
+    fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError>;
     fn get_mnemonic(&self) -> &String;
     fn get_formatted_args(&self) -> String;
     fn get_opcode(&self) -> u16;
@@ -157,6 +176,18 @@ macro_rules! instr_symbol {
                 i.nnn = AddressOrSymbol::Symbol(sym);
                 i
             }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(op_to_nnn(opc), c8)
+            }
             
             fn get_addr(&self) -> u16 {
                 match self.nnn {
@@ -197,8 +228,8 @@ macro_rules! instr_symbol {
                 }
             }
 
-            fn exec(&self, c8: &mut Chip8System) {
-                $exec(self.get_addr(), c8);
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.get_opcode(), 0)
             }
         }
     )
@@ -221,6 +252,18 @@ macro_rules! instr_no_args {
             pub fn create() -> $instr_name {
                 $instr_name::new(instr_builder::no_args($base))
             }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, _opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(c8)
+            }
         }
 
         impl Instr for $instr_name {
@@ -230,8 +273,8 @@ macro_rules! instr_no_args {
                 "".to_string()
             }
 
-            fn exec(&self, c8: &mut Chip8System) {
-                $exec(c8);
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.core.opcode, 0)
             }
         }
     )
@@ -258,6 +301,18 @@ macro_rules! instr_x_kk {
             pub fn create(x: u8, kk: u8) -> $instr_name {
                 $instr_name::new(instr_builder::arg_x_kk($base, x, kk))
             }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(c8, op_to_vx(opc), op_to_kk(opc))
+            }
         }
 
         impl Instr for $instr_name {
@@ -267,16 +322,24 @@ macro_rules! instr_x_kk {
                 format!("V{}, 0x{:02X}", self.vx, self.kk)
             }
 
-            fn exec(&self, c8: &mut Chip8System) {
-                $exec(c8, self.vx, self.kk);
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.core.opcode, 0)
             }
         }
     )
 }
 
+fn make_format_x_y() -> impl Fn(u8, u8) -> String {
+    | vx, vy | { format!("V{}, V{}", vx, vy) }
+}
+
 macro_rules! instr_x_y {
     ( $instr_name:ident, $mnemonic:expr, $flags:path,
       $base:expr, $exec:expr ) => (
+        instr_x_y!($instr_name, $mnemonic, $flags, $base, $exec, make_format_x_y());
+    );
+    ( $instr_name:ident, $mnemonic:expr, $flags:path,
+      $base:expr, $exec:expr, $formatter:expr ) => (
         pub struct $instr_name {
             core: InstrCore,
             vx: u8,
@@ -295,17 +358,29 @@ macro_rules! instr_x_y {
             pub fn create(x: u8, y: u8) -> $instr_name {
                 $instr_name::new(instr_builder::arg_x_y($base, x, y))
             }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(c8, op_to_vx(opc), op_to_vy(opc))
+            }
         }
 
         impl Instr for $instr_name {
             impl_instr!();
 
             fn get_formatted_args(&self) -> String {
-                format!("V{}, V{}", self.vx, self.vy)
+                $formatter(self.vx, self.vy)
             }
 
-            fn exec(&self, c8: &mut Chip8System) {
-                $exec(c8, self.vx, self.vy);
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.core.opcode, 0)
             }
         }
     )
@@ -334,6 +409,18 @@ macro_rules! instr_x {
             pub fn create(x: u8) -> $instr_name {
                 $instr_name::new(instr_builder::arg_x($base, x))
             }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(c8, op_to_vx(opc))
+            }
         }
 
         impl Instr for $instr_name {
@@ -344,38 +431,101 @@ macro_rules! instr_x {
             }
 
 
-            fn exec(&self, c8: &mut Chip8System) {
-                $exec(c8, self.vx);
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.core.opcode, 0)
+            }
+        }
+    )
+}
+
+macro_rules! instr_n {
+    ( $instr_name:ident, $mnemonic:expr, $flags:path,
+      $base:expr, $exec:expr ) => (
+        pub struct $instr_name {
+            core: InstrCore,
+            n: u8,
+        }
+
+        impl $instr_name {
+            pub fn new(opc: u16) -> $instr_name {
+                $instr_name {
+                    core: InstrCore::new(opc, $flags, $mnemonic),
+                    n: op_to_n(opc),
+                }
+            }
+
+            pub fn create(n: u8) -> $instr_name {
+                $instr_name::new(instr_builder::arg_n($base, n))
+            }
+
+            pub const FLAGS: InstrFlags = $flags;
+
+            pub fn from_parts(opc: u16, _operand: u16) -> $instr_name {
+                $instr_name::new(opc)
+            }
+
+            // Run straight from the encoding, without building the instruction
+            pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                               -> Result<(), EmulatorError> {
+                $exec(c8, op_to_n(opc))
+            }
+        }
+
+        impl Instr for $instr_name {
+            impl_instr!();
+
+            fn get_formatted_args(&self) -> String {
+                format!("{}", self.n)
+            }
+
+            fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+                $instr_name::exec_opcode(c8, self.core.opcode, 0)
             }
         }
     )
 }
 
-#[allow(dead_code)]
+// An opcode that isn't an instruction. Shown as something
+// the assembler turns back into the same opcode.
 pub struct UndefInstr {
     core: InstrCore,
-    message: String,
 }
 
-#[allow(dead_code)]
 impl UndefInstr {
-    pub fn new(opc: u16, msg: String) -> UndefInstr {
+    pub fn new(opc: u16) -> UndefInstr {
+        let mnemonic = if opc == BRK_OPCODE { "BRK" } else { ".word" };
         UndefInstr {
-            core: InstrCore::new(opc, InstrFlags::_None, "UNDEF"),
-            message: msg,
+            core: InstrCore::new(opc, InstrFlags::_None, mnemonic),
         }
     }
+
+    pub const FLAGS: InstrFlags = InstrFlags::_None;
+
+    pub fn from_parts(opc: u16, _operand: u16) -> UndefInstr {
+        UndefInstr::new(opc)
+    }
+
+    // Fails the same way running the opcode normally would
+    pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                       -> Result<(), EmulatorError> {
+        let pc = c8.pc.wrapping_sub(2);
+        Err(decode(opc).err().unwrap_or(DecodeError::Unknown(opc)).at(pc))
+    }
 }
 
-#[allow(dead_code)]
 impl Instr for UndefInstr {
     impl_instr!();
 
     fn get_formatted_args(&self) -> String {
-        self.message.to_string()
+        match self.core.opcode {
+            BRK_OPCODE => String::new(),
+            opc => format!("0x{:04X}", opc),
+        }
     }
 
-    fn exec(&self, _c8: &mut Chip8System) {}
+    fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+        UndefInstr::exec_opcode(c8, self.core.opcode, 0)
+    }
 }
 
 pub struct WordInstr {
@@ -401,11 +551,48 @@ impl Instr for WordInstr {
         format!("0x{:04X}", self.core.opcode)
     }
 
-    fn exec(&self, _c8: &mut Chip8System) {
+    fn exec(&self, _c8: &mut Chip8System) -> Result<(), EmulatorError> {
         panic!("Cannot execute a .word pseudo instruction!")
     }
 }
 
+// Bytes from .byte, .text and the like, which can be any length
+pub struct DataInstr {
+    core: InstrCore,
+    bytes: Vec<u8>,
+}
+
+impl DataInstr {
+    pub fn create(bytes: Vec<u8>) -> DataInstr {
+        // Not run, but the opcode is whatever the first word would be
+        let first = |i: usize| u16::from(bytes.get(i).cloned().unwrap_or(0));
+        DataInstr {
+            core: InstrCore::new((first(0) << 8) | first(1), InstrFlags::_None, ".byte"),
+            bytes,
+        }
+    }
+}
+
+impl Instr for DataInstr {
+    impl_instr!();
+
+    fn get_size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    fn get_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    fn get_formatted_args(&self) -> String {
+        self.bytes.iter().map(|b| format!("0x{:02X}", b)).collect::<Vec<String>>().join(", ")
+    }
+
+    fn exec(&self, _c8: &mut Chip8System) -> Result<(), EmulatorError> {
+        panic!("Cannot execute a .byte pseudo instruction!")
+    }
+}
+
 pub struct DrawSpriteInstr {
     core: InstrCore,
     vx: u8,
@@ -419,13 +606,77 @@ impl DrawSpriteInstr {
             core: InstrCore::new(opc, InstrFlags::Screen, "DRW"),
             vx: op_to_vx(opc),
             vy: op_to_vy(opc),
-            n: (opc & 0xF) as u8,
+            n: op_to_n(opc),
         }
     }
 
     pub fn create(x: u8, y: u8, n: u8) -> DrawSpriteInstr {
         DrawSpriteInstr::new(instr_builder::arg_x_y_n(0xD000, x, y, n))
     }
+
+    pub const FLAGS: InstrFlags = InstrFlags::Screen;
+
+    pub fn from_parts(opc: u16, _operand: u16) -> DrawSpriteInstr {
+        DrawSpriteInstr::new(opc)
+    }
+
+    pub fn exec_opcode(c8: &mut Chip8System, opc: u16, _operand: u16)
+                       -> Result<(), EmulatorError> {
+        let (vx, vy, n) = (op_to_vx(opc), op_to_vy(opc), op_to_n(opc));
+
+        //Clear overlap flag
+        c8.v_regs[15] = 0;
+
+        // SUPER-CHIP uses n=0 for a 16x16 sprite, 2 bytes per row
+        let (width, height) = if n == 0 { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = width / 8;
+        let sprite_len = height*bytes_per_row;
+
+        // XO-CHIP draws to each selected plane in turn, with the data
+        // for each one following the last.
+        let plane_mask = c8.plane_mask;
+        let planes = (0..NUM_PLANES).map(|p| 1 << p).filter(move |p| plane_mask & p != 0);
+
+        let mut addr = c8.access_i((sprite_len*planes.clone().count()) as u8, AccessKind::Read)?;
+        let screen_width = c8.get_screen_width();
+        let screen_height = c8.get_screen_height();
+        // The starting position always wraps, the rest may be clipped
+        let x = (c8.v_regs[vx as usize] as usize) % screen_width;
+        let y = (c8.v_regs[vy as usize] as usize) % screen_height;
+        let clip = c8.quirks.clip_sprites;
+
+        for plane in planes {
+            for y_offset in 0..height {
+                let row_addr = addr + (y_offset*bytes_per_row);
+                let row = c8.memory[row_addr..row_addr+bytes_per_row].iter().fold(
+                                0_u16, |acc, b| (acc << 8) | u16::from(*b));
+
+                for sprite_x in (0..width).rev() {
+                    let unwrapped_x = x+width-1-sprite_x;
+                    let unwrapped_y = y+y_offset;
+                    if clip && ((unwrapped_x >= screen_width) || (unwrapped_y >= screen_height)) {
+                        continue;
+                    }
+
+                    let final_x = unwrapped_x % screen_width;
+                    let final_y = unwrapped_y % screen_height;
+                    let screen_idx = (final_y*screen_width)+final_x;
+
+                    let pixel_set = row & (1 << sprite_x) != 0;
+                    let pixel_was = c8.screen[screen_idx] & plane != 0;
+
+                    if pixel_set && pixel_was {
+                        c8.v_regs[15] = 1;
+                    }
+                    if pixel_set {
+                        c8.screen[screen_idx] ^= plane;
+                    }
+                }
+            }
+            addr += sprite_len;
+        }
+        Ok(())
+    }
 }
 
 impl Instr for DrawSpriteInstr {
@@ -435,103 +686,201 @@ impl Instr for DrawSpriteInstr {
         format!("V{}, V{}, {}", self.vx, self.vy, self.n)
     }
 
-    fn exec(&self, c8: &mut Chip8System) {
-        //Clear overlap flag
-        c8.v_regs[15] = 0;
+    fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+        DrawSpriteInstr::exec_opcode(c8, self.core.opcode, 0)
+    }
+}
 
-        let x = c8.v_regs[self.vx as usize] as usize;
-        let y = c8.v_regs[self.vy as usize] as usize;
-        let addr = c8.bounds_check_i(self.n);
-        let sprite_data = &c8.memory[addr..addr+(self.n as usize)];
-
-        for (y_offset, row) in sprite_data.iter().enumerate() {
-            for sprite_x in (0..8).rev() {
-                let final_x = (x+7-sprite_x) % 64;
-                let final_y = (y + y_offset) % 32;
-                let screen_idx = (final_y*64)+final_x;
-
-                let pixel_set = *row & (1 << sprite_x) != 0; 
-                let pixel_was = c8.screen[screen_idx];
-                 
-                if pixel_set && pixel_was {
-                    c8.v_regs[15] = 1;
-                }
-                c8.screen[screen_idx] ^= pixel_set;
-            }
+instr_n!(ScrollDownInstr, "SCD", InstrFlags::Screen, 0x00C0,
+| c8: &mut Chip8System, n | {
+    c8.scroll(0, n as isize);
+    Ok(())
+});
+
+instr_n!(ScrollUpInstr, "SCU", InstrFlags::Screen, 0x00D0,
+| c8: &mut Chip8System, n | {
+    c8.scroll(0, -(n as isize));
+    Ok(())
+});
+
+pub struct LongLoadIInstr {
+    core: InstrCore,
+    addr: AddressOrSymbol,
+}
+
+// XO-CHIP F000 NNNN, the only instruction that is 4 bytes long
+impl LongLoadIInstr {
+    pub fn new(opc: u16, addr: u16) -> LongLoadIInstr {
+        LongLoadIInstr {
+            core: InstrCore::new(opc, InstrFlags::_None, "LDL"),
+            addr: AddressOrSymbol::Address(addr),
+        }
+    }
+
+    pub fn create(addr: u16) -> LongLoadIInstr {
+        LongLoadIInstr::new(0xF000, addr)
+    }
+
+    pub fn create_with_symbol(sym: String) -> LongLoadIInstr {
+        let mut i = LongLoadIInstr::create(0);
+        i.addr = AddressOrSymbol::Symbol(sym);
+        i
+    }
+
+    pub const FLAGS: InstrFlags = InstrFlags::_None;
+
+    // The operand is the address word following the opcode
+    pub fn from_parts(opc: u16, operand: u16) -> LongLoadIInstr {
+        LongLoadIInstr::new(opc, operand)
+    }
+
+    pub fn exec_opcode(c8: &mut Chip8System, _opc: u16, operand: u16)
+                       -> Result<(), EmulatorError> {
+        c8.i_reg = operand;
+        // Step over the address
+        c8.pc = c8.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn get_addr(&self) -> u16 {
+        match self.addr {
+            AddressOrSymbol::Address(a) => a,
+            AddressOrSymbol::Symbol(ref s) => panic!("Cannot get address for unresolved symbol \"{}\"", s),
+        }
+    }
+}
+
+impl Instr for LongLoadIInstr {
+    impl_instr_base!();
+
+    fn get_size(&self) -> u16 {
+        4
+    }
+
+    fn get_bytes(&self) -> Vec<u8> {
+        let addr = self.get_addr();
+        vec![0xF0, 0x00, (addr >> 8) as u8, addr as u8]
+    }
+
+    fn get_formatted_args(&self) -> String {
+        match self.addr {
+            AddressOrSymbol::Address(a) => format!("I, 0x{:04X}", a),
+            AddressOrSymbol::Symbol(ref s) => format!("I, {}", s),
+        }
+    }
+
+    fn get_opcode(&self) -> u16 {
+        let _ = self.get_addr();
+        self.core.opcode
+    }
+
+    fn get_symbol(&self) -> Option<String> {
+        match self.addr {
+            AddressOrSymbol::Symbol(ref s) => Some(s.to_string()),
+            AddressOrSymbol::Address(_) => None,
         }
     }
+
+    fn resolve_symbol(&mut self, addr: u16) {
+        match self.addr {
+            AddressOrSymbol::Symbol(_) => self.addr = AddressOrSymbol::Address(addr),
+            AddressOrSymbol::Address(_) => panic!("Symbol already resolved for this instruction!"),
+        }
+    }
+
+    fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+        LongLoadIInstr::exec_opcode(c8, self.core.opcode, self.get_addr())
+    }
 }
 
 instr_symbol!(SysInstr, "SYS", InstrFlags::_None, 0x0000,
-| _addr, _c8 | {}, make_nnn_format());
+| _addr, _c8 | { Ok(()) }, make_nnn_format());
 
 instr_symbol!(CallInstr, "CALL", InstrFlags::_None, 0x2000,
 | addr, c8: &mut Chip8System | {
-    if c8.stack.len() == 16 {
-        panic!("Stack is full!")
+    if c8.stack.len() == STACK_SIZE {
+        return Err(EmulatorError::StackOverflow);
     }
 
     c8.stack.push(c8.pc);
     c8.pc = addr; 
+    Ok(())
 }, make_nnn_format());
 
 instr_symbol!(JumpInstr, "JP", InstrFlags::_None, 0x1000,
 | addr, c8: &mut Chip8System | {
     c8.pc = addr;
+    Ok(())
 }, make_nnn_format());
 
 instr_no_args!(RetInstr, "RET", InstrFlags::_None, 0x00EE,
 | c8: &mut Chip8System | {
-    if c8.stack.is_empty() {
-        panic!("Stack is empty!");
-    }
-    c8.pc = c8.stack.pop().unwrap();
+    c8.pc = match c8.stack.pop() {
+        None => return Err(EmulatorError::StackUnderflow),
+        Some(addr) => addr,
+    };
+    Ok(())
 });
 
 instr_x_kk!(SkipEqualInstr, "SE", InstrFlags::_None, 0x3000,
 | c8: &mut Chip8System, vx, kk | {
     if c8.v_regs[vx as usize] == kk {
-            c8.pc += 2;
+        c8.skip_next_instr();
     }
+    Ok(())
 });
 
 instr_x_kk!(SkipNotEqualInstr, "SNE", InstrFlags::_None, 0x4000,
 | c8: &mut Chip8System, vx, kk | {
     if c8.v_regs[vx as usize] != kk {
-            c8.pc +=2;
+        c8.skip_next_instr();
     }
+    Ok(())
 });
 
 instr_x_kk!(LoadByteInstr, "LD", InstrFlags::_None, 0x6000,
 | c8: &mut Chip8System, vx, kk | {
     c8.v_regs[vx as usize] = kk;
+    Ok(())
 });
 
 instr_no_args!(ClearDisplayInstr, "CLS", InstrFlags::Screen, 0x00E0,
 | c8: &mut Chip8System | {
-        for p in c8.screen.iter_mut() {
-            *p = false;
-        }
+    c8.clear_screen();
+    Ok(())
 });
 
 instr_x_y!(MovRegInstr, "LD", InstrFlags::_None, 0x8000,
 | c8: &mut Chip8System, vx, vy | {
     c8.v_regs[vx as usize] = c8.v_regs[vy as usize];
+    Ok(())
 });
 
 instr_x_y!(OrRegInstr, "OR", InstrFlags::_None, 0x8001,
 | c8: &mut Chip8System, vx, vy | {
     c8.v_regs[vx as usize] |= c8.v_regs[vy as usize];
+    if c8.quirks.logic_resets_vf {
+        c8.v_regs[15] = 0;
+    }
+    Ok(())
 });
 
 instr_x_y!(AndRegInstr, "AND", InstrFlags::_None, 0x8002,
 | c8: &mut Chip8System, vx, vy | {
     c8.v_regs[vx as usize] &= c8.v_regs[vy as usize];
+    if c8.quirks.logic_resets_vf {
+        c8.v_regs[15] = 0;
+    }
+    Ok(())
 });
 
 instr_x_y!(XORRegInstr, "XOR", InstrFlags::_None, 0x8003,
 | c8: &mut Chip8System, vx, vy | {
     c8.v_regs[vx as usize] ^= c8.v_regs[vy as usize];
+    if c8.quirks.logic_resets_vf {
+        c8.v_regs[15] = 0;
+    }
+    Ok(())
 });
 
 instr_x_y!(AddRegInstr, "ADD", InstrFlags::_None, 0x8004,
@@ -546,6 +895,7 @@ instr_x_y!(AddRegInstr, "ADD", InstrFlags::_None, 0x8004,
     } else {
         c8.v_regs[15] = 0;
     }
+    Ok(())
 });
 
 instr_x_y!(SubRegInstr, "SUB", InstrFlags::_None, 0x8005,
@@ -555,6 +905,7 @@ instr_x_y!(SubRegInstr, "SUB", InstrFlags::_None, 0x8005,
 
     c8.v_regs[vx as usize] = x.wrapping_sub(y);
     c8.v_regs[15] = (x>y) as u8;
+    Ok(())
 });
 
 instr_x_y!(SubNRegInstr, "SUBN", InstrFlags::_None, 0x8007,
@@ -564,25 +915,41 @@ instr_x_y!(SubNRegInstr, "SUBN", InstrFlags::_None, 0x8007,
 
     c8.v_regs[vx as usize] = y.wrapping_sub(x);
     c8.v_regs[15] = (y>x) as u8;
+    Ok(())
 });
 
-instr_x!(ShrRegInstr, "SHR", InstrFlags::_None, 0x8006,
-| c8: &mut Chip8System, vx | {
-    let x = c8.v_regs[vx as usize];
-    c8.v_regs[15] = x & 1;
-    c8.v_regs[vx as usize] >>= 1;
-}, make_format_x());
+// VY is only used with the shift quirk, so it's optional
+fn make_format_shift() -> impl Fn(u8, u8) -> String {
+    | vx, vy | {
+        match vy {
+            0 => format!("V{}", vx),
+            _ => format!("V{}, V{}", vx, vy),
+        }
+    }
+}
 
-instr_x!(ShlRegInstr, "SHL", InstrFlags::_None, 0x800E,
-| c8: &mut Chip8System, vx | {
-    let x = c8.v_regs[vx as usize];
-    c8.v_regs[15] = x >> 7;
-    c8.v_regs[vx as usize] <<= 1;
-}, make_format_x());
+instr_x_y!(ShrRegInstr, "SHR", InstrFlags::_None, 0x8006,
+| c8: &mut Chip8System, vx, vy | {
+    let src = if c8.quirks.shift_uses_vy { vy } else { vx };
+    let value = c8.v_regs[src as usize];
+    c8.v_regs[vx as usize] = value >> 1;
+    c8.v_regs[15] = value & 1;
+    Ok(())
+}, make_format_shift());
+
+instr_x_y!(ShlRegInstr, "SHL", InstrFlags::_None, 0x800E,
+| c8: &mut Chip8System, vx, vy | {
+    let src = if c8.quirks.shift_uses_vy { vy } else { vx };
+    let value = c8.v_regs[src as usize];
+    c8.v_regs[vx as usize] = value << 1;
+    c8.v_regs[15] = value >> 7;
+    Ok(())
+}, make_format_shift());
 
 instr_symbol!(LoadIInstr, "LD", InstrFlags::_None, 0xA000,
 | addr, c8: &mut Chip8System | {
     c8.i_reg = addr;
+    Ok(())
 },
 | nnn: &AddressOrSymbol | {
     match nnn {
@@ -593,88 +960,108 @@ instr_symbol!(LoadIInstr, "LD", InstrFlags::_None, 0xA000,
 
 instr_x_kk!(AddByteInstr, "ADD", InstrFlags::_None, 0x7000,
 | c8: &mut Chip8System, vx, kk | {
-    c8.v_regs[vx as usize] = c8.v_regs[vx as usize].wrapping_add(kk)
+    c8.v_regs[vx as usize] = c8.v_regs[vx as usize].wrapping_add(kk);
+    Ok(())
 });
 
 instr_x!(AddIVInstr, "ADD", InstrFlags::_None, 0xF01E,
 | c8: &mut Chip8System, vx | {
-    c8.i_reg = c8.i_reg.wrapping_add(u16::from(c8.v_regs[vx as usize]))
+    c8.i_reg = c8.i_reg.wrapping_add(u16::from(c8.v_regs[vx as usize]));
+    Ok(())
 },
 | vx | { format!("I, V{}", vx) });
 
 instr_x!(SetDelayTimerInstr, "LD", InstrFlags::_None, 0xF015,
 | c8: &mut Chip8System, vx | {
-    c8.delay_timer = c8.v_regs[vx as usize]
+    c8.delay_timer = c8.v_regs[vx as usize];
+    Ok(())
 }, 
 | vx | { format!("DT, V{}", vx) });
 
 instr_x!(GetDelayTimerInstr, "LD", InstrFlags::_None, 0xF007,
 | c8: &mut Chip8System, vx | {
-    c8.v_regs[vx as usize] = c8.delay_timer
+    c8.v_regs[vx as usize] = c8.delay_timer;
+    Ok(())
 },
 | vx | { format!("V{}, DT", vx) });
 
 instr_x!(SkipKeyIfPressedInstr, "SKP", InstrFlags::Keys, 0xE09E,
 | c8: &mut Chip8System, vx | {
-    if c8.get_keystate(c8.v_regs[vx as usize]) {
-        c8.pc += 2;
+    if c8.get_keystate(c8.v_regs[vx as usize])? {
+        c8.skip_next_instr();
     }
+    Ok(())
 }, make_format_x());
 
 instr_x!(SkipKeyIfNotPressedInstr, "SKNP", InstrFlags::Keys, 0xE0A1,
 | c8: &mut Chip8System, vx | {
-    if !c8.get_keystate(c8.v_regs[vx as usize]) {
-        c8.pc += 2;
+    if !c8.get_keystate(c8.v_regs[vx as usize])? {
+        c8.skip_next_instr();
     }
+    Ok(())
 }, make_format_x());
 
 instr_x!(ReadRegsFromMemInstr, "LD", InstrFlags::_None, 0xF065,
 | c8: &mut Chip8System, vx | {
-    let addr = c8.bounds_check_i(vx+1);
+    let addr = c8.access_i(vx+1, AccessKind::Read)?;
     for reg_idx in 0..=vx {
         c8.v_regs[reg_idx as usize] = c8.memory[addr+(reg_idx as usize)];
     }
+    if c8.quirks.load_store_increments_i {
+        c8.i_reg = c8.i_reg.wrapping_add(u16::from(vx)+1);
+    }
+    Ok(())
 }, 
 | vx | { format!("V{}, [I]", vx) });
 
 instr_x!(WriteRegsToMemInstr, "LD", InstrFlags::_None, 0xF055,
 | c8: &mut Chip8System, vx | {
-    let addr = c8.bounds_check_i(vx+1);
+    let addr = c8.access_i(vx+1, AccessKind::Write)?;
     for reg_idx in 0..=vx {
         c8.memory[addr+(reg_idx as usize)] = c8.v_regs[reg_idx as usize];
     }
+    if c8.quirks.load_store_increments_i {
+        c8.i_reg = c8.i_reg.wrapping_add(u16::from(vx)+1);
+    }
+    Ok(())
 },
 | vx | { format!("[I], V{}", vx) });
 
 instr_x!(SetSoundTimerInstr, "LD", InstrFlags::Sound, 0xF018,
 | c8: &mut Chip8System, vx | {
     c8.sound_timer = c8.v_regs[vx as usize];
+    Ok(())
 },
 | vx | { format!("ST, V{}", vx) });
 
 instr_x_kk!(RandomInstr, "RND", InstrFlags::_None, 0xC000,
 | c8: &mut Chip8System, vx, kk | {
-    let mut rng = rand::thread_rng();
-    c8.v_regs[vx as usize] = kk & rng.gen::<u8>();
+    c8.v_regs[vx as usize] = kk & c8.rng.next_u8();
+    Ok(())
 });
 
 instr_x_y!(SkipIfRegsEqualInstr, "SE", InstrFlags::_None, 0x5000,
 | c8: &mut Chip8System, vx, vy | {
     if c8.v_regs[vx as usize] == c8.v_regs[vy as usize] {
-        c8.pc += 2;
+        c8.skip_next_instr();
     }
+    Ok(())
 });
 
 instr_x_y!(SkipIfRegsNotEqualInstr, "SNE", InstrFlags::_None, 0x9000,
 | c8: &mut Chip8System, vx, vy | {
     if c8.v_regs[vx as usize] != c8.v_regs[vy as usize] {
-        c8.pc += 2;
+        c8.skip_next_instr();
     }
+    Ok(())
 });
 
 instr_symbol!(JumpPlusVZeroInstr, "JP", InstrFlags::_None, 0xB000,
 | addr, c8: &mut Chip8System | {
-    c8.pc = addr + u16::from(c8.v_regs[0]);
+    let addr: u16 = addr;
+    let reg = if c8.quirks.jump_uses_vx { ((addr >> 8) & 0xF) as usize } else { 0 };
+    c8.pc = addr + u16::from(c8.v_regs[reg]);
+    Ok(())
 },
 | nnn: &AddressOrSymbol | {
     match nnn {
@@ -686,14 +1073,15 @@ instr_symbol!(JumpPlusVZeroInstr, "JP", InstrFlags::_None, 0xB000,
 instr_x!(GetDigitAddrInstr, "LD", InstrFlags::_None, 0xF029,
 | c8: &mut Chip8System, vx | {
     let digit = u16::from(c8.v_regs[vx as usize]);
-    c8.i_reg = digit*5;
+    c8.i_reg = FONT_ADDR + (digit*5);
+    Ok(())
 },
 | vx | { format!("F, V{}", vx) });
 
 instr_x!(StoreBCDInstr, "LD", InstrFlags::_None, 0xF033,
 | c8: &mut Chip8System, vx | {
     let mut value = c8.v_regs[vx as usize];
-    let mut addr = c8.bounds_check_i(3);
+    let mut addr = c8.access_i(3, AccessKind::Write)?;
 
     let hundreds = value / 100;
     c8.memory[addr] = hundreds;
@@ -706,11 +1094,381 @@ instr_x!(StoreBCDInstr, "LD", InstrFlags::_None, 0xF033,
     addr += 1;
 
     c8.memory[addr] = value;
+    Ok(())
 },
 | vx| { format!("B, V{}", vx) });
 
 instr_x!(WaitForKeyInstr, "LD", InstrFlags::WaitKey, 0xF00A,
 | c8: &mut Chip8System, vx | {
     c8.v_regs[vx as usize] = c8.pressed_key as u8;
+    Ok(())
 },
 | vx | { format!("V{}, K", vx) });
+
+instr_no_args!(ScrollRightInstr, "SCR", InstrFlags::Screen, 0x00FB,
+| c8: &mut Chip8System | {
+    c8.scroll(4, 0);
+    Ok(())
+});
+
+instr_no_args!(ScrollLeftInstr, "SCL", InstrFlags::Screen, 0x00FC,
+| c8: &mut Chip8System | {
+    c8.scroll(-4, 0);
+    Ok(())
+});
+
+instr_no_args!(ExitInstr, "EXIT", InstrFlags::_None, 0x00FD,
+| c8: &mut Chip8System | {
+    // Stay on this instruction if anyone keeps running
+    c8.exited = true;
+    c8.pc = c8.pc.wrapping_sub(2);
+    Ok(())
+});
+
+instr_no_args!(LowResInstr, "LOW", InstrFlags::Screen, 0x00FE,
+| c8: &mut Chip8System | {
+    c8.set_hires(false);
+    Ok(())
+});
+
+instr_no_args!(HighResInstr, "HIGH", InstrFlags::Screen, 0x00FF,
+| c8: &mut Chip8System | {
+    c8.set_hires(true);
+    Ok(())
+});
+
+instr_x!(GetBigDigitAddrInstr, "LD", InstrFlags::_None, 0xF030,
+| c8: &mut Chip8System, vx | {
+    let digit = u16::from(c8.v_regs[vx as usize]);
+    c8.i_reg = BIG_FONT_ADDR + (digit*10);
+    Ok(())
+},
+| vx | { format!("HF, V{}", vx) });
+
+instr_x!(WriteRegsToRplInstr, "LD", InstrFlags::_None, 0xF075,
+| c8: &mut Chip8System, vx | {
+    for reg_idx in 0..=vx {
+        c8.rpl_flags[reg_idx as usize] = c8.v_regs[reg_idx as usize];
+    }
+    Ok(())
+},
+| vx | { format!("R, V{}", vx) });
+
+instr_x!(ReadRegsFromRplInstr, "LD", InstrFlags::_None, 0xF085,
+| c8: &mut Chip8System, vx | {
+    for reg_idx in 0..=vx {
+        c8.v_regs[reg_idx as usize] = c8.rpl_flags[reg_idx as usize];
+    }
+    Ok(())
+},
+| vx | { format!("V{}, R", vx) });
+
+// XO-CHIP saves and loads can go in either direction, I is not changed
+fn reg_range(vx: u8, vy: u8) -> Vec<usize> {
+    let (x, y) = (vx as usize, vy as usize);
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
+
+instr_x_y!(SaveRegRangeInstr, "SAVE", InstrFlags::_None, 0x5002,
+| c8: &mut Chip8System, vx, vy | {
+    let regs = reg_range(vx, vy);
+    let addr = c8.access_i(regs.len() as u8, AccessKind::Write)?;
+    for (offset, reg) in regs.iter().enumerate() {
+        c8.memory[addr+offset] = c8.v_regs[*reg];
+    }
+    Ok(())
+});
+
+instr_x_y!(LoadRegRangeInstr, "LOAD", InstrFlags::_None, 0x5003,
+| c8: &mut Chip8System, vx, vy | {
+    let regs = reg_range(vx, vy);
+    let addr = c8.access_i(regs.len() as u8, AccessKind::Read)?;
+    for (offset, reg) in regs.iter().enumerate() {
+        c8.v_regs[*reg] = c8.memory[addr+offset];
+    }
+    Ok(())
+});
+
+// Plane number is a bitmask stored where X usually is
+instr_x!(PlaneInstr, "PLANE", InstrFlags::_None, 0xF001,
+| c8: &mut Chip8System, n | {
+    c8.plane_mask = n;
+    Ok(())
+},
+| n | { format!("{}", n) });
+
+instr_no_args!(AudioInstr, "AUDIO", InstrFlags::Sound, 0xF002,
+| c8: &mut Chip8System | {
+    let addr = c8.access_i(AUDIO_PATTERN_SIZE as u8, AccessKind::Read)?;
+    let mut pattern = [0; AUDIO_PATTERN_SIZE];
+    pattern.copy_from_slice(&c8.memory[addr..addr+AUDIO_PATTERN_SIZE]);
+    c8.audio_pattern = Some(pattern);
+    Ok(())
+});
+
+instr_x!(SetPitchInstr, "LD", InstrFlags::Sound, 0xF03A,
+| c8: &mut Chip8System, vx | {
+    c8.pitch = c8.v_regs[vx as usize];
+    Ok(())
+},
+| vx | { format!("PITCH, V{}", vx) });
+
+// Generates InstrKind, with one variant per instruction type,
+// and the functions that dispatch on it to each type.
+macro_rules! instr_kinds {
+    ( $( $kind:ident => $instr_name:ident ),* ) => (
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        pub enum InstrKind {
+            $( $kind ),*
+        }
+
+        impl InstrKind {
+            pub fn get_flags(self) -> InstrFlags {
+                match self {
+                    $( InstrKind::$kind => $instr_name::FLAGS ),*
+                }
+            }
+
+            fn exec(self, c8: &mut Chip8System, opcode: u16, operand: u16)
+                    -> Result<(), EmulatorError> {
+                match self {
+                    $( InstrKind::$kind => $instr_name::exec_opcode(c8, opcode, operand) ),*
+                }
+            }
+
+            fn to_instr(self, opcode: u16, operand: u16) -> Box<dyn Instr> {
+                match self {
+                    $( InstrKind::$kind => Box::new($instr_name::from_parts(opcode, operand)) ),*
+                }
+            }
+        }
+    )
+}
+
+instr_kinds!(
+    Sys => SysInstr,
+    ClearDisplay => ClearDisplayInstr,
+    Ret => RetInstr,
+    ScrollDown => ScrollDownInstr,
+    ScrollUp => ScrollUpInstr,
+    ScrollRight => ScrollRightInstr,
+    ScrollLeft => ScrollLeftInstr,
+    Exit => ExitInstr,
+    LowRes => LowResInstr,
+    HighRes => HighResInstr,
+    Jump => JumpInstr,
+    Call => CallInstr,
+    SkipEqual => SkipEqualInstr,
+    SkipNotEqual => SkipNotEqualInstr,
+    SkipIfRegsEqual => SkipIfRegsEqualInstr,
+    SaveRegRange => SaveRegRangeInstr,
+    LoadRegRange => LoadRegRangeInstr,
+    LoadByte => LoadByteInstr,
+    AddByte => AddByteInstr,
+    MovReg => MovRegInstr,
+    OrReg => OrRegInstr,
+    AndReg => AndRegInstr,
+    XORReg => XORRegInstr,
+    AddReg => AddRegInstr,
+    SubReg => SubRegInstr,
+    ShrReg => ShrRegInstr,
+    SubNReg => SubNRegInstr,
+    ShlReg => ShlRegInstr,
+    SkipIfRegsNotEqual => SkipIfRegsNotEqualInstr,
+    LoadI => LoadIInstr,
+    JumpPlusVZero => JumpPlusVZeroInstr,
+    Random => RandomInstr,
+    DrawSprite => DrawSpriteInstr,
+    SkipKeyIfPressed => SkipKeyIfPressedInstr,
+    SkipKeyIfNotPressed => SkipKeyIfNotPressedInstr,
+    LongLoadI => LongLoadIInstr,
+    Audio => AudioInstr,
+    Plane => PlaneInstr,
+    GetDelayTimer => GetDelayTimerInstr,
+    WaitForKey => WaitForKeyInstr,
+    SetDelayTimer => SetDelayTimerInstr,
+    SetSoundTimer => SetSoundTimerInstr,
+    AddIV => AddIVInstr,
+    GetDigitAddr => GetDigitAddrInstr,
+    StoreBCD => StoreBCDInstr,
+    WriteRegsToMem => WriteRegsToMemInstr,
+    ReadRegsFromMem => ReadRegsFromMemInstr,
+    GetBigDigitAddr => GetBigDigitAddrInstr,
+    WriteRegsToRpl => WriteRegsToRplInstr,
+    ReadRegsFromRpl => ReadRegsFromRplInstr,
+    SetPitch => SetPitchInstr,
+    Undef => UndefInstr
+);
+
+// A decoded instruction that can be copied around and executed without
+// allocating. Use to_instr to get the Instr view of it for printing.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Instruction {
+    pub kind: InstrKind,
+    pub opcode: u16,
+    // Only used by instructions longer than one word, e.g. LDL's address
+    pub operand: u16,
+}
+
+impl Instruction {
+    pub fn get_flags(&self) -> InstrFlags {
+        self.kind.get_flags()
+    }
+
+    pub fn exec(&self, c8: &mut Chip8System) -> Result<(), EmulatorError> {
+        self.kind.exec(c8, self.opcode, self.operand)
+    }
+
+    pub fn to_instr(self) -> Box<dyn Instr> {
+        self.kind.to_instr(self.opcode, self.operand)
+    }
+
+    // In bytes
+    pub fn size(&self) -> u16 {
+        if self.kind == InstrKind::LongLoadI { 4 } else { 2 }
+    }
+}
+
+// rchip8 uses SYS 0xFFF as a breakpoint, BRK in assembly
+pub const BRK_OPCODE: u16 = 0x0FFF;
+
+// Why an opcode couldn't be decoded
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DecodeError {
+    Breakpoint,
+    Unknown(u16),
+}
+
+impl DecodeError {
+    // What running it at pc would do
+    pub fn at(self, pc: u16) -> EmulatorError {
+        match self {
+            DecodeError::Breakpoint => EmulatorError::Breakpoint { pc },
+            DecodeError::Unknown(opcode) => EmulatorError::UnknownInstruction { opcode, pc },
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Breakpoint => write!(f, "BRK instruction"),
+            DecodeError::Unknown(opcode) => write!(f, "Unknown instruction 0x{:04X}", opcode),
+        }
+    }
+}
+
+// Which instruction an opcode is
+pub(crate) fn decode_kind(opcode: u16) -> Result<InstrKind, DecodeError> {
+    match opcode >> 12 {
+        0x0 => {
+            match opcode & 0xFFF {
+                // Note that these first two *must* begin with 0, as in 0x00E0
+                0x0E0 => Ok(InstrKind::ClearDisplay),
+                0x0EE => Ok(InstrKind::Ret),
+                // SUPER-CHIP
+                0x0C0..=0x0CF => Ok(InstrKind::ScrollDown),
+                // XO-CHIP
+                0x0D0..=0x0DF => Ok(InstrKind::ScrollUp),
+                0x0FB => Ok(InstrKind::ScrollRight),
+                0x0FC => Ok(InstrKind::ScrollLeft),
+                0x0FD => Ok(InstrKind::Exit),
+                0x0FE => Ok(InstrKind::LowRes),
+                0x0FF => Ok(InstrKind::HighRes),
+                // Special BRK instr
+                0xFFF => Err(DecodeError::Breakpoint),
+                _ =>    Ok(InstrKind::Sys),
+            }
+        }
+        0x1 => Ok(InstrKind::Jump),
+        0x2 => Ok(InstrKind::Call),
+        0x3 => Ok(InstrKind::SkipEqual),
+        0x4 => Ok(InstrKind::SkipNotEqual),
+        0x5 => {
+            match opcode & 0xF {
+                0 => Ok(InstrKind::SkipIfRegsEqual),
+                // XO-CHIP
+                2 => Ok(InstrKind::SaveRegRange),
+                3 => Ok(InstrKind::LoadRegRange),
+                _ => Err(DecodeError::Unknown(opcode)),
+            }
+        }
+        0x6 => Ok(InstrKind::LoadByte),
+        0x7 => Ok(InstrKind::AddByte),
+        0x8 => {
+            match opcode & 0xF {
+                0x0 => Ok(InstrKind::MovReg),
+                0x1 => Ok(InstrKind::OrReg),
+                0x2 => Ok(InstrKind::AndReg),
+                0x3 => Ok(InstrKind::XORReg),
+                0x4 => Ok(InstrKind::AddReg),
+                0x5 => Ok(InstrKind::SubReg),
+                0x6 => Ok(InstrKind::ShrReg),
+                0x7 => Ok(InstrKind::SubNReg),
+                0xE => Ok(InstrKind::ShlReg),
+                _   => Err(DecodeError::Unknown(opcode)),
+            }
+        }
+        0x9 => {
+            match opcode & 0xF {
+                0 => Ok(InstrKind::SkipIfRegsNotEqual),
+                _ => Err(DecodeError::Unknown(opcode)),
+            }
+        }
+        0xA => Ok(InstrKind::LoadI),
+        0xB => Ok(InstrKind::JumpPlusVZero),
+        0xC => Ok(InstrKind::Random),
+        0xD => Ok(InstrKind::DrawSprite),
+        0xE => {
+            match opcode & 0xFF {
+                0x9E => Ok(InstrKind::SkipKeyIfPressed),
+                0xA1 => Ok(InstrKind::SkipKeyIfNotPressed),
+                _    => Err(DecodeError::Unknown(opcode)),
+            }
+        }
+        0xF => {
+            // XO-CHIP, where the X position holds something else
+            match opcode {
+                0xF000 => return Ok(InstrKind::LongLoadI),
+                0xF002 => return Ok(InstrKind::Audio),
+                0xF001 | 0xF101 | 0xF201 | 0xF301 => return Ok(InstrKind::Plane),
+                _ => {},
+            }
+
+            match opcode & 0xFF {
+                0x07 => Ok(InstrKind::GetDelayTimer),
+                0x0A => Ok(InstrKind::WaitForKey),
+                0x15 => Ok(InstrKind::SetDelayTimer),
+                0x18 => Ok(InstrKind::SetSoundTimer),
+                0x1E => Ok(InstrKind::AddIV),
+                0x29 => Ok(InstrKind::GetDigitAddr),
+                0x33 => Ok(InstrKind::StoreBCD),
+                0x55 => Ok(InstrKind::WriteRegsToMem),
+                0x65 => Ok(InstrKind::ReadRegsFromMem),
+                // SUPER-CHIP
+                0x30 => Ok(InstrKind::GetBigDigitAddr),
+                0x75 => Ok(InstrKind::WriteRegsToRpl),
+                0x85 => Ok(InstrKind::ReadRegsFromRpl),
+                // XO-CHIP
+                0x3A => Ok(InstrKind::SetPitch),
+                _    => Err(DecodeError::Unknown(opcode)),
+            }
+        }
+        _ => Err(DecodeError::Unknown(opcode)),
+    }
+}
+
+// Decodes any opcode, without needing a system. LDL's operand is the word
+// after it, which is left as 0 for the caller to fill in.
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    decode_kind(opcode).map(|kind| Instruction { kind, opcode, operand: 0 })
+}
+
+// Like decode, but opcodes that aren't instructions become Undef
+pub fn decode_or_undef(opcode: u16) -> Instruction {
+    decode(opcode).unwrap_or(Instruction { kind: InstrKind::Undef, opcode, operand: 0 })
+}
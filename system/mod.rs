@@ -1,14 +1,31 @@
-// Until I work out a way around Box<dyn Instr>, impl trait is close
-#![cfg_attr(feature = "cargo-clippy", allow(clippy::borrowed_box))]
-
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use system::instr::*;
+use system::quirks::Quirks;
+use system::rng::Chip8Rng;
+use system::debug::Register;
+use system::trace::{Tracer, TraceEntry, TraceChange};
+use system::profile::Profiler;
+use system::coverage::Coverage;
 use std::fs::File;
 use std::io::Read;
+use std::fmt;
 
 mod test;
 pub mod instr;
+pub mod quirks;
+pub mod headless;
+pub mod rewind;
+pub mod movie;
+pub mod debug;
+pub mod trace;
+pub mod tracediff;
+pub mod profile;
+pub mod coverage;
+pub mod romdb;
+mod rng;
+mod sha1;
+mod state;
 
 pub fn read_rom(filename: &str) -> Vec<u8> {
     let mut file = match File::open(filename) {
@@ -27,15 +44,13 @@ pub fn read_rom(filename: &str) -> Vec<u8> {
 pub fn instrs_to_rom(instrs: &[Box<dyn Instr>]) -> Vec<u8> {
     let mut rom: Vec<u8> = vec![];
     for i in instrs {
-        let opc = i.get_opcode();
-        rom.push((opc >> 8) as u8);
-        rom.push(opc as u8);
+        rom.extend(i.get_bytes());
     }
     rom
 }
 
-pub fn make_system(rom: &[u8]) -> Chip8System {
-    let mut c = Chip8System::new();
+pub fn make_system(rom: &[u8], quirks: Quirks) -> Chip8System {
+    let mut c = Chip8System::new(quirks);
     c.init_memory(&rom);
     c
 }
@@ -49,13 +64,87 @@ pub enum InstrFlags {
     Sound,
 }
 
+// Things a ROM can do that stop the system from carrying on
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EmulatorError {
+    UnknownInstruction { opcode: u16, pc: u16 },
+    Breakpoint { pc: u16 },
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds { addr: u16, length: u8 },
+    InvalidKey(u8),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmulatorError::UnknownInstruction { opcode, pc } =>
+                write!(f, "Unknown instruction 0x{:04X} at PC 0x{:04X}", opcode, pc),
+            EmulatorError::Breakpoint { pc } =>
+                write!(f, "BRK instruction encountered at PC 0x{:04X}", pc),
+            EmulatorError::StackOverflow => write!(f, "Stack is full!"),
+            EmulatorError::StackUnderflow => write!(f, "Stack is empty!"),
+            EmulatorError::MemoryOutOfBounds { addr, length } =>
+                write!(f, "I register memory access at 0x{:04x} with length {} is out of bounds!",
+                       addr, length),
+            EmulatorError::InvalidKey(key) => write!(f, "Key number {} out of range!", key),
+        }
+    }
+}
+
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGHT: usize = 64;
+
+// XO-CHIP can address all 64K
+pub const MEMORY_SIZE: usize = 0x10000;
+const FONT_ADDR: u16 = 0x0;
+const BIG_FONT_ADDR: u16 = 0x50;
+// SUPER-CHIP has 8, XO-CHIP extends that to 16
+pub const NUM_RPL_FLAGS: usize = 16;
+pub const STACK_SIZE: usize = 16;
+// XO-CHIP bitplanes. Each screen pixel is a mask of the planes set there.
+pub const NUM_PLANES: usize = 2;
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// Everything but the PC, which the next instruction in a trace shows
+const TRACED_REGISTERS: [Register; 20] = [
+    Register::V(0),  Register::V(1),  Register::V(2),  Register::V(3),
+    Register::V(4),  Register::V(5),  Register::V(6),  Register::V(7),
+    Register::V(8),  Register::V(9),  Register::V(10), Register::V(11),
+    Register::V(12), Register::V(13), Register::V(14), Register::V(15),
+    Register::I, Register::SP, Register::DT, Register::ST,
+];
+
+// Memory used by an instruction via I, logged when enabled with set_access_log
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: AccessKind,
+}
+
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
+
+// The delay and sound timers always count down at 60Hz of emulated time.
+// One frame is cycles_per_frame instructions, so the CPU rate is
+// FRAMES_PER_SECOND * cycles_per_frame instructions per second.
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const DEFAULT_CYCLES_PER_FRAME: u32 = 10;
 
 pub struct Chip8System {
     pc : u16,
-    memory : [u8 ; 0xFFFF],
-    pub screen : [bool ; SCREEN_WIDTH*SCREEN_HEIGHT],
+    memory : [u8 ; MEMORY_SIZE],
+    pub screen : Vec<u8>,
+    hires : bool,
+    plane_mask : u8,
     pub keys : [bool ; 16],
     pub pressed_key : usize,
     v_regs : [u8 ; 16],
@@ -63,14 +152,31 @@ pub struct Chip8System {
     stack : Vec<u16>,
     delay_timer : u8,
     pub sound_timer : u8,
+    rpl_flags : [u8 ; NUM_RPL_FLAGS],
+    audio_pattern : Option<[u8 ; AUDIO_PATTERN_SIZE]>,
+    pitch : u8,
+    exited : bool,
+    tracer : Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    log_accesses : bool,
+    accesses : Vec<MemoryAccess>,
+    quirks : Quirks,
+    rng : Chip8Rng,
+    cycles_per_frame : u32,
+    frame_cycles : u32,
+    frames : u64,
+    cycles : u64,
 }
 
 impl Chip8System {
-    pub fn new() -> Chip8System {
+    pub fn new(quirks: Quirks) -> Chip8System {
         Chip8System {
             pc: 0x200,
-            memory: [0; 0xFFFF],
-            screen: [false; SCREEN_WIDTH*SCREEN_HEIGHT],
+            memory: [0; MEMORY_SIZE],
+            screen: vec![0; SCREEN_WIDTH*SCREEN_HEIGHT],
+            hires: false,
+            plane_mask: 1,
             keys: [false; 16],
             stack: vec![],
             pressed_key: 0,
@@ -78,6 +184,21 @@ impl Chip8System {
             i_reg: 0,
             delay_timer: 0,
             sound_timer: 0,
+            rpl_flags: [0; NUM_RPL_FLAGS],
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            exited: false,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            log_accesses: false,
+            accesses: vec![],
+            quirks,
+            rng: Chip8Rng::from_entropy(),
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            frame_cycles: 0,
+            frames: 0,
+            cycles: 0,
         }
     }
 
@@ -89,28 +210,189 @@ impl Chip8System {
         self.stack = vec![];
         self.delay_timer = 0;
         self.sound_timer = 0;
+        self.frame_cycles = 0;
     }
 
-    pub fn bounds_check_i(&self, length: u8) -> usize {
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        if cycles == 0 {
+            panic!("Cycles per frame must be at least 1!");
+        }
+        self.cycles_per_frame = cycles;
+    }
+
+    // Makes RND give the same sequence every run
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Chip8Rng::from_seed(seed);
+    }
+
+    // Write each instruction executed, and what it changed, to a trace
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    // Traces are buffered, so call this before exiting
+    pub fn flush_trace(&mut self) {
+        if let Some(ref mut tracer) = self.tracer {
+            if let Err(e) = tracer.flush() {
+                println!("Couldn't write trace: {}", e);
+            }
+        }
+    }
+
+    // Count how often each instruction and subroutine runs
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn get_profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Record which bytes are executed, read and written
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // Record the memory instructions use, for take_accesses
+    pub fn set_access_log(&mut self, enable: bool) {
+        self.log_accesses = enable;
+        if !enable {
+            self.accesses.clear();
+        }
+    }
+
+    // Everything logged since the last call, oldest first
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        ::std::mem::take(&mut self.accesses)
+    }
+
+    // Number of whole 60Hz frames of emulated time so far
+    pub fn get_frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    // Number of instructions fetched so far
+    pub fn get_cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    // Each instruction advances emulated time by one cycle
+    fn tick(&mut self) {
+        self.cycles += 1;
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles = 0;
+            self.frames += 1;
+
+            if self.delay_timer != 0 {
+                self.delay_timer -= 1;
+            }
+            if self.sound_timer != 0 {
+                self.sound_timer -= 1;
+            }
+        }
+    }
+
+    pub fn get_screen_width(&self) -> usize {
+        if self.hires { HIRES_SCREEN_WIDTH } else { SCREEN_WIDTH }
+    }
+
+    pub fn get_screen_height(&self) -> usize {
+        if self.hires { HIRES_SCREEN_HEIGHT } else { SCREEN_HEIGHT }
+    }
+
+    // Changing resolution also clears the screen
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.screen = vec![0; self.get_screen_width()*self.get_screen_height()];
+    }
+
+    // Only the selected planes are drawn, cleared and scrolled
+    fn clear_screen(&mut self) {
+        let mask = self.plane_mask;
+        for p in self.screen.iter_mut() {
+            *p &= !mask;
+        }
+    }
+
+    // The audio pattern is None until a program loads one,
+    // in which case you should play a plain buzzer instead.
+    pub fn get_audio_pattern(&self) -> Option<[u8; AUDIO_PATTERN_SIZE]> {
+        self.audio_pattern
+    }
+
+    pub fn get_pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    // Set by the EXIT instruction, the program has finished
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    // Positive amounts are right/down, negative are left/up
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.get_screen_width() as isize;
+        let height = self.get_screen_height() as isize;
+        let mask = self.plane_mask;
+        let old = self.screen.clone();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x-dx, y-dy);
+                let moved = if (from_x >= 0) && (from_x < width) &&
+                               (from_y >= 0) && (from_y < height) {
+                    old[((from_y*width)+from_x) as usize]
+                } else {
+                    0
+                };
+                let idx = ((y*width)+x) as usize;
+                self.screen[idx] = (old[idx] & !mask) | (moved & mask);
+            }
+        }
+    }
+
+    pub fn bounds_check_i(&self, length: u8) -> Result<usize, EmulatorError> {
         let end = (self.i_reg as usize) + (length as usize);
-        if end >= self.memory.len() {
-            panic!("I register memory access at 0x{:04x} with length {} is out of bounds!",
-                   self.i_reg, length);
+        if end > self.memory.len() {
+            return Err(EmulatorError::MemoryOutOfBounds { addr: self.i_reg, length });
         }
-        self.i_reg as usize
+        Ok(self.i_reg as usize)
+    }
+
+    // Check that the access is in bounds then log it
+    fn access_i(&mut self, length: u8, kind: AccessKind) -> Result<usize, EmulatorError> {
+        let addr = self.bounds_check_i(length)?;
+        if self.log_accesses {
+            self.accesses.push(MemoryAccess { addr: self.i_reg, len: u16::from(length), kind });
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record_access(self.i_reg, u16::from(length), kind);
+        }
+        Ok(addr)
     }
 
     pub fn screen_to_str(&self) -> String {
         let mut ret = String::from("");
         let mut row = String::from("");
+        let width = self.get_screen_width();
         for (i, pixel) in self.screen.iter().enumerate() {
-            if ((i % SCREEN_WIDTH) == 0) &&  i != 0 {
+            if ((i % width) == 0) &&  i != 0 {
                 row.push('\n');
                 ret.push_str(&row);
                 row.clear();
             }
 
-            if *pixel { row.push('@') } else { row.push('-') }
+            row.push(match *pixel {
+                0 => '-',
+                1 => '@',
+                2 => '+',
+                _ => '#',
+            });
         }
         ret.push_str(&row);
         ret
@@ -137,33 +419,34 @@ impl Chip8System {
         }
     }
 
-    pub fn get_keystate(&self, idx: u8) -> bool {
+    pub fn get_keystate(&self, idx: u8) -> Result<bool, EmulatorError> {
         if idx >= 16 {
-            panic!("Key number {} out of range!", idx);
+            return Err(EmulatorError::InvalidKey(idx));
         }
-        self.keys[idx as usize]
+        Ok(self.keys[idx as usize])
     }
 
-    #[allow(dead_code)]
-    fn dump(&self) {
-        println!("----- Chip8 State -----");
-        println!("PC: 0x{:04x} I: 0x{:04x}", self.pc, self.i_reg);
-        print!("Delay Timer: {} Sound Timer: {}", self.delay_timer, self.sound_timer);
+    pub fn dump_to_str(&self) -> String {
+        let mut ret = String::from("----- Chip8 State -----\n");
+        ret.push_str(&format!("PC: 0x{:04x} I: 0x{:04x}\n", self.pc, self.i_reg));
+        ret.push_str(&format!("Delay Timer: {} Sound Timer: {}",
+                              self.delay_timer, self.sound_timer));
         for (i, v) in self.v_regs.iter().enumerate() {
             if i % 8 == 0 {
-                println!();
+                ret.push('\n');
             }
-            print!("V{:02}: 0x{:02x} ", i, *v);
+            ret.push_str(&format!("V{:02}: 0x{:02x} ", i, *v));
         }
-        println!();
-        println!("Stack:");
+        ret.push('\n');
+        ret.push_str("Stack:\n");
         if self.stack.is_empty() {
-            println!("<...>");
+            ret.push_str("<...>\n");
         } else {
             for (i, addr) in self.stack.iter().enumerate() {
-                println!("{:02}: 0x{:04x}", i, *addr);
+                ret.push_str(&format!("{:02}: 0x{:04x}\n", i, *addr));
             }
         }
+        ret
     }
 
     fn init_memory(&mut self, rom: &[u8]) {
@@ -186,145 +469,145 @@ impl Chip8System {
                         0xF0, 0x80, 0xF0, 0x80, 0x80,  // charF
                         ];
 
-        self.memory[..font_data.len()].clone_from_slice(&font_data);
+        // SUPER-CHIP 8x10 digits, 0-9 only
+        let big_font_data = [
+                        0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C,  // Zero
+                        0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C,  // One
+                        0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF,  // Two
+                        0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C,  // Three
+                        0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06,  // Four
+                        0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C,  // Five
+                        0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C,  // Six
+                        0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60,  // Seven
+                        0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C,  // Eight
+                        0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C,  // Nine
+                        ];
+
+        let font_addr = FONT_ADDR as usize;
+        self.memory[font_addr..font_addr+font_data.len()].clone_from_slice(&font_data);
+        let big_font_addr = BIG_FONT_ADDR as usize;
+        self.memory[big_font_addr..big_font_addr+big_font_data.len()].clone_from_slice(&big_font_data);
         self.memory[0x200..0x200+rom.len()].clone_from_slice(&rom);
     }
 
+    fn read_word(&self, addr: u16) -> u16 {
+        (u16::from(self.memory[addr as usize]) << 8) |
+            u16::from(self.memory[addr.wrapping_add(1) as usize])
+    }
+
     fn fetch(&mut self) -> u16 {
-        let opcode = (u16::from(self.memory[self.pc as usize]) << 8) | u16::from(self.memory[(self.pc+1) as usize]);
-        self.pc += 2;
+        let opcode = self.read_word(self.pc);
+        self.pc = self.pc.wrapping_add(2);
         opcode
     }
 
-    fn format_unknown(&self, opcode: u16) -> String {
-        // Not the best place for this but it works
-        self.dump();
-        format!("Unknown instruction 0x{:04X} at PC 0x{:04X}", opcode, self.pc-2)
-    }
-
-    fn get_opcode_obj(&self, opcode: u16) -> Result<Box<dyn Instr>, String> {
-        match opcode >> 12 {
-            0x0 => {
-                match opcode & 0xFFF {
-                    // Note that these first two *must* begin with 0, as in 0x00E0
-                    0x0E0 => Ok(Box::new(ClearDisplayInstr::new(opcode)) as Box<dyn Instr>),
-                    0x0EE => Ok(Box::new(RetInstr::new(opcode))          as Box<dyn Instr>),
-                    0xFFF => {
-                        // Special BRK instr
-                        self.dump();
-                        panic!(format!("BRK instruction encountered at PC 0x{:04X}", self.pc-2));
-                    },
-                    _ =>    Ok(Box::new(SysInstr::new(opcode))          as Box<dyn Instr>),
-                }
-            }
-            0x1 => Ok(Box::new(JumpInstr::new(opcode))         as Box<dyn Instr>),
-            0x2 => Ok(Box::new(CallInstr::new(opcode))         as Box<dyn Instr>),
-            0x3 => Ok(Box::new(SkipEqualInstr::new(opcode))    as Box<dyn Instr>),
-            0x4 => Ok(Box::new(SkipNotEqualInstr::new(opcode)) as Box<dyn Instr>),
-            0x5 => {
-                match opcode & 0xF {
-                    0 => Ok(Box::new(SkipIfRegsEqualInstr::new(opcode)) as Box<dyn Instr>),
-                    _ => Err(self.format_unknown(opcode)),
-                }
-            }
-            0x6 => Ok(Box::new(LoadByteInstr::new(opcode)) as Box<dyn Instr>),
-            0x7 => Ok(Box::new(AddByteInstr::new(opcode))  as Box<dyn Instr>),
-            0x8 => {
-                match opcode & 0xF {
-                    0x0 => Ok(Box::new(MovRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x1 => Ok(Box::new(OrRegInstr::new(opcode))   as Box<dyn Instr>),
-                    0x2 => Ok(Box::new(AndRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x3 => Ok(Box::new(XORRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x4 => Ok(Box::new(AddRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x5 => Ok(Box::new(SubRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x6 => Ok(Box::new(ShrRegInstr::new(opcode))  as Box<dyn Instr>),
-                    0x7 => Ok(Box::new(SubNRegInstr::new(opcode)) as Box<dyn Instr>),
-                    0xE => Ok(Box::new(ShlRegInstr::new(opcode))  as Box<dyn Instr>),
-                    _   => Err(self.format_unknown(opcode)),
-                }
-            }
-            0x9 => {
-                match opcode & 0xF {
-                    0 => Ok(Box::new(SkipIfRegsNotEqualInstr::new(opcode)) as Box<dyn Instr>),
-                    _ => Err(self.format_unknown(opcode)),
-                }
-            }
-            0xA => Ok(Box::new(LoadIInstr::new(opcode))         as Box<dyn Instr>),
-            0xB => Ok(Box::new(JumpPlusVZeroInstr::new(opcode)) as Box<dyn Instr>),
-            0xC => Ok(Box::new(RandomInstr::new(opcode))        as Box<dyn Instr>),
-            0xD => Ok(Box::new(DrawSpriteInstr::new(opcode))    as Box<dyn Instr>),
-            0xE => {
-                match opcode & 0xFF {
-                    0x9E => Ok(Box::new(SkipKeyIfPressedInstr::new(opcode))    as Box<dyn Instr>),
-                    0xA1 => Ok(Box::new(SkipKeyIfNotPressedInstr::new(opcode)) as Box<dyn Instr>),
-                    _    => Err(self.format_unknown(opcode)),
-                }
-            }
-            0xF => {
-                match opcode & 0xFF {
-                    0x07 => Ok(Box::new(GetDelayTimerInstr::new(opcode))   as Box<dyn Instr>),
-                    0x0A => Ok(Box::new(WaitForKeyInstr::new(opcode))      as Box<dyn Instr>),
-                    0x15 => Ok(Box::new(SetDelayTimerInstr::new(opcode))   as Box<dyn Instr>),
-                    0x18 => Ok(Box::new(SetSoundTimerInstr::new(opcode))   as Box<dyn Instr>),
-                    0x1E => Ok(Box::new(AddIVInstr::new(opcode))           as Box<dyn Instr>),
-                    0x29 => Ok(Box::new(GetDigitAddrInstr::new(opcode))    as Box<dyn Instr>),
-                    0x33 => Ok(Box::new(StoreBCDInstr::new(opcode))        as Box<dyn Instr>),
-                    0x55 => Ok(Box::new(WriteRegsToMemInstr::new(opcode))  as Box<dyn Instr>),
-                    0x65 => Ok(Box::new(ReadRegsFromMemInstr::new(opcode)) as Box<dyn Instr>),
-                    _    => Err(self.format_unknown(opcode)),
-                }
-            }
-            _ => Err(self.format_unknown(opcode)),
-        }
+    // The XO-CHIP long load is 4 bytes so skips have to step over all of it
+    fn skip_next_instr(&mut self) {
+        let size = if self.read_word(self.pc) == 0xF000 { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(size);
     }
 
-    pub fn fetch_and_decode(&mut self) -> Box<dyn Instr> {
-        // TODO: base these on actual frequencies, not number of instructions
-        unsafe {
-            static DT_FUDGE_RESET: u16 = 100;
-            static mut DT_FUDGE: u16 = DT_FUDGE_RESET;
-            DT_FUDGE = match DT_FUDGE {
-                0 => {
-                    if self.delay_timer != 0 {
-                        self.delay_timer -= 1;
-                    }
-                    DT_FUDGE_RESET
-                }
-                _ => DT_FUDGE - 1,
-            };
+    // The instruction at the PC we just fetched from
+    fn decode(&self, opcode: u16) -> Result<Instruction, EmulatorError> {
+        let instr = decode(opcode).map_err(|e| e.at(self.pc.wrapping_sub(2)))?;
+        // Any further words of the instruction follow the PC
+        let operand = if instr.kind == InstrKind::LongLoadI { self.read_word(self.pc) } else { 0 };
+        Ok(Instruction { operand, ..instr })
+    }
 
-            static ST_FUDGE_RESET: u16 = 150;
-            static mut ST_FUDGE: u16 = ST_FUDGE_RESET;
-            ST_FUDGE = match ST_FUDGE {
-                0 => {
-                    if self.sound_timer != 0 {
-                        self.sound_timer -= 1;
-                    }
-                    ST_FUDGE_RESET
-                },
-                _ => ST_FUDGE - 1,
-            };
+    // Look at an instruction without fetching it, e.g. to disassemble
+    pub fn decode_at(&self, addr: u16) -> Result<Instruction, EmulatorError> {
+        let opcode = self.read_word(addr);
+        let instr = decode(opcode)
+            .map_err(|_| EmulatorError::UnknownInstruction { opcode, pc: addr })?;
+        let operand = if instr.kind == InstrKind::LongLoadI {
+            self.read_word(addr.wrapping_add(2))
+        } else {
+            0
+        };
+        Ok(Instruction { operand, ..instr })
+    }
+
+    // Disassembly of an instruction as this system runs it. With the
+    // jump_uses_vx quirk BNNN is really BXNN, jumping to XNN + VX.
+    pub fn repr_instr(&self, instr: Instruction) -> String {
+        if (instr.kind == InstrKind::JumpPlusVZero) && self.quirks.jump_uses_vx {
+            let addr = instr.opcode & 0xFFF;
+            return format!("JP V{:X}, 0x{:03X}", addr >> 8, addr);
         }
+        instr.to_instr().repr()
+    }
+
+    pub fn fetch_and_decode(&mut self) -> Result<Instruction, EmulatorError> {
+        self.tick();
 
         let opc = self.fetch();
-        let decode = self.get_opcode_obj(opc);
-        match decode {
-            Ok(instr) => {
-                // -2 because we already fetched beyond this instr
-                // Print this now because otherwise jumps won't look right
-                // You'll see the post jump PC, not the PC we fetched the
-                // jump from.
-                println!("0x{:04x} : 0x{:04x} : {}",
-                         self.pc-2, instr.get_opcode(), instr.repr());
-
-                instr
+        self.decode(opc)
+    }
+
+    pub fn execute(&mut self, instr: &Instruction) -> Result<(), EmulatorError> {
+        //TODO: check that fetch and decode has been called
+        // -2 because we already fetched beyond the first word
+        let pc = self.pc.wrapping_sub(2);
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record_executed(pc, instr.size());
+        }
+        let result = if self.tracer.is_none() {
+            instr.exec(self)
+        } else {
+            self.execute_traced(instr, pc)
+        };
+
+        if result.is_ok() {
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(pc, instr.kind, self.pc);
             }
-            Err(msg) => panic!(msg),
         }
+        result
     }
 
-    pub fn execute(&mut self, instr: &Box<dyn Instr>) {
-        //TODO: check that fetch and decode has been called
-        instr.exec(self);
+    fn execute_traced(&mut self, instr: &Instruction, pc: u16) -> Result<(), EmulatorError> {
+        let before: Vec<u16> = TRACED_REGISTERS.iter().map(|r| self.read_register(*r)).collect();
+        // Use the access log to find memory writes, without
+        // losing anything the debugger wanted from it
+        let was_logging = self.log_accesses;
+        let first_access = self.accesses.len();
+        self.log_accesses = true;
+
+        let result = instr.exec(self);
+
+        let accesses = self.accesses.split_off(first_access);
+        self.log_accesses = was_logging;
+        if was_logging {
+            self.accesses.extend(&accesses);
+        }
+
+        let mut tracer = self.tracer.take().unwrap();
+        if tracer.is_traced(pc, &accesses) {
+            let mut changes: Vec<TraceChange> = TRACED_REGISTERS.iter().zip(before)
+                .map(|(reg, old)| (*reg, old, self.read_register(*reg)))
+                .filter(|&(_, old, new)| old != new)
+                .map(|(reg, _, new)| TraceChange::Register(reg, new))
+                .collect();
+            changes.extend(accesses.iter().filter(|a| a.kind == AccessKind::Write).map(|a| {
+                TraceChange::Memory(a.addr, self.read_memory(a.addr, a.len as usize))
+            }));
+
+            let entry = TraceEntry {
+                cycle: self.get_cycle_count(),
+                pc,
+                opcode: instr.opcode,
+                operand: if instr.size() == 4 { Some(instr.operand) } else { None },
+                disassembly: self.repr_instr(*instr),
+                changes,
+            };
+            if let Err(e) = tracer.write(&entry) {
+                println!("Stopped tracing, couldn't write trace: {}", e);
+                return result
+            }
+        }
+        self.tracer = Some(tracer);
+
+        result
     }
 }
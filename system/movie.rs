@@ -0,0 +1,140 @@
+use system::{Chip8System, make_system};
+use system::quirks::Quirks;
+use system::rng::Chip8Rng;
+use system::state::{StateWriter, StateReader, write_quirks, read_quirks,
+                    keys_to_mask, mask_to_keys};
+
+// Movie layout, all values big endian:
+// "RC8M", u16 version, u64 seed, u32 cycles per frame, quirks,
+// u32 frame count then the key mask for each frame,
+// u32 key wait count then the key that ended each wait.
+const MOVIE_MAGIC: &[u8; 4] = b"RC8M";
+pub const MOVIE_VERSION: u16 = 1;
+
+// All the input a program was given, so that a run can be repeated exactly.
+// Keys are sampled once per frame. Waiting for a key stops emulated time,
+// so the key that ended each wait is kept separately.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Movie {
+    // The state of the RNG when the movie started
+    pub seed: u64,
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    pub frames: Vec<u16>,
+    pub wait_keys: Vec<u8>,
+}
+
+impl Movie {
+    // Start recording a system that hasn't run anything yet
+    pub fn new(c8: &Chip8System) -> Movie {
+        Movie {
+            seed: c8.rng.get_state(),
+            cycles_per_frame: c8.cycles_per_frame,
+            quirks: c8.quirks,
+            frames: vec![],
+            wait_keys: vec![],
+        }
+    }
+
+    // A system set up the way the recorded one was
+    pub fn make_system(&self, rom: &[u8]) -> Chip8System {
+        let mut c8 = make_system(rom, self.quirks);
+        c8.set_cycles_per_frame(self.cycles_per_frame);
+        c8.rng = Chip8Rng::from_state(self.seed);
+        c8
+    }
+
+    // Call at the start of each frame
+    pub fn record_frame(&mut self, keys: &[bool; 16]) {
+        self.frames.push(keys_to_mask(keys));
+    }
+
+    pub fn record_wait_key(&mut self, key: u8) {
+        self.wait_keys.push(key);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter { data: vec![] };
+        w.bytes(MOVIE_MAGIC);
+        w.u16(MOVIE_VERSION);
+
+        w.u64(self.seed);
+        w.u32(self.cycles_per_frame);
+        write_quirks(&mut w, &self.quirks);
+
+        w.u32(self.frames.len() as u32);
+        for keys in &self.frames {
+            w.u16(*keys);
+        }
+        w.u32(self.wait_keys.len() as u32);
+        w.bytes(&self.wait_keys);
+
+        w.data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, String> {
+        let mut r = StateReader { data, pos: 0, name: "Movie" };
+        if r.bytes(MOVIE_MAGIC.len()).ok() != Some(&MOVIE_MAGIC[..]) {
+            return Err("Not a movie file".to_string());
+        }
+        let version = r.u16()?;
+        if version != MOVIE_VERSION {
+            return Err(format!("Unsupported movie version {}, expected {}",
+                               version, MOVIE_VERSION));
+        }
+
+        let seed = r.u64()?;
+        let cycles_per_frame = r.u32()?;
+        if cycles_per_frame == 0 {
+            return Err("Movie has 0 cycles per frame".to_string());
+        }
+        let quirks = read_quirks(&mut r)?;
+
+        let num_frames = r.u32()?;
+        let mut frames = vec![];
+        for _ in 0..num_frames {
+            frames.push(r.u16()?);
+        }
+        let num_wait_keys = r.u32()? as usize;
+        let wait_keys = r.bytes(num_wait_keys)?.to_vec();
+        if let Some(key) = wait_keys.iter().find(|k| **k >= 16) {
+            return Err(format!("Movie has invalid wait key {}", key));
+        }
+
+        if r.pos != data.len() {
+            return Err(format!("Movie has {} unexpected trailing bytes",
+                               data.len() - r.pos));
+        }
+
+        Ok(Movie { seed, cycles_per_frame, quirks, frames, wait_keys })
+    }
+}
+
+// Feeds a movie's input back into a system made by Movie::make_system
+pub struct MoviePlayer {
+    movie: Movie,
+    next_wait_key: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer { movie, next_wait_key: 0 }
+    }
+
+    // None once the movie has run out of frames
+    pub fn keys_for_frame(&self, frame: u64) -> Option<[bool; 16]> {
+        if frame < self.movie.frames.len() as u64 {
+            Some(mask_to_keys(self.movie.frames[frame as usize]))
+        } else {
+            None
+        }
+    }
+
+    pub fn next_wait_key(&mut self) -> Option<u8> {
+        let key = self.movie.wait_keys.get(self.next_wait_key).cloned();
+        if key.is_some() {
+            self.next_wait_key += 1;
+        }
+        key
+    }
+}
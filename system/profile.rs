@@ -0,0 +1,139 @@
+use system::Chip8System;
+use system::instr::InstrKind;
+use asm::DebugInfo;
+use std::collections::BTreeMap;
+
+// Instructions run while a subroutine was on the stack
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct SubroutineProfile {
+    pub calls: u64,
+    // Including the subroutines it calls. Recursive calls only count once.
+    pub inclusive: u64,
+    // Only its own instructions, CALLs it makes and its RET included
+    pub exclusive: u64,
+}
+
+// A call the profiler has seen but not the return of yet
+#[derive(Clone, Debug)]
+struct Frame {
+    entry: u16,
+    // The profiler's total when the call was made
+    instrs_at_call: u64,
+}
+
+// Counts every instruction run, including ones run again after
+// rewinding or loading a state. Returns without a matching call, like
+// from a state loaded mid subroutine, are ignored.
+#[derive(Clone, Default, Debug)]
+pub struct Profiler {
+    pub total: u64,
+    // Times the instruction at each address was run
+    pub counts: BTreeMap<u16, u64>,
+    // By entry address
+    pub subroutines: BTreeMap<u16, SubroutineProfile>,
+    // Instructions run outside of any subroutine
+    pub top_level: u64,
+    // Number of calls from caller (None for the top level) to callee
+    pub calls: BTreeMap<(Option<u16>, u16), u64>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    // An instruction at pc ran, and next_pc is where the PC is now
+    pub fn record(&mut self, pc: u16, kind: InstrKind, next_pc: u16) {
+        self.total += 1;
+        *self.counts.entry(pc).or_insert(0) += 1;
+        match self.stack.last() {
+            None => self.top_level += 1,
+            Some(frame) => self.subroutines.entry(frame.entry).or_default().exclusive += 1,
+        }
+
+        match kind {
+            InstrKind::Call => {
+                let caller = self.stack.last().map(|f| f.entry);
+                *self.calls.entry((caller, next_pc)).or_insert(0) += 1;
+                self.subroutines.entry(next_pc).or_default().calls += 1;
+                self.stack.push(Frame { entry: next_pc, instrs_at_call: self.total });
+            }
+            InstrKind::Ret => {
+                if let Some(frame) = self.stack.pop() {
+                    if !self.stack.iter().any(|f| f.entry == frame.entry) {
+                        self.subroutines.get_mut(&frame.entry).unwrap().inclusive +=
+                            self.total - frame.instrs_at_call;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Subroutines that haven't returned yet, with what they'd have
+    // if they returned now
+    fn finished_subroutines(&self) -> BTreeMap<u16, SubroutineProfile> {
+        let mut subroutines = self.subroutines.clone();
+        for (idx, frame) in self.stack.iter().enumerate() {
+            if !self.stack[..idx].iter().any(|f| f.entry == frame.entry) {
+                subroutines.get_mut(&frame.entry).unwrap().inclusive +=
+                    self.total - frame.instrs_at_call;
+            }
+        }
+        subroutines
+    }
+
+    // The hottest max_hot_spots addresses, the subroutines and who
+    // calls them. Labels come from info, if there is any.
+    pub fn report(&self, c8: &Chip8System, info: Option<&DebugInfo>,
+                  max_hot_spots: usize) -> String {
+        let name = |addr: u16| {
+            match info.and_then(|i| i.label_for_addr(addr)) {
+                None => format!("0x{:04x}", addr),
+                Some((label, start)) if start == addr => format!("0x{:04x} {}", addr, label),
+                Some((label, start)) => format!("0x{:04x} {}+0x{:x}", addr, label, addr - start),
+            }
+        };
+        let percent = |count: u64| match self.total {
+            0 => 0.0,
+            total => (count as f64) * 100.0 / (total as f64),
+        };
+
+        let mut lines = vec![format!("Profile of {} instructions", self.total), String::new(),
+                             "Hot spots:".to_string(),
+                             "     Count      %  Address".to_string()];
+        let mut hot_spots: Vec<(&u16, &u64)> = self.counts.iter().collect();
+        hot_spots.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (addr, count) in hot_spots.into_iter().take(max_hot_spots) {
+            let disassembly = match c8.decode_at(*addr) {
+                Err(_) => String::new(),
+                Ok(instr) => c8.repr_instr(instr),
+            };
+            lines.push(format!("{:10} {:5.1}%  {}  {}",
+                               count, percent(*count), name(*addr), disassembly));
+        }
+
+        lines.push(String::new());
+        lines.push("Subroutines:".to_string());
+        lines.push("     Calls  Inclusive      %  Exclusive      %  Address".to_string());
+        lines.push(format!("{:>10} {:10} {:5.1}% {:10} {:5.1}%  <top level>",
+                           "-", self.total, 100.0, self.top_level, percent(self.top_level)));
+        let mut subroutines: Vec<(u16, SubroutineProfile)> =
+            self.finished_subroutines().into_iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        for (addr, sub) in subroutines {
+            lines.push(format!("{:10} {:10} {:5.1}% {:10} {:5.1}%  {}",
+                               sub.calls, sub.inclusive, percent(sub.inclusive),
+                               sub.exclusive, percent(sub.exclusive), name(addr)));
+        }
+
+        lines.push(String::new());
+        lines.push("Call graph:".to_string());
+        for (&(caller, callee), count) in &self.calls {
+            let caller = caller.map_or("<top level>".to_string(), &name);
+            lines.push(format!("  {} -> {}: {} calls", caller, name(callee), count));
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,63 @@
+// Different interpreters disagree on how some instructions behave.
+// Each flag here selects the less common behaviour when set,
+// so the default of all flags off is how rchip8 has always behaved.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing after the last register accessed
+    pub load_store_increments_i: bool,
+    // 8XY1/8XY2/8XY3 set VF to 0
+    pub logic_resets_vf: bool,
+    // Sprites are clipped at the edges of the screen instead of wrapping
+    pub clip_sprites: bool,
+    // BNNN is BXNN, jumping to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+}
+
+pub const QUIRKS_PRESET_NAMES: [&str; 4] = ["default", "vip", "chip48", "schip"];
+
+impl Quirks {
+    // The original interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            jump_uses_vx: false,
+        }
+    }
+
+    // CHIP-48 on the HP48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            jump_uses_vx: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            jump_uses_vx: true,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "default" => Some(Quirks::default()),
+            "vip"     => Some(Quirks::cosmac_vip()),
+            "chip48"  => Some(Quirks::chip48()),
+            "schip"   => Some(Quirks::superchip()),
+            _ => None,
+        }
+    }
+}
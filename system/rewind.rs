@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+
+// Keeps the most recent save states so the system can be stepped back in time.
+// Only the newest state is stored whole. Each older one is stored as the
+// difference from the state after it, which is small since most of memory
+// never changes between snapshots.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // Oldest first, applying the back one to latest gives the previous state
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        if capacity == 0 {
+            panic!("Rewind buffer capacity must be at least 1!");
+        }
+        RewindBuffer { capacity, latest: None, deltas: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.latest {
+            None => 0,
+            Some(_) => self.deltas.len() + 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // Once full the oldest state is dropped
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(make_delta(&state, &previous));
+            if self.deltas.len() >= self.capacity {
+                let _ = self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    // Newest state first
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take();
+        if let Some(ref newer) = state {
+            if let Some(delta) = self.deltas.pop_back() {
+                self.latest = Some(apply_delta(newer, &delta));
+            }
+        }
+        state
+    }
+
+    // The newest state that isn't current. The newest snapshot is often
+    // the state the system is in now, so restoring it wouldn't go back.
+    pub fn pop_before(&mut self, current: &[u8]) -> Option<Vec<u8>> {
+        loop {
+            let state = self.pop()?;
+            if state != current {
+                return Some(state)
+            }
+        }
+    }
+}
+
+// A delta is the older state's length then runs of:
+// u16 count of unchanged bytes, u16 count of changed bytes, the changed bytes XORed
+// States can be different lengths, missing bytes are treated as 0.
+fn make_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let get = |data: &[u8], idx: usize| if idx < data.len() { data[idx] } else { 0 };
+    let mut delta = vec![];
+    push_u32(&mut delta, older.len() as u32);
+
+    let mut idx = 0;
+    while idx < older.len() {
+        let same_start = idx;
+        while (idx < older.len()) && ((idx - same_start) < 0xFFFF) &&
+              (get(newer, idx) == older[idx]) {
+            idx += 1;
+        }
+        let diff_start = idx;
+        while (idx < older.len()) && ((idx - diff_start) < 0xFFFF) &&
+              (get(newer, idx) != older[idx]) {
+            idx += 1;
+        }
+
+        push_u16(&mut delta, (diff_start - same_start) as u16);
+        push_u16(&mut delta, (idx - diff_start) as u16);
+        for (i, old) in older.iter().enumerate().take(idx).skip(diff_start) {
+            delta.push(get(newer, i) ^ *old);
+        }
+    }
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let read_u16 = |pos: usize| (usize::from(delta[pos]) << 8) | usize::from(delta[pos+1]);
+    let older_len = (read_u16(0) << 16) | read_u16(2);
+    let mut older = newer.to_vec();
+    older.resize(older_len, 0);
+
+    let mut idx = 0;
+    let mut pos = 4;
+    while pos < delta.len() {
+        idx += read_u16(pos);
+        let num_diff = read_u16(pos+2);
+        pos += 4;
+        for b in &delta[pos..pos+num_diff] {
+            older[idx] ^= *b;
+            idx += 1;
+        }
+        pos += num_diff;
+    }
+    older
+}
+
+fn push_u16(data: &mut Vec<u8>, v: u16) {
+    data.push((v >> 8) as u8);
+    data.push(v as u8);
+}
+
+fn push_u32(data: &mut Vec<u8>, v: u32) {
+    push_u16(data, (v >> 16) as u16);
+    push_u16(data, v as u16);
+}
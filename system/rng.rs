@@ -0,0 +1,41 @@
+extern crate rand;
+use system::rng::rand::Rng;
+
+// A small xorshift generator for RND. Unlike thread_rng, its state
+// is all ours so it can be saved, restored and seeded.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Chip8Rng {
+    state: u64,
+}
+
+impl Chip8Rng {
+    pub fn from_entropy() -> Chip8Rng {
+        Chip8Rng::from_state(rand::thread_rng().gen::<u64>())
+    }
+
+    // Nearby seeds give nearby states, which xorshift takes a while
+    // to get away from. So mix the seed up first with splitmix64.
+    pub fn from_seed(seed: u64) -> Chip8Rng {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Chip8Rng::from_state(z ^ (z >> 31))
+    }
+
+    pub fn from_state(state: u64) -> Chip8Rng {
+        // Xorshift gets stuck at 0 so swap in any other value
+        Chip8Rng { state: if state == 0 { 0x853C_49E6_748F_EA9B } else { state } }
+    }
+
+    pub fn get_state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        // xorshift64*, the top bits are the best quality
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
@@ -0,0 +1,149 @@
+use system::quirks::Quirks;
+use system::sha1::sha1_hex;
+use std::collections::HashMap;
+
+// The ROMs rchip8 knows about out of the box
+const BUILT_IN_DATABASE: &str = include_str!("romdb.txt");
+
+pub const PLATFORM_NAMES: [&str; 4] = ["chip8", "chip48", "schip", "xochip"];
+
+// How to run a ROM
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct RomInfo {
+    pub title: String,
+    // One of PLATFORM_NAMES
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    pub cycles_per_frame: Option<u32>,
+    // Chip8 keys moved from the default layout, to the name of the
+    // host key for them, e.g. (5, "Up")
+    pub keys: Vec<(u8, String)>,
+}
+
+impl RomInfo {
+    // The quirks given, otherwise the usual ones for the platform
+    pub fn get_quirks(&self) -> Option<Quirks> {
+        self.quirks.or_else(|| match self.platform.as_deref() {
+            Some("chip8") => Some(Quirks::cosmac_vip()),
+            Some("chip48") => Some(Quirks::chip48()),
+            Some("schip") => Some(Quirks::superchip()),
+            Some("xochip") => Some(Quirks::default()),
+            _ => None,
+        })
+    }
+}
+
+// The SHA-1 of the ROM file, as sha1sum would print it
+pub fn rom_hash(rom: &[u8]) -> String {
+    sha1_hex(rom)
+}
+
+// ROMs by the SHA-1 of their file. As a file:
+// [<sha1>]
+// title = <title>
+// platform = <one of PLATFORM_NAMES>
+// quirks = <one of QUIRKS_PRESET_NAMES>
+// cpf = <cycles per frame>
+// keys = <chip8 key>:<host key name> ...
+// Everything but the title is optional. Lines starting with '#' are comments.
+#[derive(Clone, Default, Debug)]
+pub struct RomDatabase {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    pub fn built_in() -> RomDatabase {
+        RomDatabase::parse(BUILT_IN_DATABASE, "<built in>").unwrap()
+    }
+
+    pub fn parse(text: &str, filename: &str) -> Result<RomDatabase, String> {
+        let mut db = RomDatabase::default();
+        // With the line number of its [<sha1>] line
+        let mut current: Option<(String, RomInfo, usize)> = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let error = |msg: String| format!("{}:{}: error: {}", filename, idx+1, msg);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let hash = line[1..line.len()-1].to_lowercase();
+                if (hash.len() != 40) || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error(format!("Invalid SHA-1 \"{}\"", hash)))
+                }
+                db.add(current.take(), filename)?;
+                current = Some((hash, RomInfo::default(), idx+1));
+                continue
+            }
+
+            let info = match current {
+                None => return Err(error("Expected a [<sha1>] line first".to_string())),
+                Some((_, ref mut info, _)) => info,
+            };
+            let pos = line.find('=')
+                .ok_or_else(|| error("Expected <name> = <value>".to_string()))?;
+            let (name, value) = (line[..pos].trim(), line[pos+1..].trim());
+            match name {
+                "title" => info.title = value.to_string(),
+                "platform" => {
+                    if !PLATFORM_NAMES.contains(&value) {
+                        return Err(error(format!("Unknown platform \"{}\", expected one of: {}",
+                                                 value, PLATFORM_NAMES.join(", "))))
+                    }
+                    info.platform = Some(value.to_string());
+                }
+                "quirks" => {
+                    info.quirks = Some(Quirks::from_name(value).ok_or_else(|| {
+                        error(format!("Unknown quirks preset \"{}\"", value))
+                    })?);
+                }
+                "cpf" => {
+                    info.cycles_per_frame = match value.parse::<u32>() {
+                        Ok(cycles) if cycles > 0 => Some(cycles),
+                        _ => return Err(error(format!("Invalid cycles per frame \"{}\"", value))),
+                    };
+                }
+                "keys" => {
+                    for mapping in value.split_whitespace() {
+                        let key = mapping.find(':').and_then(|pos| {
+                            u8::from_str_radix(&mapping[..pos], 16).ok()
+                                .filter(|k| *k < 16)
+                                .map(|k| (k, mapping[pos+1..].to_string()))
+                        });
+                        match key {
+                            None => return Err(error(format!("Invalid key mapping \"{}\", \
+                                                              expected <0-F>:<key>", mapping))),
+                            Some(key) => info.keys.push(key),
+                        }
+                    }
+                }
+                _ => return Err(error(format!("Unknown setting \"{}\"", name))),
+            }
+        }
+
+        db.add(current, filename)?;
+        Ok(db)
+    }
+
+    fn add(&mut self, rom: Option<(String, RomInfo, usize)>, filename: &str)
+           -> Result<(), String> {
+        if let Some((hash, info, line_no)) = rom {
+            if info.title.is_empty() {
+                return Err(format!("{}:{}: error: ROM {} has no title", filename, line_no, hash))
+            }
+            let _ = self.roms.insert(hash, info);
+        }
+        Ok(())
+    }
+
+    // Entries in other replace any for the same ROM
+    pub fn extend(&mut self, other: RomDatabase) {
+        self.roms.extend(other.roms);
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&rom_hash(rom))
+    }
+}
@@ -0,0 +1,245 @@
+use system::{Chip8System, MEMORY_SIZE, NUM_RPL_FLAGS, AUDIO_PATTERN_SIZE, NUM_PLANES,
+             STACK_SIZE};
+use system::quirks::Quirks;
+use system::rng::Chip8Rng;
+
+// Save state layout, all values big endian:
+// "RC8S", u16 version, then each field in the order written by save_state.
+// Bump the version whenever that order or any field's size changes.
+const STATE_MAGIC: &[u8; 4] = b"RC8S";
+pub const STATE_VERSION: u16 = 2;
+
+// Also used for other files the system writes, like movies
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&[(v >> 8) as u8, v as u8]);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.u16((v >> 16) as u16);
+        self.u16(v as u16);
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.u32((v >> 32) as u32);
+        self.u32(v as u32);
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+}
+
+pub struct StateReader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+    // What the data is, for error messages
+    pub name: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(format!("{} is truncated, expected {} bytes at offset {}",
+                               self.name, len, self.pos));
+        }
+        let ret = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(format!("Invalid boolean value {} at offset {}", v, self.pos-1)),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok((u16::from(b[0]) << 8) | u16::from(b[1]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok((u32::from(self.u16()?) << 16) | u32::from(self.u16()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok((u64::from(self.u32()?) << 32) | u64::from(self.u32()?))
+    }
+}
+
+pub fn write_quirks(w: &mut StateWriter, quirks: &Quirks) {
+    w.bool(quirks.shift_uses_vy);
+    w.bool(quirks.load_store_increments_i);
+    w.bool(quirks.logic_resets_vf);
+    w.bool(quirks.clip_sprites);
+    w.bool(quirks.jump_uses_vx);
+}
+
+pub fn read_quirks(r: &mut StateReader) -> Result<Quirks, String> {
+    Ok(Quirks {
+        shift_uses_vy: r.bool()?,
+        load_store_increments_i: r.bool()?,
+        logic_resets_vf: r.bool()?,
+        clip_sprites: r.bool()?,
+        jump_uses_vx: r.bool()?,
+    })
+}
+
+// Bit N is set if key N is pressed
+pub fn keys_to_mask(keys: &[bool; 16]) -> u16 {
+    let mut mask: u16 = 0;
+    for (idx, pressed) in keys.iter().enumerate() {
+        if *pressed {
+            mask |= 1 << idx;
+        }
+    }
+    mask
+}
+
+pub fn mask_to_keys(mask: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (idx, pressed) in keys.iter_mut().enumerate() {
+        *pressed = (mask & (1 << idx)) != 0;
+    }
+    keys
+}
+
+impl Chip8System {
+    // Everything needed to carry on exactly where the system left off
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter { data: vec![] };
+        w.bytes(STATE_MAGIC);
+        w.u16(STATE_VERSION);
+
+        w.u16(self.pc);
+        w.bytes(&self.memory);
+        w.bool(self.hires);
+        w.bytes(&self.screen);
+        w.u8(self.plane_mask);
+
+        w.u16(keys_to_mask(&self.keys));
+        w.u8(self.pressed_key as u8);
+
+        w.bytes(&self.v_regs);
+        w.u16(self.i_reg);
+        w.u8(self.stack.len() as u8);
+        for addr in self.stack.iter() {
+            w.u16(*addr);
+        }
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+
+        w.bytes(&self.rpl_flags);
+        match self.audio_pattern {
+            None => w.bool(false),
+            Some(pattern) => {
+                w.bool(true);
+                w.bytes(&pattern);
+            }
+        }
+        w.u8(self.pitch);
+        w.bool(self.exited);
+
+        write_quirks(&mut w, &self.quirks);
+        w.u64(self.rng.get_state());
+
+        w.u32(self.cycles_per_frame);
+        w.u32(self.frame_cycles);
+        w.u64(self.frames);
+        w.u64(self.cycles);
+
+        w.data
+    }
+
+    // The system is only modified if the whole state is valid
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader { data, pos: 0, name: "Save state" };
+        if r.bytes(STATE_MAGIC.len()).ok() != Some(&STATE_MAGIC[..]) {
+            return Err("Not a save state file".to_string());
+        }
+        let version = r.u16()?;
+        if version != STATE_VERSION {
+            return Err(format!("Unsupported save state version {}, expected {}",
+                               version, STATE_VERSION));
+        }
+
+        let mut c8 = Chip8System::new(Quirks::default());
+        c8.pc = r.u16()?;
+        c8.memory.clone_from_slice(r.bytes(MEMORY_SIZE)?);
+        c8.set_hires(r.bool()?);
+        let screen_size = c8.screen.len();
+        c8.screen = r.bytes(screen_size)?.to_vec();
+        c8.plane_mask = r.u8()?;
+        if c8.plane_mask >= (1 << NUM_PLANES) {
+            return Err(format!("Save state has invalid plane mask 0x{:X}", c8.plane_mask));
+        }
+
+        c8.keys = mask_to_keys(r.u16()?);
+        c8.pressed_key = r.u8()? as usize;
+
+        c8.v_regs.clone_from_slice(r.bytes(16)?);
+        c8.i_reg = r.u16()?;
+        let stack_len = r.u8()?;
+        if usize::from(stack_len) > STACK_SIZE {
+            return Err(format!("Save state stack has {} entries, the most is {}",
+                               stack_len, STACK_SIZE));
+        }
+        for _ in 0..stack_len {
+            c8.stack.push(r.u16()?);
+        }
+        c8.delay_timer = r.u8()?;
+        c8.sound_timer = r.u8()?;
+
+        c8.rpl_flags.clone_from_slice(r.bytes(NUM_RPL_FLAGS)?);
+        if r.bool()? {
+            let mut pattern = [0; AUDIO_PATTERN_SIZE];
+            pattern.clone_from_slice(r.bytes(AUDIO_PATTERN_SIZE)?);
+            c8.audio_pattern = Some(pattern);
+        }
+        c8.pitch = r.u8()?;
+        c8.exited = r.bool()?;
+
+        c8.quirks = read_quirks(&mut r)?;
+        c8.rng = Chip8Rng::from_state(r.u64()?);
+
+        c8.cycles_per_frame = r.u32()?;
+        if c8.cycles_per_frame == 0 {
+            return Err("Save state has 0 cycles per frame".to_string());
+        }
+        c8.frame_cycles = r.u32()?;
+        c8.frames = r.u64()?;
+        c8.cycles = r.u64()?;
+
+        if r.pos != data.len() {
+            return Err(format!("Save state has {} unexpected trailing bytes",
+                               data.len() - r.pos));
+        }
+
+        // Tracing, profiling and coverage aren't part of the state, they carry on
+        c8.tracer = self.tracer.take();
+        c8.profiler = self.profiler.take();
+        c8.coverage = self.coverage.take();
+        *self = c8;
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 #[cfg(test)]
 mod test {
     use system::*;
+    use system::quirks::Quirks;
     use std::path::PathBuf;
-    use asm::parse_asm_str;
+    use asm::{parse_asm_str, parse_asm_with_info};
     use std::collections::HashSet;
     extern crate rand;
     use system::test::test::rand::{Rng, thread_rng};
@@ -14,7 +15,7 @@ mod test {
         let mut rom_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         rom_path.push("roms/BC_test.ch8");
         let rom_path_str = String::from(rom_path.to_str().expect("bad path!"));
-        let mut c8 = make_system(&read_rom(&rom_path_str));
+        let mut c8 = make_system(&read_rom(&rom_path_str), Quirks::default());
 
         let expected = "\
         ----------------------------------------------------------------\n\
@@ -50,14 +51,9 @@ mod test {
         --@@-----@-------@@----@@--@@----@@--@@@---@----@@---@@---@-@---\n\
         -------@@@------------------------------------------------------";
        
-        'running: loop {
-            let instr = c8.fetch_and_decode();
-            // Jump to self
-            if instr.get_opcode() == 0x130e {
-                break 'running;
-            }
-            c8.execute(&instr);
-        }
+        let limits = headless::RunLimits { until_self_jump: true, ..Default::default() };
+        assert_eq!(Ok(headless::StopReason::SelfJump), headless::run_headless(&mut c8, &limits, None));
+        assert_eq!(0x30e, c8.pc);
         let got = c8.screen_to_str();
         assert_eq!(expected, got);
     }
@@ -78,7 +74,7 @@ mod test {
             }
         }
 
-        let bases: [u16; 2] = [0x5000, 0x9000];
+        let bases: [u16; 4] = [0x5000, 0x5002, 0x5003, 0x9000];
         for base in bases.iter() {
             for n in 0..0x100_u16 {
                 instrs.push(base+(n << 4));
@@ -101,7 +97,8 @@ mod test {
             }
         }
 
-        let f_ends: [u16; 9] = [0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55, 0x65];
+        let f_ends: [u16; 13] = [0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x30, 0x33, 0x3A, 0x55,
+                                 0x65, 0x75, 0x85];
         for end in f_ends.iter() {
             let base = 0xF000 | *end;
             for n in 0..0x10_u16 {
@@ -109,6 +106,9 @@ mod test {
             }
         }
 
+        // XO-CHIP long load, audio and planes 0-3
+        instrs.extend_from_slice(&[0xF000, 0xF002, 0xF001, 0xF101, 0xF201, 0xF301]);
+
         instrs
     }
 
@@ -116,7 +116,7 @@ mod test {
     fn do_all_valid_chip8_instr() {
         let instrs = all_valid_chip8_instrs();
         let dummy: Vec<u8> = vec![];
-        let mut c8 = make_system(&dummy);
+        let mut c8 = make_system(&dummy, Quirks::default());
 
         // Execute all instructions at 0x200, reset in between
         // So that something like JP to self doesn't hold up
@@ -125,10 +125,10 @@ mod test {
             // This allows us to do a ret without having a corresponding call
             c8.stack.push(0x200);
 
-            let decode = c8.get_opcode_obj(*i);
+            let decode = c8.decode(*i);
             match decode {
-                Err(msg)  => panic!(msg),
-                Ok(instr) => c8.execute(&instr),
+                Err(e)    => panic!("{}", e),
+                Ok(instr) => c8.execute(&instr).unwrap(),
             }
 
             // Prevent trying to call with a full stack etc.
@@ -143,17 +143,38 @@ mod test {
         let invalid_instrs = all_encodings.difference(&all_instrs);
 
         let dummy: Vec<u8> = vec![];
-        let c8 = make_system(&dummy);
+        let c8 = make_system(&dummy, Quirks::default());
         for i in invalid_instrs {
             // Skip BRK
             if *i != 0x0FFF {
                 println!("{:04x}", *i);
-                let decode = c8.get_opcode_obj(*i);
+                let decode = c8.decode(*i);
                 assert!(decode.is_err());
             }
         }
     }
 
+    #[test]
+    fn instruction_matches_instr_view() {
+        // Running a decoded Instruction and its Instr view should do the same thing
+        let rom: Vec<u8> = vec![0x12, 0x34];
+        for i in all_valid_chip8_instrs() {
+            let mut c8 = make_system(&rom, Quirks::default());
+            c8.stack.push(0x200);
+            c8.pc = 0x202;
+            let instr = c8.decode(i).unwrap();
+            let view = instr.to_instr();
+            assert_eq!(i, view.get_opcode());
+            assert!(instr.get_flags() == view.get_flags());
+
+            let mut view_c8 = make_system(&rom, Quirks::default());
+            view_c8.load_state(&c8.save_state()).unwrap();
+            let result = instr.exec(&mut c8);
+            assert_eq!(result, view.exec(&mut view_c8));
+            assert!(c8.save_state() == view_c8.save_state(), "0x{:04x}", i);
+        }
+    }
+
     fn setup_max_gp_regs(c8: &mut Chip8System) {
         for r in c8.v_regs.iter_mut() {
             *r = <u8>::max_value();
@@ -166,17 +187,17 @@ mod test {
         let valid_instrs = all_valid_chip8_instrs();
 
         let dummy: Vec<u8> = vec![];
-        let mut c8 = make_system(&dummy);
+        let mut c8 = make_system(&dummy, Quirks::default());
 
         for i in valid_instrs {
             c8.reset_regs();
             setup_max_gp_regs(&mut c8);
             c8.stack.push(0x200);
 
-            let instr = c8.get_opcode_obj(i).unwrap();
+            let instr = c8.decode(i).unwrap();
             // Again we need to handle key index > 16 somehow, just not now
             if instr.get_flags() != InstrFlags::Keys {
-                c8.execute(&instr);
+                c8.execute(&instr).unwrap();
             }
         }
     }
@@ -185,12 +206,12 @@ mod test {
         // CALL 0x200
         // call to self so we only need one
         let instrs: Vec<u8> = vec![0x22, 0x00];
-        let mut c8 = make_system(&instrs);
+        let mut c8 = make_system(&instrs, Quirks::default());
 
         // 1 off of the limit
         for _ in 0..16 {
-            let ins = c8.fetch_and_decode();
-            c8.execute(&ins);
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
         }
         c8
     }
@@ -210,40 +231,49 @@ mod test {
         c8.memory[pc] = 0x00; c8.memory[pc+1] = 0xEE;
 
         for _ in 0..16 {
-            let ins = c8.fetch_and_decode();
-            c8.execute(&ins);
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
         }
     }
 
     #[test]
-    #[should_panic(expected = "Stack is full!")]
-    fn stack_nested_calls_panics() {
+    fn stack_nested_calls_error() {
         let mut c8 = setup_nested_call_test();
         // do the last CALL
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        assert_eq!(Err(EmulatorError::StackOverflow), c8.execute(&ins));
     }
 
     #[test]
-    #[should_panic(expected = "Stack is empty!")]
-    fn ret_empty_stack_panics() {
+    fn ret_empty_stack_error() {
         // RET
         let instrs: Vec<u8> = vec![0x00, 0xEE];
-        let mut c8 = make_system(&instrs);
+        let mut c8 = make_system(&instrs, Quirks::default());
 
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        assert_eq!(Err(EmulatorError::StackUnderflow), c8.execute(&ins));
     }
 
     #[test]
-    #[should_panic(expected="BRK instruction encountered at PC 0x0200")]
-    fn panic_on_brk() {
+    fn error_on_brk() {
         // BRK aka SYS 0xFFF
         let instrs: Vec<u8> =vec![0x0F, 0xFF];
-        let mut c8 = make_system(&instrs);
+        let mut c8 = make_system(&instrs, Quirks::default());
 
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let err = c8.fetch_and_decode().err().unwrap();
+        assert_eq!(EmulatorError::Breakpoint { pc: 0x200 }, err);
+        assert_eq!("BRK instruction encountered at PC 0x0200", err.to_string());
+    }
+
+    #[test]
+    fn error_on_unknown_instr() {
+        let instrs: Vec<u8> =vec![0x00, 0xE0, 0x80, 0x08];
+        let mut c8 = make_system(&instrs, Quirks::default());
+        c8.pc = 0x202;
+
+        let err = c8.fetch_and_decode().err().unwrap();
+        assert_eq!(EmulatorError::UnknownInstruction { opcode: 0x8008, pc: 0x202 }, err);
+        assert_eq!("Unknown instruction 0x8008 at PC 0x0202", err.to_string());
     }
 
     #[test]
@@ -251,17 +281,17 @@ mod test {
         // Key numbers 0-15 should not panic
 
         let dummy: Vec<u8> = vec![];
-        let mut c8 = make_system(&dummy);
+        let mut c8 = make_system(&dummy, Quirks::default());
         // Both using V0
         let instrs: Vec<u16> = vec![0xE09E, 0xE0A1];
 
         for key in 0..16_u8 {
             c8.v_regs[0] = key;
             for instr in instrs.iter() {
-                let decode = c8.get_opcode_obj(*instr);
+                let decode = c8.decode(*instr);
                 match decode {
-                    Err(msg)  => panic!(msg),
-                    Ok(instr) => c8.execute(&instr),
+                    Err(e)    => panic!("{}", e),
+                    Ok(instr) => c8.execute(&instr).unwrap(),
                 }
             }
         }
@@ -270,28 +300,28 @@ mod test {
     fn setup_invalid_key_test() -> Chip8System {
         //SKP V0; SKNP V0
         let rom: Vec<u8> = vec![0xE0, 0x9E, 0xE0, 0xA1];
-        make_system(&rom)
+        make_system(&rom, Quirks::default())
     }
 
     #[test]
-    #[should_panic(expected="Key number 16 out of range!")]
     fn invalid_key_index_if_pressed() {
-        // Key numbers >=16 should panic
+        // Key numbers >=16 are an error
         let mut c8 = setup_invalid_key_test();
         c8.v_regs[0] = 16; // TODO: check > 16 too
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        assert_eq!(Err(EmulatorError::InvalidKey(16)), c8.execute(&ins));
     }
 
     #[test]
-    #[should_panic(expected="Key number 16 out of range!")]
     fn invalid_key_index_if_not_pressed() {
-        // Key numbers >=16 should panic
+        // Key numbers >=16 are an error
         let mut c8 = setup_invalid_key_test();
         c8.v_regs[0] = 16; // TODO: check > 16 too
         c8.pc += 2; // Skip to if not instr
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        let err = c8.execute(&ins).err().unwrap();
+        assert_eq!(EmulatorError::InvalidKey(16), err);
+        assert_eq!("Key number 16 out of range!", err.to_string());
     }
 
     fn setup_out_of_bounds_i_reg() -> Chip8System {
@@ -300,35 +330,114 @@ mod test {
             0xF2, 0x55, // Store registers
             0xF2, 0x65, // Load registers
         ];
-        let mut c8 = make_system(&rom);
-        c8.i_reg = 0xFFFD;
+        let mut c8 = make_system(&rom, Quirks::default());
+        // Memory is 64K so this is just over the end
+        c8.i_reg = 0xFFFE;
         c8
     }
 
+    fn out_of_bounds_i_reg_error() -> Result<(), EmulatorError> {
+        Err(EmulatorError::MemoryOutOfBounds { addr: 0xfffe, length: 3 })
+    }
+
     #[test]
-    #[should_panic(expected="I register memory access at 0xfffd with length 3 is out of bounds!")]
     fn out_of_bounds_i_reg_bcd() {
         let mut c8 = setup_out_of_bounds_i_reg();
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        let result = c8.execute(&ins);
+        assert_eq!(out_of_bounds_i_reg_error(), result);
+        assert_eq!("I register memory access at 0xfffe with length 3 is out of bounds!",
+                   result.err().unwrap().to_string());
     }
 
     #[test]
-    #[should_panic(expected="I register memory access at 0xfffd with length 3 is out of bounds!")]
     fn out_of_bounds_i_reg_store_regs() {
         let mut c8 = setup_out_of_bounds_i_reg();
         c8.pc = 0x202;
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        assert_eq!(out_of_bounds_i_reg_error(), c8.execute(&ins));
     }
 
     #[test]
-    #[should_panic(expected="I register memory access at 0xfffd with length 3 is out of bounds!")]
     fn out_of_bounds_i_reg_load_regs() {
         let mut c8 = setup_out_of_bounds_i_reg();
         c8.pc = 0x204;
-        let ins = c8.fetch_and_decode();
-        c8.execute(&ins);
+        let ins = c8.fetch_and_decode().unwrap();
+        assert_eq!(out_of_bounds_i_reg_error(), c8.execute(&ins));
+    }
+
+    fn setup_timer_test(cycles_per_frame: u32) -> Chip8System {
+        // LD V0, 0x05; LD DT, V0; LD ST, V0; JP self
+        let rom: Vec<u8> = vec![0x60, 0x05, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06];
+        let mut c8 = make_system(&rom, Quirks::default());
+        c8.set_cycles_per_frame(cycles_per_frame);
+        c8
+    }
+
+    fn run_cycles(c8: &mut Chip8System, cycles: u32) {
+        for _ in 0..cycles {
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
+        }
+    }
+
+    #[test]
+    fn timers_count_down_once_per_frame() {
+        let mut c8 = setup_timer_test(10);
+
+        // Setup instructions are part of the first frame
+        run_cycles(&mut c8, 3);
+        assert_eq!(5, c8.delay_timer);
+        assert_eq!(5, c8.sound_timer);
+
+        run_cycles(&mut c8, 6);
+        assert_eq!(0, c8.get_frame_count());
+        assert_eq!(5, c8.delay_timer);
+
+        run_cycles(&mut c8, 1);
+        assert_eq!(1, c8.get_frame_count());
+        assert_eq!(4, c8.delay_timer);
+        assert_eq!(4, c8.sound_timer);
+
+        // Timers stop at 0
+        run_cycles(&mut c8, 10*10);
+        assert_eq!(11, c8.get_frame_count());
+        assert_eq!(0, c8.delay_timer);
+        assert_eq!(0, c8.sound_timer);
+    }
+
+    #[test]
+    fn timers_independent_of_other_systems() {
+        let mut fast = setup_timer_test(20);
+        let mut slow = setup_timer_test(5);
+
+        for _ in 0..40 {
+            run_cycles(&mut fast, 1);
+            run_cycles(&mut slow, 1);
+        }
+
+        assert_eq!(2, fast.get_frame_count());
+        assert_eq!(3, fast.delay_timer);
+        assert_eq!(8, slow.get_frame_count());
+        assert_eq!(0, slow.delay_timer);
+    }
+
+    #[test]
+    fn cycle_count_kept_when_speed_changes() {
+        let mut c8 = setup_timer_test(10);
+        run_cycles(&mut c8, 25);
+        assert_eq!(25, c8.get_cycle_count());
+
+        c8.set_cycles_per_frame(3);
+        assert_eq!(25, c8.get_cycle_count());
+        run_cycles(&mut c8, 4);
+        assert_eq!(29, c8.get_cycle_count());
+    }
+
+    #[test]
+    #[should_panic(expected="Cycles per frame must be at least 1!")]
+    fn zero_cycles_per_frame_panics() {
+        let _ = setup_timer_test(0);
     }
 
     static PROG_EXPECTED: &'static str = "\
@@ -400,11 +509,11 @@ mod test {
         instrs.push(Box::new(JumpInstr::create(target)));
 
         let rom = instrs_to_rom(&instrs);
-        let mut c8 = make_system(&rom);
+        let mut c8 = make_system(&rom, Quirks::default());
 
         while c8.pc != target {
-            let ins = c8.fetch_and_decode();
-            c8.execute(&ins);
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
         }
 
         assert_eq!(PROG_EXPECTED, c8.screen_to_str());
@@ -436,11 +545,11 @@ mod test {
         let rom = instrs_to_rom(&instrs);
 
         let target: u16 = 0x214;
-        let mut c8 = make_system(&rom);
+        let mut c8 = make_system(&rom, Quirks::default());
 
         while c8.pc != target {
-            let ins = c8.fetch_and_decode();
-            c8.execute(&ins);
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
         }
 
         assert_eq!(PROG_EXPECTED, c8.screen_to_str());
@@ -465,9 +574,10 @@ mod test {
             (Box::new(XORRegInstr::create(13, 2))             as Box<dyn Instr>, "XOR V13, V2"),
             (Box::new(AddRegInstr::create(7, 14))             as Box<dyn Instr>, "ADD V7, V14"),
             (Box::new(SubRegInstr::create(6, 13))             as Box<dyn Instr>, "SUB V6, V13"),
-            (Box::new(ShrRegInstr::create(5))                 as Box<dyn Instr>, "SHR V5"),
+            (Box::new(ShrRegInstr::create(5, 0))              as Box<dyn Instr>, "SHR V5"),
             (Box::new(SubNRegInstr::create(2, 9))             as Box<dyn Instr>, "SUBN V2, V9"),
-            (Box::new(ShlRegInstr::create(11))                as Box<dyn Instr>, "SHL V11"),
+            (Box::new(ShlRegInstr::create(11, 0))             as Box<dyn Instr>, "SHL V11"),
+            (Box::new(ShlRegInstr::create(3, 4))              as Box<dyn Instr>, "SHL V3, V4"),
             (Box::new(SkipIfRegsNotEqualInstr::create(10, 3)) as Box<dyn Instr>, "SNE V10, V3"),
             (Box::new(LoadIInstr::create(0x847))              as Box<dyn Instr>, "LD I, 0x847"),
             (Box::new(JumpPlusVZeroInstr::create(0x734))      as Box<dyn Instr>, "JP V0, 0x734"),
@@ -484,6 +594,22 @@ mod test {
             (Box::new(StoreBCDInstr::create(6))               as Box<dyn Instr>, "LD B, V6"),
             (Box::new(WriteRegsToMemInstr::create(15))        as Box<dyn Instr>, "LD [I], V15"),
             (Box::new(ReadRegsFromMemInstr::create(2))        as Box<dyn Instr>, "LD V2, [I]"),
+            (Box::new(ScrollDownInstr::create(4))             as Box<dyn Instr>, "SCD 4"),
+            (Box::new(ScrollRightInstr::create())             as Box<dyn Instr>, "SCR"),
+            (Box::new(ScrollLeftInstr::create())              as Box<dyn Instr>, "SCL"),
+            (Box::new(ExitInstr::create())                    as Box<dyn Instr>, "EXIT"),
+            (Box::new(LowResInstr::create())                  as Box<dyn Instr>, "LOW"),
+            (Box::new(HighResInstr::create())                 as Box<dyn Instr>, "HIGH"),
+            (Box::new(GetBigDigitAddrInstr::create(3))        as Box<dyn Instr>, "LD HF, V3"),
+            (Box::new(WriteRegsToRplInstr::create(7))         as Box<dyn Instr>, "LD R, V7"),
+            (Box::new(ReadRegsFromRplInstr::create(5))        as Box<dyn Instr>, "LD V5, R"),
+            (Box::new(ScrollUpInstr::create(9))               as Box<dyn Instr>, "SCU 9"),
+            (Box::new(SaveRegRangeInstr::create(2, 6))        as Box<dyn Instr>, "SAVE V2, V6"),
+            (Box::new(LoadRegRangeInstr::create(6, 2))        as Box<dyn Instr>, "LOAD V6, V2"),
+            (Box::new(LongLoadIInstr::create(0xABCD))         as Box<dyn Instr>, "LDL I, 0xABCD"),
+            (Box::new(PlaneInstr::create(3))                  as Box<dyn Instr>, "PLANE 3"),
+            (Box::new(AudioInstr::create())                   as Box<dyn Instr>, "AUDIO"),
+            (Box::new(SetPitchInstr::create(4))               as Box<dyn Instr>, "LD PITCH, V4"),
         ];
 
         for (ins, expected) in data.iter() {
@@ -497,10 +623,302 @@ mod test {
     #[should_panic(expected="Cannot get address for unresolved symbol \"xyz\"")]
     fn exec_on_unresolved_symbol_panics() {
         let dummy: Vec<u8> = vec![];
-        let mut c8 = make_system(&dummy);
+        let mut c8 = make_system(&dummy, Quirks::default());
 
         let ins = Box::new(SysInstr::create_with_symbol("xyz".to_string())) as Box<dyn Instr>;
-        c8.execute(&ins);
+        let _ = ins.exec(&mut c8);
+    }
+
+    fn run_asm_until_self_jump(asm: &str) -> Chip8System {
+        run_asm_with_quirks(asm, Quirks::default())
+    }
+
+    fn run_asm_with_quirks(asm: &str, quirks: Quirks) -> Chip8System {
+        let instrs = parse_asm_str(asm).unwrap();
+        let rom = instrs_to_rom(&instrs);
+        let mut c8 = make_system(&rom, quirks);
+        let mut old_pc: u16 = 0xffff;
+
+        while c8.pc != old_pc {
+            old_pc = c8.pc;
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
+        }
+        c8
+    }
+
+    #[test]
+    fn hires_mode_switching() {
+        let mut c8 = run_asm_until_self_jump("\
+            LD F, V0
+            DRW V0, V0, 5
+            HIGH
+        end:
+            JP end");
+        assert_eq!(128, c8.get_screen_width());
+        assert_eq!(64, c8.get_screen_height());
+        // Switching mode clears the screen
+        let hires_blank = vec!["-".repeat(128); 64].join("\n");
+        assert_eq!(hires_blank, c8.screen_to_str());
+
+        // Drawing uses the full hires screen
+        c8.v_regs[0] = 120;
+        c8.v_regs[1] = 60;
+        c8.i_reg = 0x300;
+        c8.memory[0x300] = 0xFF;
+        let ins = DrawSpriteInstr::create(0, 1, 1);
+        ins.exec(&mut c8).unwrap();
+        let mut expected = vec!["-".repeat(128); 64];
+        expected[60] = "-".repeat(120) + "@@@@@@@@";
+        assert_eq!(expected.join("\n"), c8.screen_to_str());
+
+        let ins = LowResInstr::create();
+        ins.exec(&mut c8).unwrap();
+        assert_eq!(vec!["-".repeat(64); 32].join("\n"), c8.screen_to_str());
+    }
+
+    #[test]
+    fn draw_16x16_sprite() {
+        let c8 = run_asm_until_self_jump("\
+            JP start
+        sprite:
+            .word 0xFFFF
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0x8001
+            .word 0xFFFF
+        start:
+            LD I, sprite
+            LD V0, 56 // Wraps around the right edge
+            LD V1, 2
+            DRW V0, V1, 0
+        end:
+            JP end");
+
+        let mut expected = vec!["-".repeat(64); 32];
+        expected[2] = "@".repeat(8) + &"-".repeat(48) + &"@".repeat(8);
+        for row in expected.iter_mut().skip(3).take(14) {
+            *row = "-------@".to_string() + &"-".repeat(48) + "@-------";
+        }
+        expected[17] = expected[2].clone();
+        assert_eq!(expected.join("\n"), c8.screen_to_str());
+        assert_eq!(0, c8.v_regs[15]);
+    }
+
+    #[test]
+    fn scroll_screen() {
+        let mut c8 = run_asm_until_self_jump("\
+            LD V0, 1
+            LD F, V0
+            LD V1, 8
+            DRW V1, V1, 5
+        end:
+            JP end");
+        let one = c8.screen_to_str();
+
+        c8.scroll(0, 2);
+        let mut expected: Vec<&str> = one.lines().collect();
+        for _ in 0..2 {
+            let _ = expected.pop();
+            let first = expected[0];
+            expected.insert(0, first);
+        }
+        // Row 0 was blank so inserting it again is fine
+        let down_two = expected.join("\n");
+        assert_eq!(down_two, c8.screen_to_str());
+
+        c8.scroll(4, 0);
+        let right_four = down_two.lines().map(
+            |l| "----".to_string() + &l[..60]).join("\n");
+        assert_eq!(right_four, c8.screen_to_str());
+
+        c8.scroll(-4, 0);
+        let left_four = right_four.lines().map(
+            |l| l[4..].to_string() + "----").join("\n");
+        assert_eq!(left_four, c8.screen_to_str());
+        assert_eq!(down_two, c8.screen_to_str());
+    }
+
+    #[test]
+    fn big_digit_addr() {
+        let c8 = run_asm_until_self_jump("\
+            LD V3, 7
+            LD HF, V3
+        end:
+            JP end");
+        assert_eq!(0x50 + (7*10), c8.i_reg);
+        assert_eq!([0xFF, 0xFF, 0x03], c8.memory[c8.i_reg as usize..(c8.i_reg as usize)+3]);
+    }
+
+    #[test]
+    fn rpl_flags_round_trip() {
+        let c8 = run_asm_until_self_jump("\
+            LD V0, 0x12
+            LD V1, 0x34
+            LD V2, 0x56
+            LD R, V1
+            LD V0, 0
+            LD V1, 0
+            LD V2, 0
+            LD V2, R
+        end:
+            JP end");
+        assert_eq!([0x12, 0x34, 0, 0], c8.rpl_flags[..4]);
+        assert_eq!([0x12, 0x34, 0], c8.v_regs[..3]);
+    }
+
+    #[test]
+    fn exit_instr() {
+        // EXIT; CLS
+        let rom: Vec<u8> = vec![0x00, 0xFD, 0x00, 0xE0];
+        let mut c8 = make_system(&rom, Quirks::default());
+        assert!(!c8.has_exited());
+
+        for _ in 0..3 {
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
+            assert!(c8.has_exited());
+            // Never goes past the exit
+            assert_eq!(0x200, c8.pc);
+        }
+
+        // At the end of memory the PC has wrapped round to 0
+        c8.memory[0xFFFE] = 0x00;
+        c8.memory[0xFFFF] = 0xFD;
+        c8.pc = 0xFFFE;
+        let ins = c8.fetch_and_decode().unwrap();
+        c8.execute(&ins).unwrap();
+        assert_eq!(0xFFFE, c8.pc);
+    }
+
+    #[test]
+    fn long_load_i() {
+        let c8 = run_asm_until_self_jump("\
+            LDL I, 0xFFF0
+            LD V0, 0x12
+            LD [I], V0
+            LDL I, data
+            LD V1, [I]
+            // Skips have to step over all 4 bytes
+            SE V1, 0x56
+            LDL I, 0x1234
+        end:
+            JP end
+        data:
+            .word 0x3456");
+        assert_eq!(0x12, c8.memory[0xFFF0]);
+        assert_eq!(0x216, c8.i_reg);
+        assert_eq!([0x34, 0x56], c8.v_regs[..2]);
+    }
+
+    #[test]
+    fn save_and_load_reg_range() {
+        let c8 = run_asm_until_self_jump("\
+            LD V2, 0x22
+            LD V3, 0x33
+            LD V4, 0x44
+            LD I, data
+            SAVE V2, V4
+            LDL I, reversed
+            SAVE V4, V2
+            LD I, data
+            LOAD V7, V9
+        end:
+            JP end
+        data:
+            .word 0x0000
+            .word 0x0000
+        reversed:
+            .word 0x0000
+            .word 0x0000");
+        assert_eq!([0x22, 0x33, 0x44, 0x00, 0x44, 0x33, 0x22],
+                   c8.memory[0x216..0x21D]);
+        assert_eq!([0x22, 0x33, 0x44], c8.v_regs[7..10]);
+        // I is not incremented
+        assert_eq!(0x216, c8.i_reg);
+    }
+
+    #[test]
+    fn draw_to_planes() {
+        let mut c8 = run_asm_until_self_jump("\
+            LD I, data
+            PLANE 2
+            DRW V0, V0, 1
+            PLANE 3
+            LD V1, 8
+            DRW V1, V0, 1
+            // Plane 1 gets the first byte, plane 2 the second
+            LD V1, 16
+            DRW V1, V0, 1
+        end:
+            JP end
+        data:
+            .word 0xF00F");
+        assert_eq!(0, c8.v_regs[15]);
+        let mut expected = vec!["-".repeat(64); 32];
+        expected[0] = "++++----@@@@++++@@@@++++".to_string() + &"-".repeat(40);
+        assert_eq!(expected.join("\n"), c8.screen_to_str());
+
+        // Collisions are on any plane
+        c8.v_regs[1] = 8;
+        let ins = DrawSpriteInstr::create(1, 0, 1);
+        ins.exec(&mut c8).unwrap();
+        assert_eq!(1, c8.v_regs[15]);
+
+        // Only the selected plane is cleared
+        c8.plane_mask = 1;
+        let ins = ClearDisplayInstr::create();
+        ins.exec(&mut c8).unwrap();
+        expected[0] = "++++----------------++++".to_string() + &"-".repeat(40);
+        assert_eq!(expected.join("\n"), c8.screen_to_str());
+    }
+
+    #[test]
+    fn scroll_selected_planes() {
+        let mut c8 = make_system(&[], Quirks::default());
+        c8.screen[0] = 3;
+        c8.plane_mask = 2;
+        c8.scroll(0, 1);
+        c8.scroll(-4, 0);
+        c8.scroll(0, -1);
+        c8.scroll(4, 0);
+        assert_eq!(1, c8.screen[0]);
+        assert!(c8.screen[1..].iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch() {
+        let c8 = run_asm_until_self_jump("\
+            LD I, pattern
+            AUDIO
+            LD V0, 0x70
+            LD PITCH, V0
+        end:
+            JP end
+        pattern:
+            .word 0x0011
+            .word 0x2233
+            .word 0x4455
+            .word 0x6677
+            .word 0x8899
+            .word 0xAABB
+            .word 0xCCDD
+            .word 0xEEFF");
+        let expected = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+                        0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF];
+        assert_eq!(Some(expected), c8.get_audio_pattern());
+        assert_eq!(0x70, c8.get_pitch());
     }
 
     fn make_sprite_asm(sprite: &String) -> Vec<String> {
@@ -594,13 +1012,13 @@ mod test {
 
         let instrs = parse_asm_str(&asm).unwrap();
         let rom = instrs_to_rom(&instrs);
-        let mut c8 = make_system(&rom);
+        let mut c8 = make_system(&rom, Quirks::default());
         let mut old_pc: u16 = 0xffff;
 
         while c8.pc != old_pc {
             old_pc = c8.pc;
-            let ins = c8.fetch_and_decode();
-            c8.execute(&ins);
+            let ins = c8.fetch_and_decode().unwrap();
+            c8.execute(&ins).unwrap();
         }
 
         // Convert to a screen dump for comparison
@@ -619,12 +1037,756 @@ mod test {
         c8.i_reg = rng.gen::<u16>();
     }
 
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let asm = "\
+            LD V0, 0x10
+            LD V1, 0x81
+            SHR V0, V1
+            LD V2, 0x10
+            SHL V2, V1
+        end:
+            JP end";
+
+        let c8 = run_asm_until_self_jump(asm);
+        assert_eq!(0x08, c8.v_regs[0]);
+        assert_eq!(0x20, c8.v_regs[2]);
+        assert_eq!(0, c8.v_regs[15]);
+
+        let c8 = run_asm_with_quirks(asm, Quirks::cosmac_vip());
+        assert_eq!(0x40, c8.v_regs[0]);
+        assert_eq!(0x02, c8.v_regs[2]);
+        assert_eq!(1, c8.v_regs[15]);
+    }
+
+    #[test]
+    fn quirk_load_store_increments_i() {
+        let asm = "\
+            LD I, 0x300
+            LD [I], V2
+            LD V0, [I]
+        end:
+            JP end";
+
+        let c8 = run_asm_until_self_jump(asm);
+        assert_eq!(0x300, c8.i_reg);
+
+        let c8 = run_asm_with_quirks(asm, Quirks::chip48());
+        assert_eq!(0x304, c8.i_reg);
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let asm = "\
+            LD VF, 0x55
+            OR V0, V1
+            LD V2, VF
+            LD VF, 0x55
+            AND V0, V1
+            LD V3, VF
+            LD VF, 0x55
+            XOR V0, V1
+        end:
+            JP end";
+
+        let c8 = run_asm_until_self_jump(asm);
+        assert_eq!([0x55, 0x55, 0x55], [c8.v_regs[2], c8.v_regs[3], c8.v_regs[15]]);
+
+        let c8 = run_asm_with_quirks(asm, Quirks::cosmac_vip());
+        assert_eq!([0, 0, 0], [c8.v_regs[2], c8.v_regs[3], c8.v_regs[15]]);
+    }
+
+    #[test]
+    fn quirk_clip_sprites() {
+        // Draw a digit hanging off the bottom right corner
+        let asm = "\
+            LD V0, 62
+            LD V1, 30
+            LD V2, 8
+            LD F, V2
+            DRW V0, V1, 5
+        end:
+            JP end";
+
+        let c8 = run_asm_until_self_jump(asm);
+        let lit = c8.screen.iter().filter(|p| **p != 0).count();
+        // 8 is 16 pixels, all of which wrap onto the screen
+        assert_eq!(16, lit);
+        // Top left corner has the wrapped part
+        assert_eq!(1, c8.screen[0]);
+
+        let c8 = run_asm_with_quirks(asm, Quirks::superchip());
+        let lit = c8.screen.iter().filter(|p| **p != 0).count();
+        // Only the top two rows' left hand pixels are visible
+        assert_eq!(3, lit);
+        assert_eq!(0, c8.screen[0]);
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let dummy: Vec<u8> = vec![];
+        let jump: Box<dyn Instr> = Box::new(JumpPlusVZeroInstr::create(0x300));
+
+        for (quirks, expected) in [(Quirks::default(), 0x302),
+                                   (Quirks::chip48(), 0x304)].iter() {
+            let mut c8 = make_system(&dummy, *quirks);
+            c8.v_regs[0] = 2;
+            c8.v_regs[3] = 4;
+            jump.exec(&mut c8).unwrap();
+            assert_eq!(*expected, c8.pc);
+        }
+
+        // Disassembly shows the register that's really used
+        let instr = decode(0xB300).unwrap();
+        assert_eq!("JP V0, 0x300", make_system(&dummy, Quirks::default()).repr_instr(instr));
+        assert_eq!("JP V3, 0x300", make_system(&dummy, Quirks::chip48()).repr_instr(instr));
+    }
+
+    #[test]
+    fn seeded_random() {
+        let asm = "\
+            RND V0, 0xFF
+            RND V1, 0xFF
+            RND V2, 0xFF
+            RND V3, 0x0F";
+        let instrs = parse_asm_str(asm).unwrap();
+        let rom = instrs_to_rom(&instrs);
+        let run_with_seed = |seed| {
+            let mut c8 = make_system(&rom, Quirks::default());
+            c8.set_seed(seed);
+            run_cycles(&mut c8, 4);
+            c8.v_regs
+        };
+
+        let regs = run_with_seed(1234);
+        assert_eq!(regs, run_with_seed(1234));
+        assert!(regs[3] <= 0x0F);
+        // Even neighbouring seeds go their own way
+        assert_ne!(regs[0..3], run_with_seed(1235)[0..3]);
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let asm = "\
+            HIGH
+            LD V0, 0x12
+            LD I, 0x300
+            LD [I], V0
+            LD DT, V0
+            LD R, V0
+            PLANE 3
+            CALL sub
+        sub:
+            RND V1, 0xFF
+            RND V2, 0xFF
+            JP sub";
+
+        let instrs = parse_asm_str(asm).unwrap();
+        let rom = instrs_to_rom(&instrs);
+        let mut c8 = make_system(&rom, Quirks::chip48());
+        // Up to the first RND
+        run_cycles(&mut c8, 8);
+        c8.keys[3] = true;
+        let state = c8.save_state();
+
+        run_cycles(&mut c8, 2);
+        let randoms = (c8.v_regs[1], c8.v_regs[2]);
+
+        // Restoring into a fresh system gets the same random numbers
+        let mut restored = make_system(&[], Quirks::default());
+        restored.load_state(&state).unwrap();
+        assert_eq!(state, restored.save_state());
+        assert_eq!(0x210, restored.pc);
+        assert_eq!(128, restored.get_screen_width());
+        assert_eq!(Quirks::chip48(), restored.quirks);
+        assert_eq!(vec![0x210], restored.stack);
+        assert_eq!(0x12, restored.rpl_flags[0]);
+        assert!(restored.keys[3]);
+
+        run_cycles(&mut restored, 2);
+        assert_eq!(randoms, (restored.v_regs[1], restored.v_regs[2]));
+        assert_eq!(c8.save_state(), restored.save_state());
+    }
+
+    #[test]
+    fn load_invalid_state() {
+        let mut c8 = make_system(&[], Quirks::default());
+        c8.v_regs[0] = 0x34;
+        let mut state = c8.save_state();
+
+        assert_eq!(Err("Not a save state file".to_string()),
+                   c8.load_state(b"RC8"));
+
+        let mut bad_version = state.clone();
+        bad_version[5] = 99;
+        assert_eq!(Err("Unsupported save state version 99, expected 2".to_string()),
+                   c8.load_state(&bad_version));
+
+        let len = state.len();
+        assert!(c8.load_state(&state[..len-1]).unwrap_err().starts_with(
+                "Save state is truncated"));
+
+        state.push(0);
+        assert_eq!(Err("Save state has 1 unexpected trailing bytes".to_string()),
+                   c8.load_state(&state));
+
+        let mut deep = make_system(&[], Quirks::default());
+        deep.stack = vec![0x200; STACK_SIZE+1];
+        assert_eq!(Err("Save state stack has 17 entries, the most is 16".to_string()),
+                   c8.load_state(&deep.save_state()));
+
+        let mut planes = make_system(&[], Quirks::default());
+        planes.plane_mask = 4;
+        assert_eq!(Err("Save state has invalid plane mask 0x4".to_string()),
+                   c8.load_state(&planes.save_state()));
+
+        // Failed loads leave the system alone
+        assert_eq!(0x34, c8.v_regs[0]);
+    }
+
+    #[test]
+    fn rewind_buffer() {
+        let instrs = parse_asm_str("\
+            LD V0, 1
+        loop:
+            ADD V0, 1
+            LD I, 0x300
+            LD [I], V0
+            JP loop").unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+        let mut rewind = rewind::RewindBuffer::new(3);
+        assert!(rewind.pop().is_none());
+
+        let mut states = vec![];
+        for _ in 0..5 {
+            states.push(c8.save_state());
+            rewind.push(c8.save_state());
+            run_cycles(&mut c8, 4);
+        }
+        // Change the state's length too
+        c8.set_hires(true);
+        states.push(c8.save_state());
+        rewind.push(c8.save_state());
+        assert_eq!(3, rewind.len());
+
+        // Only the newest 3 are kept
+        for state in states.iter().rev().take(3) {
+            assert_eq!(Some(state.clone()), rewind.pop());
+        }
+        assert!(rewind.pop().is_none());
+        assert!(rewind.is_empty());
+
+        // Going back from the state just pushed skips over it
+        rewind.push(states[0].clone());
+        rewind.push(states[1].clone());
+        assert_eq!(Some(states[0].clone()), rewind.pop_before(&states[1]));
+        assert!(rewind.pop_before(&states[0]).is_none());
+    }
+
+    #[test]
+    fn headless_limits() {
+        use system::headless::{RunLimits, StopReason, run_headless};
+
+        let instrs = parse_asm_str("\
+            LD V0, 1
+        loop:
+            ADD V0, 1
+            SE V0, 50
+            JP loop
+            LD V1, K
+            EXIT").unwrap();
+        let rom = instrs_to_rom(&instrs);
+        let run = |limits: RunLimits| {
+            let mut c8 = make_system(&rom, Quirks::default());
+            let reason = run_headless(&mut c8, &limits, None).unwrap();
+            (reason, c8)
+        };
+
+        let (reason, c8) = run(RunLimits { max_instrs: Some(7), ..Default::default() });
+        assert_eq!(StopReason::MaxInstrs, reason);
+        assert_eq!(3, c8.v_regs[0]);
+
+        let (reason, c8) = run(RunLimits { max_frames: Some(2), ..Default::default() });
+        assert_eq!(StopReason::MaxFrames, reason);
+        assert_eq!(2, c8.get_frame_count());
+
+        let (reason, c8) = run(RunLimits { until_pc: Some(0x208), ..Default::default() });
+        assert_eq!(StopReason::ReachedPc, reason);
+        assert_eq!(50, c8.v_regs[0]);
+
+        // Nobody can press a key so the wait is not executed
+        let (reason, c8) = run(RunLimits { until_self_jump: true, ..Default::default() });
+        assert_eq!(StopReason::WaitKey, reason);
+        assert_eq!(0x208, c8.pc);
+    }
+
+    #[test]
+    fn headless_exit() {
+        let instrs = parse_asm_str("EXIT").unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+        let limits = headless::RunLimits { max_instrs: Some(10), ..Default::default() };
+        assert_eq!(Ok(headless::StopReason::Exited), headless::run_headless(&mut c8, &limits, None));
+    }
+
+    #[test]
+    fn headless_error() {
+        let instrs = parse_asm_str("RET").unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+        let limits = headless::RunLimits { max_instrs: Some(10), ..Default::default() };
+        assert_eq!(Err(EmulatorError::StackUnderflow), headless::run_headless(&mut c8, &limits, None));
+    }
+
+    #[test]
+    fn play_movie_headless() {
+        use system::headless::{run_headless, RunLimits, StopReason};
+        use system::movie::{Movie, MoviePlayer};
+
+        let instrs = parse_asm_str("\
+            LD V0, K
+            RND V1, 0xFF
+            LD V2, 7
+        wait:
+            SKP V2
+            JP wait
+        end:
+            JP end").unwrap();
+        let rom = instrs_to_rom(&instrs);
+
+        let mut movie = Movie::new(&make_system(&rom, Quirks::chip48()));
+        movie.record_wait_key(5);
+        for frame in 0..4 {
+            let mut keys = [false; 16];
+            keys[7] = frame == 3;
+            movie.record_frame(&keys);
+        }
+
+        // Files hold exactly the same movie
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(Quirks::chip48(), movie.quirks);
+
+        let play = |movie: &Movie| {
+            let mut c8 = movie.make_system(&rom);
+            let mut player = MoviePlayer::new(movie.clone());
+            let limits = RunLimits { until_self_jump: true, ..Default::default() };
+            let reason = run_headless(&mut c8, &limits, Some(&mut player)).unwrap();
+            (reason, c8)
+        };
+
+        let (reason, c8) = play(&movie);
+        assert_eq!(StopReason::SelfJump, reason);
+        assert_eq!(5, c8.v_regs[0]);
+        assert_eq!(3, c8.get_frame_count());
+        // Including the random number
+        let (_, again) = play(&movie);
+        assert_eq!(c8.save_state(), again.save_state());
+
+        // The key never gets pressed in a shorter movie
+        let mut short = movie.clone();
+        short.frames.truncate(2);
+        let (reason, c8) = play(&short);
+        assert_eq!(StopReason::MovieEnded, reason);
+        assert_eq!(2, c8.get_frame_count());
+
+        // Nor does the wait finish without a key for it
+        short.wait_keys.clear();
+        let (reason, c8) = play(&short);
+        assert_eq!(StopReason::WaitKey, reason);
+        assert_eq!(0x200, c8.pc);
+    }
+
+    #[test]
+    fn load_invalid_movie() {
+        use system::movie::Movie;
+
+        let mut movie = Movie::new(&make_system(&[], Quirks::default()));
+        movie.record_wait_key(2);
+        let data = movie.to_bytes();
+
+        assert_eq!(Err("Not a movie file".to_string()), Movie::from_bytes(b"RC8S"));
+
+        let mut bad_version = data.clone();
+        bad_version[5] = 2;
+        assert_eq!(Err("Unsupported movie version 2, expected 1".to_string()),
+                   Movie::from_bytes(&bad_version));
+
+        assert_eq!(Err("Movie is truncated, expected 1 bytes at offset 31".to_string()),
+                   Movie::from_bytes(&data[..data.len()-1]));
+
+        let mut bad_key = data.clone();
+        *bad_key.last_mut().unwrap() = 16;
+        assert_eq!(Err("Movie has invalid wait key 16".to_string()),
+                   Movie::from_bytes(&bad_key));
+    }
+
+    #[test]
+    fn debug_register_access() {
+        use system::debug::{Register, ALL_REGISTERS};
+
+        for reg in ALL_REGISTERS.iter() {
+            assert_eq!(Some(*reg), Register::from_name(&reg.name()));
+        }
+        assert_eq!(Some(Register::V(10)), Register::from_name("va"));
+        assert_eq!(Some(Register::PC), Register::from_name("pc"));
+        assert_eq!(None, Register::from_name("V16"));
+        assert_eq!(None, Register::from_name("Q"));
+
+        let mut c8 = make_system(&[], Quirks::default());
+        c8.write_register(Register::SP, 2).unwrap();
+        assert_eq!(vec![0, 0], c8.stack);
+        assert_eq!(Err("Value 0x11 is too large for SP, maximum is 0x10".to_string()),
+                   c8.write_register(Register::SP, 17));
+        c8.write_register(Register::I, 0xFFFE).unwrap();
+        assert_eq!(0xFFFE, c8.i_reg);
+
+        c8.write_memory(0xFFFE, &[1, 2]).unwrap();
+        assert_eq!(vec![1, 2], c8.read_memory(0xFFFE, 4));
+        assert_eq!(Err("Writing 3 bytes at 0xfffe would go past the end of memory".to_string()),
+                   c8.write_memory(0xFFFE, &[1, 2, 3]));
+    }
+
+    // A trace file that the test can still read after giving it away
+    #[derive(Clone, Default)]
+    struct SharedBuffer(::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>);
+
+    impl ::std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace_asm(asm: &str, filter: trace::TraceFilter, instrs: usize) -> Vec<String> {
+        let instrs_list = parse_asm_str(asm).unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs_list), Quirks::default());
+        let buffer = SharedBuffer::default();
+        c8.set_tracer(Some(trace::Tracer::new(Box::new(buffer.clone()), filter)));
+        for _ in 0..instrs {
+            let instr = c8.fetch_and_decode().unwrap();
+            c8.execute(&instr).unwrap();
+        }
+        c8.flush_trace();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        output.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn trace_instructions() {
+        let asm = "\
+            LD V0, 0x12
+            LD V1, 0x34
+            LDL I, 0x1234
+            LD [I], V1
+            CALL sub
+        sub:
+            LD DT, V0";
+
+        assert_eq!(vec![
+            "1 0x0200 6012 LD V0, 0x12 | V0=0x12",
+            "2 0x0202 6134 LD V1, 0x34 | V1=0x34",
+            "3 0x0204 F000 1234 LDL I, 0x1234 | I=0x1234",
+            "4 0x0208 F155 LD [I], V1 | [0x1234]=12,34",
+            "5 0x020a 220C CALL 0x20C | SP=0x01",
+            "6 0x020c F015 LD DT, V0 | DT=0x12",
+        ], trace_asm(asm, trace::TraceFilter::default(), 6));
+
+        // Instructions in a range of addresses
+        let filter = trace::TraceFilter { pc_ranges: vec![(0x202, 0x204), (0x20c, 0x20c)],
+                                          addr_ranges: vec![] };
+        assert_eq!(vec![
+            "2 0x0202 6134 LD V1, 0x34 | V1=0x34",
+            "3 0x0204 F000 1234 LDL I, 0x1234 | I=0x1234",
+            "6 0x020c F015 LD DT, V0 | DT=0x12",
+        ], trace_asm(asm, filter, 6));
+
+        // Instructions using a range of memory
+        let filter = trace::TraceFilter { pc_ranges: vec![],
+                                          addr_ranges: vec![(0x1235, 0x2000)] };
+        assert_eq!(vec!["4 0x0208 F155 LD [I], V1 | [0x1234]=12,34"],
+                   trace_asm(asm, filter, 6));
+        let filter = trace::TraceFilter { pc_ranges: vec![],
+                                          addr_ranges: vec![(0x1000, 0x1233)] };
+        assert!(trace_asm(asm, filter, 6).is_empty());
+    }
+
+    #[test]
+    fn trace_keeps_access_log() {
+        let instrs = parse_asm_str("LD I, 0x300\nLD [I], V0").unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+        c8.set_tracer(Some(trace::Tracer::new(Box::new(SharedBuffer::default()),
+                                              trace::TraceFilter::default())));
+        c8.set_access_log(true);
+        for _ in 0..2 {
+            let instr = c8.fetch_and_decode().unwrap();
+            c8.execute(&instr).unwrap();
+        }
+        assert_eq!(vec![MemoryAccess { addr: 0x300, len: 1, kind: AccessKind::Write }],
+                   c8.take_accesses());
+
+        // Tracing carries on after loading a state
+        let state = c8.save_state();
+        c8.load_state(&state).unwrap();
+        assert!(c8.tracer.is_some());
+    }
+
+    #[test]
+    fn trace_parse_and_diff() {
+        let asm = "LD V0, 0x12\nLDL I, 0x300\nLD [I], V0\nADD V0, 1\nLD V1, V0";
+        let lines = trace_asm(asm, trace::TraceFilter::default(), 5);
+        let a = trace::parse_trace(&lines.join("\n"), "a.txt").unwrap();
+        assert_eq!(lines, a.iter().map(|e| e.to_string()).collect::<Vec<String>>());
+        assert_eq!(Some(0x300), a[1].operand);
+        assert_eq!(None, tracediff::diff_traces(&a, &a, "a.txt", "a.txt", 3));
+
+        // Disassembly and comments don't matter
+        let b = trace::parse_trace("# other\n\n1 0x0200 6012 ld v0, 18 | V0=0x12", "b.txt");
+        assert_eq!(None, tracediff::find_divergence(&a[..1], &b.unwrap(), "a.txt", "b.txt"));
+
+        let b = trace::parse_trace(&lines.join("\n").replace("=12", "=13")
+                                   .replace("V0=0x13", "V0=0x14"), "b.txt").unwrap();
+        assert_eq!(Some(tracediff::Divergence {
+            index: 2, differences: vec!["[0x0300]: 0x12 vs 0x13".to_string()] }),
+                   tracediff::find_divergence(&a, &b, "a.txt", "b.txt"));
+        assert_eq!(Some("\
+Traces diverge at instruction 3, cycle 3 of a.txt and cycle 3 of b.txt:
+  [0x0300]: 0x12 vs 0x13
+a.txt:
+  2 0x0202 F000 0300 LDL I, 0x0300 | I=0x0300
+> 3 0x0206 F055 LD [I], V0 | [0x0300]=12
+  4 0x0208 7001 ADD V0, 0x01 | V0=0x13
+b.txt:
+  2 0x0202 F000 0300 LDL I, 0x0300 | I=0x0300
+> 3 0x0206 F055 LD [I], V0 | [0x0300]=13
+  4 0x0208 7001 ADD V0, 0x01 | V0=0x14".to_string()),
+                   tracediff::diff_traces(&a, &b, "a.txt", "b.txt", 1));
+
+        // Registers, and a trace that stops early
+        let mut b = a.clone();
+        b[3].pc = 0x20a;
+        b[3].changes = vec![trace::TraceChange::Register(debug::Register::V(0), 0x14)];
+        assert_eq!(vec!["PC: 0x0208 vs 0x020a", "V0: 0x13 vs 0x14"],
+                   tracediff::find_divergence(&a, &b, "a.txt", "b.txt").unwrap().differences);
+        assert_eq!(Some(tracediff::Divergence {
+            index: 2, differences: vec!["b.txt ends after 2 instructions, a.txt has 5".to_string()] }),
+                   tracediff::find_divergence(&a, &a[..2], "a.txt", "b.txt"));
+
+        assert_eq!(Err("b.txt:2: error: Unknown register \"X\"".to_string()),
+                   trace::parse_trace("1 0x0200 00E0 CLS\n2 0x0202 00E0 CLS | X=0x1", "b.txt"));
+    }
+
+    #[test]
+    fn profile_subroutines() {
+        let asm = "\
+        start:
+            CALL outer
+            CALL inner
+            JP start
+        outer:
+            CALL inner
+            RET
+        inner:
+            LD V0, 1
+            RET";
+        let (instrs, info) = parse_asm_with_info(asm, "<str>", &mut vec![]).unwrap();
+        let mut c8 = make_system(&instrs_to_rom(&instrs), Quirks::default());
+        c8.set_profiler(Some(profile::Profiler::new()));
+        // Stop partway through the second call to outer
+        for _ in 0..12 {
+            let instr = c8.fetch_and_decode().unwrap();
+            c8.execute(&instr).unwrap();
+        }
+
+        let profiler = c8.get_profiler().unwrap();
+        assert_eq!(12, profiler.total);
+        assert_eq!(Some(&2), profiler.counts.get(&0x200));
+        assert_eq!(Some(&3), profiler.counts.get(&0x20a));
+        assert_eq!(profile::SubroutineProfile { calls: 2, inclusive: 4, exclusive: 3 },
+                   profiler.subroutines[&0x206]);
+        assert_eq!(profile::SubroutineProfile { calls: 3, inclusive: 4, exclusive: 5 },
+                   profiler.subroutines[&0x20a]);
+        assert_eq!(4, profiler.top_level);
+        let calls: Vec<(Option<u16>, u16, u64)> = profiler.calls.iter()
+            .map(|(&(from, to), n)| (from, to, *n)).collect();
+        assert_eq!(vec![(None, 0x206, 2), (None, 0x20a, 1), (Some(0x206), 0x20a, 2)], calls);
+
+        // Subroutines still running count up to now
+        let report = profiler.report(&c8, Some(&info), 2);
+        assert_eq!("\
+Profile of 12 instructions
+
+Hot spots:
+     Count      %  Address
+         3  25.0%  0x020a inner  LD V0, 0x01
+         2  16.7%  0x0200 start  CALL 0x206
+
+Subroutines:
+     Calls  Inclusive      %  Exclusive      %  Address
+         -         12 100.0%          4  33.3%  <top level>
+         2          6  50.0%          3  25.0%  0x0206 outer
+         3          5  41.7%          5  41.7%  0x020a inner
+
+Call graph:
+  <top level> -> 0x0206 outer: 2 calls
+  <top level> -> 0x020a inner: 1 calls
+  0x0206 outer -> 0x020a inner: 2 calls", report);
+    }
+
+    #[test]
+    fn coverage_listing() {
+        let asm = "\
+        start:
+            LD I, data
+            LD [I], V1
+            SE V0, 0
+            CLS
+            EXIT
+        data:
+            CLS
+            CLS";
+        let (instrs, info) = parse_asm_with_info(asm, "<str>", &mut vec![]).unwrap();
+        let rom = instrs_to_rom(&instrs);
+        let mut c8 = make_system(&rom, Quirks::default());
+        c8.set_coverage(Some(coverage::Coverage::new(0x200, rom.len())));
+        for _ in 0..4 {
+            let instr = c8.fetch_and_decode().unwrap();
+            c8.execute(&instr).unwrap();
+        }
+
+        let coverage = c8.get_coverage().unwrap();
+        assert_eq!(coverage::ByteUsage { executed: false, read: false, written: true },
+                   coverage.usage(0x20b));
+        assert_eq!("\
+Coverage of 14 ROM bytes at 0x0200
+  Code:        8 bytes  57.1%
+  Data:        2 bytes  14.3%
+  Unused:      4 bytes  28.6%
+Never used: 0x0206-0x0207, 0x020c-0x020d", coverage.summary());
+        assert_eq!("\
+X--  0x0200  A20A      LD I, 0x20A
+X--  0x0202  F155      LD [I], V1
+X--  0x0204  3000      SE V0, 0x00
+---  0x0206  00 E0
+X--  0x0208  00FD      EXIT
+--W  0x020a  00 00
+---  0x020c  00 E0", coverage.listing(&c8));
+
+        let source = asm.replace("        ", "");
+        assert_eq!(vec![
+            "             | start:",
+            "X--  0x0200 |     LD I, data",
+            "X--  0x0202 |     LD [I], V1",
+            "X--  0x0204 |     SE V0, 0",
+            "---  0x0206 |     CLS",
+            "X--  0x0208 |     EXIT",
+            "             | data:",
+            "--W  0x020a |     CLS",
+            "---  0x020c |     CLS",
+        ], coverage.source_listing(&source, &info).lines().collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn sha1_digests() {
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", sha1::sha1_hex(b""));
+        assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", sha1::sha1_hex(b"abc"));
+        // Padding that needs a second block
+        assert_eq!("84983e441c3bd26ebaae4aa1f95129e5e54670f1", sha1::sha1_hex(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"));
+        assert_eq!("291e9a6c66994949b57ba5e650361e98fc36b1ba", sha1::sha1_hex(&[b'a'; 1000]));
+    }
+
+    #[test]
+    fn rom_database() {
+        let mut rom_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        rom_path.push("roms/BC_test.ch8");
+        let rom = read_rom(rom_path.to_str().unwrap());
+        let mut db = romdb::RomDatabase::built_in();
+        assert_eq!("BC_test", db.lookup(&rom).unwrap().title);
+        assert_eq!(None, db.lookup(&[0x00, 0xE0]));
+
+        // Later entries replace built in ones
+        let user_db = romdb::RomDatabase::parse("\
+# Mine
+[9DF1689015A0D1D95144F141903296F9F1C35FC5]
+title = BC test, faster
+platform = schip
+cpf = 30
+keys = 5:Up 8:Down
+
+[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]
+title = Other
+quirks = vip
+platform = schip", "mine.txt").unwrap();
+        db.extend(user_db);
+        let info = db.lookup(&rom).unwrap();
+        assert_eq!(romdb::RomInfo {
+            title: "BC test, faster".to_string(),
+            platform: Some("schip".to_string()),
+            quirks: None,
+            cycles_per_frame: Some(30),
+            keys: vec![(5, "Up".to_string()), (8, "Down".to_string())],
+        }, *info);
+        // Quirks come from the platform unless they're given
+        assert_eq!(Some(Quirks::superchip()), info.get_quirks());
+        assert_eq!(None, romdb::RomInfo::default().get_quirks());
+
+        let errors = [
+            ("title = x", "db.txt:1: error: Expected a [<sha1>] line first"),
+            ("[1234]", "db.txt:1: error: Invalid SHA-1 \"1234\""),
+            ("[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\ncpf = 0",
+             "db.txt:2: error: Invalid cycles per frame \"0\""),
+            ("[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\nkeys = G:Up",
+             "db.txt:2: error: Invalid key mapping \"G:Up\", expected <0-F>:<key>"),
+            ("[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\nplatform = nes",
+             "db.txt:2: error: Unknown platform \"nes\", \
+              expected one of: chip8, chip48, schip, xochip"),
+            ("[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\nspeed = 1",
+             "db.txt:2: error: Unknown setting \"speed\""),
+            ("[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\ncpf = 1",
+             "db.txt:1: error: ROM 0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9 has no title"),
+            ("# Comment\n[0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\n\
+              [1e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9]\ntitle = Two",
+             "db.txt:2: error: ROM 0e7e4a2c8a3d1c8fbe5b86b5a5fdb56a4ca1e3c9 has no title"),
+        ];
+        for &(text, error) in errors.iter() {
+            assert_eq!(Err(error.to_string()),
+                       romdb::RomDatabase::parse(text, "db.txt").map(|_| ()));
+        }
+    }
+
+    #[test]
+    fn decode_without_a_system() {
+        let cls = instr::decode(0x00E0).unwrap();
+        assert_eq!(InstrKind::ClearDisplay, cls.kind);
+        assert_eq!("CLS", cls.to_instr().repr());
+        assert_eq!(Err(instr::DecodeError::Breakpoint), instr::decode(0x0FFF));
+        assert_eq!(Err(instr::DecodeError::Unknown(0x5001)), instr::decode(0x5001));
+
+        // Invalid opcodes can still be shown, as something that assembles back to them
+        for &(opcode, text) in [(0x5001, ".word 0x5001"), (0x0FFF, "BRK"),
+                                (0xE0A1, "SKNP V0")].iter() {
+            let instr = instr::decode_or_undef(opcode);
+            assert_eq!(text, instr.to_instr().repr());
+            assert_eq!(vec![(opcode >> 8) as u8, opcode as u8],
+                       instrs_to_rom(&parse_asm_str(text).unwrap()));
+        }
+
+        // Running one fails like any unknown opcode
+        let mut c8 = make_system(&[0x50, 0x01], Quirks::default());
+        let undef = instr::decode_or_undef(0x5001);
+        assert_eq!(InstrKind::Undef, undef.kind);
+        c8.pc += 2;
+        assert_eq!(Err(EmulatorError::UnknownInstruction { opcode: 0x5001, pc: 0x200 }),
+                   c8.execute(&undef));
+    }
+
     #[test]
     #[ignore]
     fn fuzz_test () {
         let mut valid_instrs = all_valid_chip8_instrs();
         let dummy: Vec<u8> = vec![];
-        let mut c8 = make_system(&dummy);
+        let mut c8 = make_system(&dummy, Quirks::default());
 
         let exceptions: Vec<u16> = vec![
             0x00EE, // RET
@@ -645,13 +1807,13 @@ mod test {
                        continue;
                    }
                 randomise_regs(&mut c8);
-                let decode = c8.get_opcode_obj(*i);
+                let decode = c8.decode(*i);
                 print!("0x{:04x}", *i);
                 match decode {
-                    Err(msg)  => panic!(msg),
+                    Err(e)    => panic!("{}", e),
                     Ok(instr) => {
-                        println!(" -- {}", instr.repr());
-                        c8.execute(&instr);
+                        println!(" -- {}", instr.to_instr().repr());
+                        c8.execute(&instr).unwrap();
                     },
                 }
             }
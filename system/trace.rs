@@ -0,0 +1,166 @@
+use system::MemoryAccess;
+use system::debug::Register;
+use std::fmt;
+use std::io::Write;
+
+// What an instruction changed
+#[derive(Clone, PartialEq, Debug)]
+pub enum TraceChange {
+    Register(Register, u16),
+    // The bytes written starting at an address
+    Memory(u16, Vec<u8>),
+}
+
+impl fmt::Display for TraceChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceChange::Register(reg, value) => match reg.size() {
+                2 => write!(f, "{}=0x{:04x}", reg.name(), value),
+                _ => write!(f, "{}=0x{:02x}", reg.name(), value),
+            },
+            TraceChange::Memory(addr, ref data) => {
+                let bytes: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "[0x{:04x}]={}", addr, bytes.join(","))
+            }
+        }
+    }
+}
+
+// One executed instruction. As a line of the trace file:
+// <cycle> <pc> <opcode> [operand] <disassembly> | <changes>
+// e.g. "12 0x0204 6112 LD V1, 0x12 | V1=0x12"
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceEntry {
+    // Counting from 1 for the first instruction
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // Only for 4 byte instructions
+    pub operand: Option<u16>,
+    pub disassembly: String,
+    pub changes: Vec<TraceChange>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} 0x{:04x} {:04X}", self.cycle, self.pc, self.opcode)?;
+        if let Some(operand) = self.operand {
+            write!(f, " {:04X}", operand)?;
+        }
+        write!(f, " {}", self.disassembly)?;
+        if !self.changes.is_empty() {
+            let changes: Vec<String> = self.changes.iter().map(|c| c.to_string()).collect();
+            write!(f, " | {}", changes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// Inclusive ranges of addresses. Empty means anything goes.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct TraceFilter {
+    // Instructions at these addresses
+    pub pc_ranges: Vec<(u16, u16)>,
+    // Instructions that read or write memory in these ranges
+    pub addr_ranges: Vec<(u16, u16)>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, accesses: &[MemoryAccess]) -> bool {
+        let pc_matches = self.pc_ranges.is_empty() ||
+            self.pc_ranges.iter().any(|&(start, end)| (pc >= start) && (pc <= end));
+        let addr_matches = self.addr_ranges.is_empty() ||
+            self.addr_ranges.iter().any(|&(start, end)| {
+                accesses.iter().any(|access| {
+                    let access_end = u32::from(access.addr) + u32::from(access.len);
+                    (u32::from(access.addr) <= u32::from(end)) &&
+                        (access_end > u32::from(start))
+                })
+            });
+        pc_matches && addr_matches
+    }
+}
+
+// Writes the entries that pass the filter
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write + Send>, filter: TraceFilter) -> Tracer {
+        Tracer { output, filter }
+    }
+
+    pub fn is_traced(&self, pc: u16, accesses: &[MemoryAccess]) -> bool {
+        self.filter.matches(pc, accesses)
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> ::std::io::Result<()> {
+        writeln!(self.output, "{}", entry)
+    }
+
+    pub fn flush(&mut self) -> ::std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+fn parse_hex(text: &str, what: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid {} \"{}\"", what, text))
+}
+
+impl TraceChange {
+    pub fn parse(text: &str) -> Result<TraceChange, String> {
+        let pos = text.find('=').ok_or_else(|| format!("Invalid change \"{}\"", text))?;
+        let (name, value) = (&text[..pos], &text[pos+1..]);
+        if name.starts_with('[') && name.ends_with(']') {
+            let addr = parse_hex(&name[1..name.len()-1], "address")?;
+            let data = value.split(',').map(|b| {
+                u8::from_str_radix(b, 16).map_err(|_| format!("Invalid byte \"{}\"", b))
+            }).collect::<Result<Vec<u8>, String>>()?;
+            return Ok(TraceChange::Memory(addr, data))
+        }
+        let reg = Register::from_name(name)
+            .ok_or_else(|| format!("Unknown register \"{}\"", name))?;
+        Ok(TraceChange::Register(reg, parse_hex(value, "value")?))
+    }
+}
+
+impl TraceEntry {
+    // Reads back a line of a trace file. The operand is
+    // the word after the opcode, if that's 4 hex digits.
+    pub fn parse(line: &str) -> Result<TraceEntry, String> {
+        let (instr, changes) = match line.find(" | ") {
+            None => (line, ""),
+            Some(pos) => (&line[..pos], &line[pos+3..]),
+        };
+
+        let mut words = instr.split_whitespace().peekable();
+        let cycle = words.next().ok_or_else(|| "Empty line".to_string())?;
+        let cycle = cycle.parse::<u64>().map_err(|_| format!("Invalid cycle \"{}\"", cycle))?;
+        let pc = parse_hex(words.next().unwrap_or(""), "PC")?;
+        let opcode = parse_hex(words.next().unwrap_or(""), "opcode")?;
+        let operand = match words.peek() {
+            Some(word) if (word.len() == 4) && word.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(parse_hex(words.next().unwrap(), "operand")?)
+            }
+            _ => None,
+        };
+        let disassembly = words.collect::<Vec<&str>>().join(" ");
+        let changes = changes.split_whitespace().map(TraceChange::parse)
+            .collect::<Result<Vec<TraceChange>, String>>()?;
+
+        Ok(TraceEntry { cycle, pc, opcode, operand, disassembly, changes })
+    }
+}
+
+// Blank lines and lines starting with '#' are skipped
+pub fn parse_trace(text: &str, filename: &str) -> Result<Vec<TraceEntry>, String> {
+    text.lines().enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| {
+            TraceEntry::parse(line).map_err(|e| format!("{}:{}: error: {}", filename, idx+1, e))
+        })
+        .collect()
+}
@@ -0,0 +1,143 @@
+use system::trace::{TraceEntry, TraceChange};
+use system::debug::ALL_REGISTERS;
+use std::collections::{BTreeSet, HashMap};
+
+// Where two traces stop agreeing
+#[derive(Clone, PartialEq, Debug)]
+pub struct Divergence {
+    // Of the first entry that differs, in both traces
+    pub index: usize,
+    // e.g. "V3: 0x01 vs 0x02", or that one trace ended
+    pub differences: Vec<String>,
+}
+
+// What a trace says the system looks like so far. Registers
+// start at 0 and memory is unknown until something writes it.
+struct TraceState {
+    registers: [u16; 21],
+    memory: HashMap<u16, u8>,
+}
+
+impl TraceState {
+    fn new() -> TraceState {
+        TraceState { registers: [0; 21], memory: HashMap::new() }
+    }
+
+    // Adds what the entry touched to registers and addrs
+    fn apply(&mut self, entry: &TraceEntry,
+             registers: &mut BTreeSet<usize>, addrs: &mut BTreeSet<u16>) {
+        for change in &entry.changes {
+            match *change {
+                TraceChange::Register(reg, value) => {
+                    let idx = ALL_REGISTERS.iter().position(|r| *r == reg).unwrap();
+                    self.registers[idx] = value;
+                    let _ = registers.insert(idx);
+                }
+                TraceChange::Memory(addr, ref data) => {
+                    for (offset, byte) in data.iter().enumerate() {
+                        let addr = addr.wrapping_add(offset as u16);
+                        let _ = self.memory.insert(addr, *byte);
+                        let _ = addrs.insert(addr);
+                    }
+                }
+            }
+        }
+    }
+
+    fn format_memory(&self, addr: u16) -> String {
+        match self.memory.get(&addr) {
+            None => "not written".to_string(),
+            Some(byte) => format!("0x{:02x}", byte),
+        }
+    }
+}
+
+// The disassembly isn't compared, so traces from other emulators can
+// be used as long as they have the same columns and change format.
+pub fn find_divergence(a: &[TraceEntry], b: &[TraceEntry],
+                       a_name: &str, b_name: &str) -> Option<Divergence> {
+    let mut a_state = TraceState::new();
+    let mut b_state = TraceState::new();
+
+    for (index, (a_entry, b_entry)) in a.iter().zip(b.iter()).enumerate() {
+        let mut differences = vec![];
+        if a_entry.pc != b_entry.pc {
+            differences.push(format!("PC: 0x{:04x} vs 0x{:04x}", a_entry.pc, b_entry.pc));
+        }
+        if (a_entry.opcode, a_entry.operand) != (b_entry.opcode, b_entry.operand) {
+            differences.push(format!("opcode: {:04X} vs {:04X}", a_entry.opcode, b_entry.opcode));
+        }
+
+        // Everything before was the same, so only compare what changed
+        let mut registers = BTreeSet::new();
+        let mut addrs = BTreeSet::new();
+        a_state.apply(a_entry, &mut registers, &mut addrs);
+        b_state.apply(b_entry, &mut registers, &mut addrs);
+        for idx in registers {
+            let (a_value, b_value) = (a_state.registers[idx], b_state.registers[idx]);
+            if a_value != b_value {
+                differences.push(format!("{}: 0x{:x} vs 0x{:x}",
+                                         ALL_REGISTERS[idx].name(), a_value, b_value));
+            }
+        }
+        for addr in addrs {
+            if a_state.memory.get(&addr) != b_state.memory.get(&addr) {
+                differences.push(format!("[0x{:04x}]: {} vs {}", addr,
+                                         a_state.format_memory(addr),
+                                         b_state.format_memory(addr)));
+            }
+        }
+
+        if !differences.is_empty() {
+            return Some(Divergence { index, differences })
+        }
+    }
+
+    if a.len() == b.len() {
+        return None
+    }
+    let (shorter, longer, longer_len) = if a.len() < b.len() {
+        (a_name, b_name, b.len())
+    } else {
+        (b_name, a_name, a.len())
+    };
+    let index = a.len().min(b.len());
+    Some(Divergence {
+        index,
+        differences: vec![format!("{} ends after {} instructions, {} has {}",
+                                  shorter, index, longer, longer_len)],
+    })
+}
+
+fn format_context(entries: &[TraceEntry], index: usize, context: usize) -> String {
+    let start = index.saturating_sub(context);
+    let end = entries.len().min(index + context + 1);
+    entries[start..end].iter().enumerate().map(|(offset, entry)| {
+        let marker = if (start + offset) == index { ">" } else { " " };
+        format!("{} {}", marker, entry)
+    }).collect::<Vec<String>>().join("\n")
+}
+
+// Describes where the traces diverge, with context lines either side
+pub fn diff_traces(a: &[TraceEntry], b: &[TraceEntry], a_name: &str, b_name: &str,
+                   context: usize) -> Option<String> {
+    let divergence = find_divergence(a, b, a_name, b_name)?;
+    let cycle = |entries: &[TraceEntry]| match entries.get(divergence.index) {
+        None => "the end".to_string(),
+        Some(entry) => format!("cycle {}", entry.cycle),
+    };
+
+    let mut report = vec![
+        format!("Traces diverge at instruction {}, {} of {} and {} of {}:",
+                divergence.index + 1, cycle(a), a_name, cycle(b), b_name),
+    ];
+    report.extend(divergence.differences.iter().map(|d| format!("  {}", d)));
+    for &(entries, name) in [(a, a_name), (b, b_name)].iter() {
+        report.push(format!("{}:", name));
+        let lines = format_context(entries, divergence.index, context);
+        if !lines.is_empty() {
+            report.push(lines);
+        }
+    }
+    Some(report.join("\n"))
+}